@@ -19,12 +19,23 @@ fn main() {
         }).ok();
         window.add_menu_separator().ok();
     }).ok();
+    app.add_check_item("Notifications", true, |_, checked| {
+        println!("Notifications {}", if checked { "on" } else { "off" });
+    }).ok();
+    app.add_menu_separator().ok();
+    if let Ok(power) = app.add_submenu("Power mode") {
+        power.add_radio_group(&mut app, &["Low", "Balanced", "Performance"], 1, |_, _, selected| {
+            println!("Selected power mode {}", selected);
+        }).ok();
+    }
     app.add_menu_separator().ok();
     app.add_menu_item(&"Quit".to_string(), |window| {
         window.quit();
     }).ok();
     println!("Waiting on message!");
-    app.wait_for_message();
+    for event in app.events() {
+        println!("{:?}", event);
+    }
 }
 
 // #[cfg(not(target_os = "windows"))]
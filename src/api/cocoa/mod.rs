@@ -1,18 +1,64 @@
 use std;
-use {SystrayError};
+use std::sync::Arc;
+use {SystrayError, Capability, Category, EventSender, IconSet, MenuNode, Tooltip, TooltipProvider};
+
+#[derive(Clone)]
+pub struct Handle;
+
+impl Handle {
+    pub fn set_menu_entry_label(&self, _: u32, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_menu_entry_checked(&self, _: u32, _: bool) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_icon_from_file(&self, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_menu_entry_label_nowait(&self, _: u32, _: &str) {
+    }
+    pub fn set_icon_from_file_nowait(&self, _: &str) {
+    }
+    pub fn set_tooltip(&self, _: &Tooltip) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn request_attention(&self, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_visible(&self, _: bool) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_icon_label(&self, _: &str, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn has_capability(&self, _: Capability) -> bool {
+        false
+    }
+    pub fn quit(&self) {
+    }
+}
 
 pub struct Window {
 }
 
 impl Window {
-    pub fn new() -> Result<Window, SystrayError> {
+    pub fn new(_: EventSender, _: &str, _: &str, _: Category) -> Result<Window, SystrayError> {
         Err(SystrayError::NotImplementedError)
     }
+    pub fn handle(&self) -> Handle {
+        Handle
+    }
     pub fn quit(&self) {
         unimplemented!()
     }
-    pub fn set_tooltip(&self, _: &String) -> Result<(), SystrayError> {
-        unimplemented!()
+    pub fn set_tooltip(&self, _: &Tooltip) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_tooltip_provider(&self, _: Arc<TooltipProvider>) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
     }
     pub fn add_menu_item<F>(&self, _: &String, _: F) -> Result<u32, SystrayError>
         where F: std::ops::Fn(&Window) -> () + 'static
@@ -25,4 +71,61 @@ impl Window {
     pub fn set_icon_from_buffer(&self, _: &[u8], _: u32, _: u32) -> Result<(), SystrayError> {
         unimplemented!()
     }
+    pub fn set_icon_from_rgba(&self, _: &[u8], _: u32, _: u32) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_icon_from_png(&self, _: &[u8]) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_icon_set(&self, _: &IconSet) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn request_attention(&self, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_visible(&self, _: bool) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_icon_label(&self, _: &str, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn has_capability(&self, _: Capability) -> bool {
+        false
+    }
+    pub fn set_icon_variants(&self, _: &IconSet, _: &IconSet) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_icon_from_theme(&self, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_icon_theme_path(&self, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn insert_menu_node(&self, _: Option<u32>, _: usize, _: &MenuNode) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_radio_selected(&self, _: u32, _: usize) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_menu_entry_checked(&self, _: u32, _: bool) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_menu_entry_label(&self, _: u32, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_menu_entry_icon(&self, _: u32, _: Option<&str>) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_menu_entry_enabled(&self, _: u32, _: bool) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn set_menu_entry_visible(&self, _: u32, _: bool) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+    pub fn remove_menu_entry(&self, _: u32) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
 }
@@ -0,0 +1,127 @@
+use glib::translate::ToGlibPtr;
+use gobject_sys;
+use gtk;
+use gtk_sys;
+use libappindicator_sys::*;
+use std;
+use std::os::raw::{c_int, c_uint, c_void};
+use Category;
+
+// Wrapper around the raw libappindicator calls. The libappindicator crate
+// keeps its indicator pointer private, which puts signals and the calls it
+// does not wrap out of reach.
+pub struct Indicator {
+    raw: *mut AppIndicator,
+}
+
+type ScrollHandler = Box<Fn(i32, c_uint) + 'static>;
+
+unsafe extern "C" fn scroll_trampoline(_: *mut AppIndicator, delta: c_int, direction: c_uint,
+                                       f: *mut c_void) {
+    let f = &*(f as *const ScrollHandler);
+    f(delta, direction);
+}
+
+unsafe extern "C" fn drop_scroll_handler(f: *mut c_void, _: *mut gobject_sys::GClosure) {
+    drop(Box::from_raw(f as *mut ScrollHandler));
+}
+
+impl Indicator {
+    // The id is exported as the StatusNotifierItem id, which hosts remember
+    // settings by.
+    pub fn new(id: &str, icon: &str, category: Category) -> Indicator {
+        let category = match category {
+            Category::ApplicationStatus => AppIndicatorCategory::APP_INDICATOR_CATEGORY_APPLICATION_STATUS,
+            Category::Communications => AppIndicatorCategory::APP_INDICATOR_CATEGORY_COMMUNICATIONS,
+            Category::SystemServices => AppIndicatorCategory::APP_INDICATOR_CATEGORY_SYSTEM_SERVICES,
+            Category::Hardware => AppIndicatorCategory::APP_INDICATOR_CATEGORY_HARDWARE,
+        };
+        Indicator {
+            raw: unsafe {
+                app_indicator_new(id.to_glib_none().0, icon.to_glib_none().0, category)
+            }
+        }
+    }
+
+    pub fn set_status(&mut self, status: AppIndicatorStatus) {
+        unsafe {
+            app_indicator_set_status(self.raw, status);
+        }
+    }
+
+    pub fn set_menu(&mut self, menu: &gtk::Menu) {
+        unsafe {
+            app_indicator_set_menu(self.raw, menu.to_glib_none().0);
+        }
+    }
+
+    pub fn set_icon_full(&mut self, name: &str, desc: &str) {
+        unsafe {
+            app_indicator_set_icon_full(self.raw, name.to_glib_none().0, desc.to_glib_none().0);
+        }
+    }
+
+    // Shown next to the icon by hosts that can. The guide is the longest
+    // text the label is expected to hold, so its width stays put.
+    pub fn set_label(&mut self, label: &str, guide: &str) {
+        unsafe {
+            app_indicator_set_label(self.raw, label.to_glib_none().0, guide.to_glib_none().0);
+        }
+    }
+
+    // Shown instead of the icon while the status is attention.
+    pub fn set_attention_icon_full(&mut self, name: &str, desc: &str) {
+        unsafe {
+            app_indicator_set_attention_icon_full(self.raw, name.to_glib_none().0, desc.to_glib_none().0);
+        }
+    }
+
+    // Exported as the IconThemePath property. Hosts look up icon names in
+    // the themes below it before their own.
+    pub fn set_icon_theme_path(&mut self, path: &str) {
+        unsafe {
+            app_indicator_set_icon_theme_path(self.raw, path.to_glib_none().0);
+        }
+    }
+
+    // Exported as the StatusNotifierItem title, which hosts show as the
+    // tooltip of the icon.
+    pub fn set_title(&mut self, title: &str) {
+        unsafe {
+            app_indicator_set_title(self.raw, title.to_glib_none().0);
+        }
+    }
+
+    // The item is activated on middle clicks over the icon. It has to be
+    // visible and sensitive, but does not need to be part of the menu. The
+    // indicator keeps a reference to it.
+    pub fn set_secondary_activate_target(&mut self, item: &gtk::MenuItem) {
+        unsafe {
+            let widget: *mut gtk_sys::GtkWidget = item.to_glib_none().0;
+            app_indicator_set_secondary_activate_target(self.raw, widget);
+        }
+    }
+
+    // Called with the number of steps and the GdkScrollDirection.
+    pub fn connect_scroll_event<F>(&self, f: F)
+        where F: Fn(i32, c_uint) + 'static {
+        let f: Box<ScrollHandler> = Box::new(Box::new(f));
+        unsafe {
+            let handler: unsafe extern "C" fn(*mut AppIndicator, c_int, c_uint, *mut c_void) = scroll_trampoline;
+            gobject_sys::g_signal_connect_data(self.raw as *mut gobject_sys::GObject,
+                                               b"scroll-event\0".as_ptr() as *const _,
+                                               Some(std::mem::transmute(handler)),
+                                               Box::into_raw(f) as *mut c_void,
+                                               Some(drop_scroll_handler),
+                                               gobject_sys::GConnectFlags::empty());
+        }
+    }
+}
+
+impl Drop for Indicator {
+    fn drop(&mut self) {
+        unsafe {
+            gobject_sys::g_object_unref(self.raw as *mut gobject_sys::GObject);
+        }
+    }
+}
@@ -1,22 +1,58 @@
 use gtk::{ self, Window as GTKWindow, WindowType, WidgetExt,
-           Inhibit, Widget, Menu, MenuShellExt, MenuItemExt };
-use libappindicator::{AppIndicator,
-                      AppIndicatorStatus};
-use std::cell::{RefCell};
+           Inhibit, Widget, Menu, MenuShellExt, MenuItemExt, CheckMenuItemExt, Cast };
+use libappindicator_sys::AppIndicatorStatus;
+use png;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use {SystrayEvent, SystrayError};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use {SystrayEvent, SystrayError, Capability, Category, ColorScheme, EventSender, IconSet, MenuNode, MenuNodeKind, ScrollOrientation, Tooltip, TooltipProvider};
 use glib;
 use std;
 use std::thread;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use icon_cache::{icon_file, IconCache};
+#[cfg(feature = "dbus")]
+use portal::ColorSchemeWatch;
+
+mod indicator;
+mod settings;
+use self::indicator::Indicator;
+
+// GdkScrollDirection values, as passed to the scroll-event handler
+const GDK_SCROLL_UP: u32 = 0;
+const GDK_SCROLL_DOWN: u32 = 1;
+const GDK_SCROLL_LEFT: u32 = 2;
+const GDK_SCROLL_RIGHT: u32 = 3;
+
+// How long to wait for the GTK thread to carry out a command.
+const CALL_TIMEOUT_SECS: u64 = 5;
 
 // Gtk specific struct that will live only in the Gtk thread, since a lot of the
 // base types involved don't implement Send (for good reason).
 pub struct GtkSystrayApp {
     menu: gtk::Menu,
-    ai: RefCell<AppIndicator>,
+    ai: RefCell<Indicator>,
     menu_items: RefCell<HashMap<u32, gtk::MenuItem>>,
-    event_tx: Sender<SystrayEvent>
+    // Menus hanging off submenu entries, by entry id
+    submenus: RefCell<HashMap<u32, gtk::Menu>>,
+    // Members of each radio group, with their index in the group
+    radio_groups: RefCell<HashMap<u32, Vec<(usize, gtk::RadioMenuItem)>>>,
+    // Cleared on shutdown, or else when the GTK thread ends
+    icon_cache: RefCell<IconCache>,
+    // The theme path given by the user. Icon sets replace it with the
+    // theme in the icon cache while they are shown.
+    icon_theme_path: RefCell<String>,
+    showing_icon_set: Cell<bool>,
+    // Icons for light and dark color schemes, switched as it changes
+    icon_variants: RefCell<Option<(IconSet, IconSet)>>,
+    // Where the portal has no preference, GtkSettings is asked instead.
+    portal_color_scheme: Cell<Option<ColorScheme>>,
+    // Together these make the status of the indicator.
+    visible: Cell<bool>,
+    attention: Cell<bool>,
+    event_tx: EventSender
 }
 
 thread_local!(static GTK_STASH: RefCell<Option<GtkSystrayApp>> = RefCell::new(None));
@@ -48,72 +84,543 @@ fn run_on_gtk_thread<F>(f: F)
     });
 }
 
+// Like run_on_gtk_thread, but waits for f to finish and returns its result.
+// Fails with SystrayError::Timeout when the GTK thread is stuck or gone.
+// On the GTK thread itself, where only it has the stash, f runs right away
+// instead of waiting for a turn that comes only after the caller returns.
+fn call_on_gtk_thread<F>(f: F) -> Result<(), SystrayError>
+    where F: std::ops::Fn(&GtkSystrayApp) -> Result<(), SystrayError> + Send + 'static {
+    let here = GTK_STASH.with(|stash| stash.borrow().as_ref().map(|stash| f(stash)));
+    if let Some(result) = here {
+        return result;
+    }
+    let (tx, rx) = channel();
+    run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+        tx.send(f(stash)).ok();
+    });
+    rx.recv_timeout(Duration::from_secs(CALL_TIMEOUT_SECS))?
+}
+
+// RGBA pixels as a PNG file, for the icon cache to write out.
+fn encode_png(data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, SystrayError> {
+    let mut buf = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut buf, width, height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()
+            .map_err(|e| SystrayError::OsError(format!("Error encoding icon: {}", e)))?;
+        writer.write_image_data(data)
+            .map_err(|e| SystrayError::OsError(format!("Error encoding icon: {}", e)))?;
+    }
+    Ok(buf)
+}
+
+// Neither libappindicator nor GtkImage complain about missing files; the
+// former takes them for icon names, the latter shows a broken image.
+fn check_icon_file(file: &str) -> Result<(), SystrayError> {
+    if !Path::new(file).is_file() {
+        return Err(SystrayError::OsError(format!("No icon file at {}", file)));
+    }
+    Ok(())
+}
+
 impl GtkSystrayApp {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<GtkSystrayApp, SystrayError> {
+    pub fn new(event_tx: EventSender, id: &str, title: &str,
+               category: Category) -> Result<GtkSystrayApp, SystrayError> {
         if let Err(e) = gtk::init() {
             return Err(SystrayError::OsError(format!("{}", "Gtk init error!")));
         }
-        let mut m = gtk::Menu::new();
-        let mut ai = AppIndicator::new("", "");
+        let m = gtk::Menu::new();
+        let mut ai = Indicator::new(id, "", category);
+        if !title.is_empty() {
+            ai.set_title(title);
+        }
         ai.set_status(AppIndicatorStatus::APP_INDICATOR_STATUS_ACTIVE);
-        ai.set_menu(&mut m);
+        ai.set_menu(&m);
+        // Hosts only tell the GTK menu about opening and closing where they
+        // let it draw itself, rather than mirroring it over dbusmenu.
+        m.connect_show(|_| {
+            run_on_gtk_thread(|stash : &GtkSystrayApp| {
+                stash.send_event(SystrayEvent::MenuOpened { time: Instant::now(), position: None });
+            });
+        });
+        m.connect_hide(|_| {
+            run_on_gtk_thread(|stash : &GtkSystrayApp| {
+                stash.send_event(SystrayEvent::MenuClosed { time: Instant::now(), position: None });
+            });
+        });
+        // Middle clicks activate this item, which is never put in a menu.
+        let secondary_target = gtk::MenuItem::new();
+        secondary_target.show();
+        secondary_target.connect_activate(|_| {
+            run_on_gtk_thread(|stash : &GtkSystrayApp| {
+                stash.send_event(SystrayEvent::SecondaryActivate { time: Instant::now(), position: None });
+            });
+        });
+        ai.set_secondary_activate_target(&secondary_target);
+        settings::connect_theme_changed(|| {
+            run_on_gtk_thread(|stash : &GtkSystrayApp| {
+                if let Err(e) = stash.show_icon_variant() {
+                    warn!("Error switching icon variant: {}", e);
+                }
+            });
+        });
+        ai.connect_scroll_event(|delta, direction| {
+            let (delta, orientation) = match direction {
+                GDK_SCROLL_UP => (-delta, ScrollOrientation::Vertical),
+                GDK_SCROLL_DOWN => (delta, ScrollOrientation::Vertical),
+                GDK_SCROLL_LEFT => (-delta, ScrollOrientation::Horizontal),
+                GDK_SCROLL_RIGHT => (delta, ScrollOrientation::Horizontal),
+                _ => return
+            };
+            run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+                stash.send_event(SystrayEvent::Scroll {
+                    delta: delta,
+                    orientation: orientation,
+                    time: Instant::now(),
+                    position: None,
+                });
+            });
+        });
         Ok(GtkSystrayApp {
             menu: m,
             ai: RefCell::new(ai),
             menu_items: RefCell::new(HashMap::new()),
+            submenus: RefCell::new(HashMap::new()),
+            radio_groups: RefCell::new(HashMap::new()),
+            icon_cache: RefCell::new(IconCache::new()),
+            icon_theme_path: RefCell::new(String::new()),
+            showing_icon_set: Cell::new(false),
+            icon_variants: RefCell::new(None),
+            portal_color_scheme: Cell::new(None),
+            visible: Cell::new(true),
+            attention: Cell::new(false),
             event_tx: event_tx
         })
     }
 
+    pub fn send_event(&self, event: SystrayEvent) {
+        self.event_tx.send(event).ok();
+    }
+
+    // libappindicator does not tell where the pointer was.
+    fn send_activated(&self, id: u32, checked: Option<bool>, selected: Option<usize>) {
+        self.send_event(SystrayEvent::MenuItemActivated {
+            id: id,
+            checked: checked,
+            selected: selected,
+            time: Instant::now(),
+            position: None,
+        });
+    }
+
     pub fn systray_menu_selected(&self, menu_id: u32) {
-        self.event_tx.send(SystrayEvent {
-            menu_index: menu_id as u32,
-        }).ok();
+        self.send_activated(menu_id, None, None);
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) {
-        //let mut menu_items = self.menu_items.borrow_mut();
-        let m = gtk::SeparatorMenuItem::new();
-        self.menu.append(&m);
-        //menu_items.insert(item_idx, m);
-        self.menu.show_all();
+    pub fn systray_menu_toggled(&self, menu_id: u32, checked: bool) {
+        self.send_activated(menu_id, Some(checked), None);
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &String) {
-        let mut menu_items = self.menu_items.borrow_mut();
-        if menu_items.contains_key(&item_idx) {
-            let m : &gtk::MenuItem = menu_items.get(&item_idx).unwrap();
+    pub fn systray_radio_selected(&self, group_id: u32, selected: usize) {
+        self.send_activated(group_id, None, Some(selected));
+    }
+
+    // The menu entries with the given parent are appended to. None is the
+    // menu of the indicator itself.
+    fn parent_menu(&self, parent_idx: Option<u32>) -> Result<gtk::Menu, SystrayError> {
+        match parent_idx {
+            None => Ok(self.menu.clone()),
+            Some(p) => self.submenus.borrow().get(&p).cloned()
+                .ok_or(SystrayError::OsError(format!("No submenu with id {}", p)))
+        }
+    }
+
+    // Creates the widget for a node, and for everything below it, at the
+    // given position of its parent menu.
+    pub fn insert_menu_node(&self, parent_idx: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        let item_idx = node.id;
+        let parent = self.parent_menu(parent_idx)?;
+        if let Some(ref icon) = node.icon {
+            check_icon_file(icon)?;
+        }
+        let m: gtk::MenuItem = match node.kind {
+            MenuNodeKind::Separator => gtk::SeparatorMenuItem::new().upcast(),
+            MenuNodeKind::Item | MenuNodeKind::Submenu => {
+                let m = gtk::ImageMenuItem::new_with_label(&node.label);
+                if let Some(ref icon) = node.icon {
+                    m.set_image(Some(&gtk::Image::new_from_file(icon)));
+                    m.set_always_show_image(true);
+                }
+                if node.kind == MenuNodeKind::Item {
+                    m.connect_activate(move |_| {
+                        run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+                            stash.systray_menu_selected(item_idx);
+                        });
+                    });
+                }
+                m.upcast()
+            }
+            MenuNodeKind::Check { checked } => {
+                let m = gtk::CheckMenuItem::new_with_label(&node.label);
+                m.set_active(checked);
+                // The check menu item flips its own state before user handlers
+                // run, so get_active() already holds the new value here.
+                // set_active() from code does not emit "activate", so
+                // programmatic changes stay silent.
+                m.connect_activate(move |m| {
+                    let checked = m.get_active();
+                    run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+                        stash.systray_menu_toggled(item_idx, checked);
+                    });
+                });
+                m.upcast()
+            }
+            MenuNodeKind::Radio { group: group_idx, index: member, selected } => {
+                let mut radio_groups = self.radio_groups.borrow_mut();
+                let group = radio_groups.entry(group_idx).or_insert_with(Vec::new);
+                let m = gtk::RadioMenuItem::new_with_label_from_widget(group.first().map(|g| &g.1),
+                                                                       node.label.as_str());
+                if selected {
+                    m.set_active(true);
+                }
+                // Selecting an entry also emits "toggled" on the one losing
+                // the selection, but "activate" only fires on the entry that
+                // was clicked.
+                m.connect_activate(move |m| {
+                    if !m.get_active() {
+                        return;
+                    }
+                    run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+                        stash.systray_radio_selected(group_idx, member);
+                    });
+                });
+                group.push((member, m.clone()));
+                m.upcast()
+            }
+        };
+        m.set_sensitive(node.enabled);
+        parent.insert(&m, position as i32);
+        // Only show the new widget. Calling show_all() on the menu would
+        // bring back entries that were hidden through set_menu_entry_visible.
+        if node.visible {
+            m.show();
+        }
+        if node.kind == MenuNodeKind::Submenu {
+            let submenu = gtk::Menu::new();
+            m.set_submenu(Some(&submenu));
+            self.submenus.borrow_mut().insert(item_idx, submenu);
+        }
+        self.menu_items.borrow_mut().insert(item_idx, m);
+        for (i, child) in node.children.iter().enumerate() {
+            self.insert_menu_node(Some(item_idx), i, child)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_radio_selected(&self, group_idx: u32, member: usize) {
+        if let Some(group) = self.radio_groups.borrow().get(&group_idx) {
+            if let Some(&(_, ref m)) = group.iter().find(|g| g.0 == member) {
+                m.set_active(true);
+            }
+        }
+    }
+
+    pub fn set_menu_entry_checked(&self, item_idx: u32, checked: bool) {
+        if let Some(m) = self.menu_items.borrow().get(&item_idx) {
+            if let Ok(m) = m.clone().downcast::<gtk::CheckMenuItem>() {
+                m.set_active(checked);
+            }
+        }
+    }
+
+    pub fn set_menu_entry_label(&self, item_idx: u32, item_name: &str) {
+        if let Some(m) = self.menu_items.borrow().get(&item_idx) {
             m.set_label(item_name);
-            self.menu.show_all();
-            return;
         }
-        let m = gtk::MenuItem::new_with_label(item_name);
-        self.menu.append(&m);
-        m.connect_activate(move |_| {
-            run_on_gtk_thread(move |stash : &GtkSystrayApp| {
-                stash.systray_menu_selected(item_idx);
-            });
+    }
+
+    pub fn set_menu_entry_icon(&self, item_idx: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        if let Some(icon) = icon {
+            check_icon_file(icon)?;
+        }
+        if let Some(m) = self.menu_items.borrow().get(&item_idx) {
+            // Only plain items and submenus are created as image menu items.
+            if let Ok(m) = m.clone().downcast::<gtk::ImageMenuItem>() {
+                match icon {
+                    Some(icon) => {
+                        m.set_image(Some(&gtk::Image::new_from_file(icon)));
+                        m.set_always_show_image(true);
+                    }
+                    None => m.set_image(None::<&gtk::Image>)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_entry_enabled(&self, item_idx: u32, enabled: bool) {
+        if let Some(m) = self.menu_items.borrow().get(&item_idx) {
+            m.set_sensitive(enabled);
+        }
+    }
+
+    pub fn set_menu_entry_visible(&self, item_idx: u32, visible: bool) {
+        if let Some(m) = self.menu_items.borrow().get(&item_idx) {
+            m.set_visible(visible);
+        }
+    }
+
+    pub fn remove_menu_entry(&self, item_idx: u32) {
+        let mut menu_items = self.menu_items.borrow_mut();
+        if let Some(m) = menu_items.remove(&item_idx) {
+            // Destroying the widget also removes it from its parent menu,
+            // and takes down any submenu along with its entries.
+            m.destroy();
+        }
+        menu_items.retain(|_, m| m.get_parent().is_some());
+        self.submenus.borrow_mut().retain(|idx, _| menu_items.contains_key(idx));
+        // Forget radio groups once all of their entries are gone.
+        self.radio_groups.borrow_mut().retain(|_, g| {
+            g.retain(|r| r.1.get_parent().is_some());
+            !g.is_empty()
         });
-        menu_items.insert(item_idx, m);
-        self.menu.show_all();
     }
 
-    pub fn set_icon_from_file(&self, file: &String) {
+    // libappindicator has no tooltip of its own. The title is what
+    // StatusNotifierItem hosts show in its place, as plain text.
+    pub fn set_tooltip(&self, tooltip: &Tooltip) {
+        self.ai.borrow_mut().set_title(&tooltip.to_plain_text());
+    }
+
+    // libappindicator only takes icon names and files, so icons from
+    // memory go through the icon cache first.
+    pub fn set_icon_from_png(&self, png: &[u8]) -> Result<(), SystrayError> {
+        self.icon_variants.borrow_mut().take();
+        let file = self.icon_cache.borrow_mut().file_for(png)?;
+        self.ai.borrow_mut().set_icon_full(&file.to_string_lossy(), "icon");
+        Ok(())
+    }
+
+    // Takes icon names as well as files.
+    pub fn set_icon_from_file(&self, file: &str) -> Result<(), SystrayError> {
+        self.icon_variants.borrow_mut().take();
+        let icon = match icon_file(file)? {
+            Some(path) => path,
+            None => {
+                self.restore_icon_theme_path();
+                file.to_string()
+            }
+        };
+        self.ai.borrow_mut().set_icon_full(&icon, "icon");
+        Ok(())
+    }
+
+    pub fn set_icon_from_theme(&self, name: &str) {
+        self.icon_variants.borrow_mut().take();
+        self.restore_icon_theme_path();
+        self.ai.borrow_mut().set_icon_full(name, "icon");
+    }
+
+    pub fn set_icon_theme_path(&self, dir: &str) {
+        *self.icon_theme_path.borrow_mut() = dir.to_string();
+        if !self.showing_icon_set.get() {
+            self.ai.borrow_mut().set_icon_theme_path(dir);
+        }
+    }
+
+    fn restore_icon_theme_path(&self) {
+        if !self.showing_icon_set.replace(false) {
+            return;
+        }
+        self.ai.borrow_mut().set_icon_theme_path(&self.icon_theme_path.borrow());
+    }
+
+    pub fn request_attention(&self, icon: &str) -> Result<(), SystrayError> {
+        let icon = icon_file(icon)?.unwrap_or_else(|| icon.to_string());
+        self.ai.borrow_mut().set_attention_icon_full(&icon, "attention");
+        self.attention.set(true);
+        self.update_status();
+        Ok(())
+    }
+
+    pub fn clear_attention(&self) {
+        self.attention.set(false);
+        self.update_status();
+    }
+
+    pub fn set_icon_label(&self, label: &str, guide: &str) {
+        self.ai.borrow_mut().set_label(label, guide);
+    }
+
+    // Hosts drop passive items from the panel, while the indicator keeps
+    // its menu and icons for when it is active again.
+    pub fn set_visible(&self, visible: bool) {
+        self.visible.set(visible);
+        self.update_status();
+    }
+
+    fn update_status(&self) {
+        let status = match (self.visible.get(), self.attention.get()) {
+            (false, _) => AppIndicatorStatus::APP_INDICATOR_STATUS_PASSIVE,
+            (true, true) => AppIndicatorStatus::APP_INDICATOR_STATUS_ATTENTION,
+            (true, false) => AppIndicatorStatus::APP_INDICATOR_STATUS_ACTIVE
+        };
+        self.ai.borrow_mut().set_status(status);
+    }
+
+    // Shown by name from a theme in the icon cache, so the host picks the
+    // rendition for its own panel size and scale.
+    fn show_icon_set(&self, set: &IconSet) -> Result<(), SystrayError> {
+        let (theme_path, name) = self.icon_cache.borrow_mut().theme_icon_for(set)?;
         let mut ai = self.ai.borrow_mut();
-        ai.set_icon_full(file, "icon");
+        ai.set_icon_theme_path(&theme_path.to_string_lossy());
+        ai.set_icon_full(&name, "icon");
+        self.showing_icon_set.set(true);
+        Ok(())
+    }
+
+    pub fn set_icon_set(&self, set: &IconSet) -> Result<(), SystrayError> {
+        self.icon_variants.borrow_mut().take();
+        self.show_icon_set(set)
+    }
+
+    pub fn set_icon_variants(&self, light: &IconSet, dark: &IconSet) -> Result<(), SystrayError> {
+        *self.icon_variants.borrow_mut() = Some((light.clone(), dark.clone()));
+        self.show_icon_variant()
+    }
+
+    fn show_icon_variant(&self) -> Result<(), SystrayError> {
+        let variants = self.icon_variants.borrow();
+        let scheme = self.portal_color_scheme.get().unwrap_or_else(settings::color_scheme);
+        match (&*variants, scheme) {
+            (&Some((ref light, _)), ColorScheme::Light) => self.show_icon_set(light),
+            (&Some((_, ref dark)), ColorScheme::Dark) => self.show_icon_set(dark),
+            (&None, _) => Ok(())
+        }
+    }
+
+    #[cfg(feature = "dbus")]
+    pub fn set_portal_color_scheme(&self, scheme: Option<ColorScheme>) {
+        self.portal_color_scheme.set(scheme);
+        if let Err(e) = self.show_icon_variant() {
+            warn!("Error switching icon variant: {}", e);
+        }
+    }
+}
+
+// The part of the window that other threads may use. Everything it does
+// is run on the GTK thread, so it needs no state of its own.
+#[derive(Clone)]
+pub struct Handle;
+
+impl Handle {
+    pub fn set_menu_entry_label(&self, item_idx: u32, item_name: &str) -> Result<(), SystrayError> {
+        let n = item_name.to_string();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_menu_entry_label(item_idx, &n);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_entry_label_nowait(&self, item_idx: u32, item_name: &str) {
+        let n = item_name.to_string();
+        run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_menu_entry_label(item_idx, &n);
+        });
+    }
+
+    pub fn set_menu_entry_checked(&self, item_idx: u32, checked: bool) -> Result<(), SystrayError> {
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_menu_entry_checked(item_idx, checked);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_from_file(&self, file: &str) -> Result<(), SystrayError> {
+        let n = file.to_string();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_icon_from_file(&n)
+        })
+    }
+
+    pub fn set_icon_from_file_nowait(&self, file: &str) {
+        let n = file.to_string();
+        run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            if let Err(e) = stash.set_icon_from_file(&n) {
+                warn!("Error setting icon from file: {}", e);
+            }
+        });
+    }
+
+    pub fn set_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
+        let t = tooltip.clone();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_tooltip(&t);
+            Ok(())
+        })
+    }
+
+    pub fn request_attention(&self, icon: &str) -> Result<(), SystrayError> {
+        let n = icon.to_string();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.request_attention(&n)
+        })
+    }
+
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.clear_attention();
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_label(&self, label: &str, guide: &str) -> Result<(), SystrayError> {
+        let (l, g) = (label.to_string(), guide.to_string());
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_icon_label(&l, &g);
+            Ok(())
+        })
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Label | Capability::NativeAttention => true,
+            Capability::TooltipProvider => false
+        }
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_visible(visible);
+            Ok(())
+        })
+    }
+
+    pub fn quit(&self) {
+        glib::idle_add(|| {
+            gtk::main_quit();
+            glib::Continue(false)
+        });
     }
 }
 
 pub struct Window {
-    gtk_loop: Option<thread::JoinHandle<()>>
+    gtk_loop: Option<thread::JoinHandle<()>>,
+    // Started along with the first icon variants
+    #[cfg(feature = "dbus")]
+    color_scheme_watch: RefCell<Option<ColorSchemeWatch>>,
 }
 
 impl Window {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<Window, SystrayError> {
+    pub fn new(event_tx: EventSender, id: &str, title: &str, category: Category) -> Result<Window, SystrayError> {
         let (tx, rx) = channel();
+        let id = id.to_string();
+        let title = title.to_string();
         let gtk_loop = thread::spawn(move || {
             GTK_STASH.with(|stash| {
-                match GtkSystrayApp::new(event_tx) {
+                match GtkSystrayApp::new(event_tx, &id, &title, category) {
                     Ok(data) => {
                         (*stash.borrow_mut()) = Some(data);
                         tx.send(Ok(()));
@@ -128,7 +635,9 @@ impl Window {
         });
         match rx.recv().unwrap() {
             Ok(()) => Ok(Window {
-                gtk_loop: Some(gtk_loop)
+                gtk_loop: Some(gtk_loop),
+                #[cfg(feature = "dbus")]
+                color_scheme_watch: RefCell::new(None),
             }),
             Err(e) => {
                 Err(e)
@@ -136,46 +645,188 @@ impl Window {
         }
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &String) -> Result<(), SystrayError> {
-        let n = item_name.clone();
-        run_on_gtk_thread(move |stash : &GtkSystrayApp| {
-            stash.add_menu_entry(item_idx, &n);
-        });
-        Ok(())
+    pub fn handle(&self) -> Handle {
+        Handle
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) -> Result<(), SystrayError> {
-        run_on_gtk_thread(move |stash : &GtkSystrayApp| {
-            stash.add_menu_separator(item_idx);
-        });
-        Ok(())
+    pub fn insert_menu_node(&self, parent_idx: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        let node = node.clone();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.insert_menu_node(parent_idx, position, &node)
+        })
+    }
+
+    pub fn set_radio_selected(&self, group_idx: u32, member: usize) -> Result<(), SystrayError> {
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_radio_selected(group_idx, member);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_entry_checked(&self, item_idx: u32, checked: bool) -> Result<(), SystrayError> {
+        self.handle().set_menu_entry_checked(item_idx, checked)
+    }
+
+    pub fn set_menu_entry_label(&self, item_idx: u32, item_name: &str) -> Result<(), SystrayError> {
+        self.handle().set_menu_entry_label(item_idx, item_name)
+    }
+
+    pub fn set_menu_entry_icon(&self, item_idx: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        let icon = icon.map(|i| i.to_string());
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_menu_entry_icon(item_idx, icon.as_ref().map(|i| i.as_str()))
+        })
+    }
+
+    pub fn set_menu_entry_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), SystrayError> {
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_menu_entry_enabled(item_idx, enabled);
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_entry_visible(&self, item_idx: u32, visible: bool) -> Result<(), SystrayError> {
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_menu_entry_visible(item_idx, visible);
+            Ok(())
+        })
+    }
+
+    pub fn remove_menu_entry(&self, item_idx: u32) -> Result<(), SystrayError> {
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.remove_menu_entry(item_idx);
+            Ok(())
+        })
+    }
+
+    // Hosts read the title through libappindicator, which never asks us
+    // for it, so there is no moment to run the provider in.
+    pub fn set_tooltip_provider(&self, _: Arc<TooltipProvider>) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
     }
 
     pub fn set_icon_from_file(&self, file: &String) -> Result<(), SystrayError> {
-        let n : String = file.clone();
-        run_on_gtk_thread(move |stash : &GtkSystrayApp| {
-            stash.set_icon_from_file(&n);
-        });
+        self.handle().set_icon_from_file(file)
+    }
+
+    pub fn set_icon_from_png(&self, png: &[u8]) -> Result<(), SystrayError> {
+        if !png.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return Err(SystrayError::OsError("Icon data is not a PNG image".to_string()));
+        }
+        let png = png.to_vec();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_icon_from_png(&png)
+        })
+    }
+
+    pub fn set_icon_set(&self, set: &IconSet) -> Result<(), SystrayError> {
+        let set = set.clone();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_icon_set(&set)
+        })
+    }
+
+    // Encoded here rather than on the GTK thread, which has a menu to run.
+    pub fn set_icon_from_rgba(&self, data: &[u8], width: u32, height: u32) -> Result<(), SystrayError> {
+        self.set_icon_from_png(&encode_png(data, width, height)?)
+    }
+
+    pub fn request_attention(&self, icon: &str) -> Result<(), SystrayError> {
+        self.handle().request_attention(icon)
+    }
+
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        self.handle().clear_attention()
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        self.handle().set_visible(visible)
+    }
+
+    pub fn set_icon_label(&self, label: &str, guide: &str) -> Result<(), SystrayError> {
+        self.handle().set_icon_label(label, guide)
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.handle().has_capability(capability)
+    }
+
+    pub fn set_icon_variants(&self, light: &IconSet, dark: &IconSet) -> Result<(), SystrayError> {
+        let (light, dark) = (light.clone(), dark.clone());
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_icon_variants(&light, &dark)
+        })?;
+        self.watch_color_scheme();
         Ok(())
     }
 
-    pub fn set_icon_from_resource(&self, resource: &str) -> Result<(), SystrayError> {
-        panic!("Not implemented on this platform!");
+    #[cfg(feature = "dbus")]
+    fn watch_color_scheme(&self) {
+        let mut watch = self.color_scheme_watch.borrow_mut();
+        if watch.is_none() {
+            *watch = Some(ColorSchemeWatch::new(|scheme| {
+                run_on_gtk_thread(move |stash : &GtkSystrayApp| {
+                    stash.set_portal_color_scheme(scheme);
+                });
+            }));
+        }
     }
 
+    // Without D-Bus, only GtkSettings tells about the color scheme.
+    #[cfg(not(feature = "dbus"))]
+    fn watch_color_scheme(&self) {}
+
+    pub fn set_icon_from_theme(&self, name: &str) -> Result<(), SystrayError> {
+        if name.is_empty() || name.contains('/') {
+            return Err(SystrayError::OsError(format!("Invalid icon name: {}", name)));
+        }
+        let n = name.to_string();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_icon_from_theme(&n);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_theme_path(&self, dir: &str) -> Result<(), SystrayError> {
+        if !Path::new(dir).is_dir() {
+            return Err(SystrayError::OsError(format!("No icon theme directory at {}", dir)));
+        }
+        let d = dir.to_string();
+        call_on_gtk_thread(move |stash : &GtkSystrayApp| {
+            stash.set_icon_theme_path(&d);
+            Ok(())
+        })
+    }
+
+    // There are no resources compiled into Linux binaries.
+    pub fn set_icon_from_resource(&self, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+
+    // The indicator is hidden before its icon files go, so the host does
+    // not look for them anymore. This waits for the files to be gone, as the
+    // process may well exit right after. Should the GTK thread be gone
+    // already, the files went along with it.
     pub fn shutdown(&self) -> Result<(), SystrayError> {
-        Ok(())
+        #[cfg(feature = "dbus")]
+        self.color_scheme_watch.borrow_mut().take();
+        let cleared = call_on_gtk_thread(|stash : &GtkSystrayApp| {
+            stash.set_visible(false);
+            stash.icon_cache.borrow_mut().clear();
+            Ok(())
+        });
+        match cleared {
+            Err(SystrayError::Disconnected) => Ok(()),
+            result => result
+        }
     }
 
-    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), SystrayError> {
-        panic!("Not implemented on this platform!");
+    pub fn set_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
+        self.handle().set_tooltip(tooltip)
     }
 
     pub fn quit(&self) {
-        glib::idle_add(|| {
-            gtk::main_quit();
-            glib::Continue(false)
-        });
+        self.handle().quit()
     }
 
 }
@@ -0,0 +1,78 @@
+use glib::translate::from_glib_full;
+use gobject_sys;
+use gtk_sys;
+use std;
+use std::os::raw::{c_char, c_int, c_void};
+use ColorScheme;
+
+// GtkSettings, which gtk 0.1 has no bindings for. Under X11 it follows the
+// XSettings of the desktop, so it knows the theme of the panel too.
+
+type NotifyHandler = Box<Fn() + 'static>;
+
+unsafe extern "C" fn notify_trampoline(_: *mut gobject_sys::GObject, _: *mut gobject_sys::GParamSpec,
+                                       f: *mut c_void) {
+    let f = &*(f as *const NotifyHandler);
+    f();
+}
+
+unsafe extern "C" fn drop_notify_handler(f: *mut c_void, _: *mut gobject_sys::GClosure) {
+    drop(Box::from_raw(f as *mut NotifyHandler));
+}
+
+fn settings() -> Option<*mut gobject_sys::GObject> {
+    let raw = unsafe { gtk_sys::gtk_settings_get_default() };
+    if raw.is_null() {
+        None
+    } else {
+        Some(raw as *mut gobject_sys::GObject)
+    }
+}
+
+// Dark if the theme asks for it, or goes by a name like Adwaita-dark.
+pub fn color_scheme() -> ColorScheme {
+    let settings = match settings() {
+        Some(s) => s,
+        None => return ColorScheme::Light
+    };
+    let mut prefer_dark: c_int = 0;
+    let name: Option<String> = unsafe {
+        gobject_sys::g_object_get(settings,
+                                  b"gtk-application-prefer-dark-theme\0".as_ptr() as *const c_char,
+                                  &mut prefer_dark,
+                                  std::ptr::null::<c_char>());
+        let mut name: *mut c_char = std::ptr::null_mut();
+        gobject_sys::g_object_get(settings,
+                                  b"gtk-theme-name\0".as_ptr() as *const c_char,
+                                  &mut name,
+                                  std::ptr::null::<c_char>());
+        from_glib_full(name)
+    };
+    let dark_name = name.map(|n| n.to_lowercase().contains("dark")).unwrap_or(false);
+    if prefer_dark != 0 || dark_name {
+        ColorScheme::Dark
+    } else {
+        ColorScheme::Light
+    }
+}
+
+// Called whenever the theme changes, such as when the desktop switches it
+// through XSettings.
+pub fn connect_theme_changed<F>(f: F)
+    where F: Fn() + 'static {
+    let settings = match settings() {
+        Some(s) => s,
+        None => return
+    };
+    let f: Box<NotifyHandler> = Box::new(Box::new(f));
+    unsafe {
+        let handler: unsafe extern "C" fn(*mut gobject_sys::GObject, *mut gobject_sys::GParamSpec, *mut c_void) =
+            notify_trampoline;
+        gobject_sys::g_signal_connect_data(settings,
+                                           b"notify::gtk-theme-name\0".as_ptr() as *const _,
+                                           Some(std::mem::transmute(handler)),
+                                           Box::into_raw(f) as *mut c_void,
+                                           Some(drop_notify_handler),
+                                           gobject_sys::GConnectFlags::empty());
+    }
+}
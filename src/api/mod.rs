@@ -2,10 +2,17 @@
 #[path="win32/mod.rs"]
 pub mod api;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "appindicator", not(feature = "sni")))]
 #[path="linux/mod.rs"]
 pub mod api;
 
+#[cfg(all(target_os = "linux", feature = "sni"))]
+#[path="sni/mod.rs"]
+pub mod api;
+
+#[cfg(all(target_os = "linux", not(any(feature = "appindicator", feature = "sni"))))]
+compile_error!("Linux needs either the appindicator or the sni feature");
+
 #[cfg(target_os = "macos")]
 #[path="cocoa/mod.rs"]
 pub mod api;
@@ -0,0 +1,418 @@
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use {Category, ColorScheme, EventSender, IconSet, ScrollOrientation, SystrayEvent, SystrayError, Tooltip,
+     TooltipProvider};
+use dbusmenu::DbusMenu;
+use icon_cache::IconCache;
+use super::pixmap::{self, Pixmap};
+
+pub const ITEM_PATH: &'static str = "/StatusNotifierItem";
+const ITEM: &'static str = "org.kde.StatusNotifierItem";
+const WATCHER: &'static str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &'static str = "/StatusNotifierWatcher";
+const PROPERTIES: &'static str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE: &'static str = "org.freedesktop.DBus.Introspectable";
+pub const MENU_PATH: &'static str = "/MenuBar";
+
+// How long to wait for the watcher to take the registration.
+const CALL_TIMEOUT_MS: u64 = 2000;
+// How often the bus thread looks for signals to send and whether to stop.
+const POLL_MS: u64 = 100;
+
+const PROPERTY_NAMES: [&'static str; 13] = [
+    "Category", "Id", "Title", "Status", "IconName", "IconPixmap", "IconThemePath", "AttentionIconName",
+    "ToolTip", "ItemIsMenu", "Menu", "XAyatanaLabel", "XAyatanaLabelGuide",
+];
+
+const INTROSPECTION: &'static str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.kde.StatusNotifierItem">
+    <property name="Category" type="s" access="read"/>
+    <property name="Id" type="s" access="read"/>
+    <property name="Title" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconName" type="s" access="read"/>
+    <property name="IconPixmap" type="a(iiay)" access="read"/>
+    <property name="IconThemePath" type="s" access="read"/>
+    <property name="AttentionIconName" type="s" access="read"/>
+    <property name="ToolTip" type="(sa(iiay)ss)" access="read"/>
+    <property name="ItemIsMenu" type="b" access="read"/>
+    <property name="Menu" type="o" access="read"/>
+    <property name="XAyatanaLabel" type="s" access="read"/>
+    <property name="XAyatanaLabelGuide" type="s" access="read"/>
+    <method name="Activate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="SecondaryActivate"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="ContextMenu"><arg name="x" type="i" direction="in"/><arg name="y" type="i" direction="in"/></method>
+    <method name="Scroll"><arg name="delta" type="i" direction="in"/><arg name="orientation" type="s" direction="in"/></method>
+    <signal name="NewTitle"/>
+    <signal name="NewIcon"/>
+    <signal name="NewAttentionIcon"/>
+    <signal name="NewToolTip"/>
+    <signal name="NewStatus"><arg name="status" type="s"/></signal>
+    <signal name="NewIconThemePath"><arg name="icon_theme_path" type="s"/></signal>
+    <signal name="XAyatanaNewLabel"><arg name="label" type="s"/><arg name="guide" type="s"/></signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get"><arg type="s" direction="in"/><arg type="s" direction="in"/><arg type="v" direction="out"/></method>
+    <method name="GetAll"><arg type="s" direction="in"/><arg type="a{sv}" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg type="s" direction="out"/></method>
+  </interface>
+</node>
+"#;
+
+// Signals telling hosts to read properties again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    NewIcon,
+    NewAttentionIcon,
+    NewToolTip,
+    NewStatus,
+    NewIconThemePath,
+    XAyatanaNewLabel,
+}
+
+// Everything hosts can read from the item. Shared between the bus thread,
+// which answers for it, and the handles changing it.
+pub struct Item {
+    pub id: String,
+    pub title: String,
+    pub category: Category,
+    pub icon_name: String,
+    pub icon_pixmaps: Vec<Pixmap>,
+    // The theme path given by the user, and the one of the icon cache
+    // while an icon set is shown from there.
+    pub icon_theme_path: String,
+    pub icon_set_theme_path: Option<String>,
+    pub attention_icon_name: String,
+    pub tooltip: Tooltip,
+    // Asked whenever a host reads the tooltip, in place of tooltip
+    pub tooltip_provider: Option<Arc<TooltipProvider>>,
+    pub label: String,
+    pub label_guide: String,
+    pub visible: bool,
+    pub attention: bool,
+    // Light and dark icons, the one matching color_scheme is shown
+    pub icon_variants: Option<(IconSet, IconSet)>,
+    pub color_scheme: Option<ColorScheme>,
+    pub icon_cache: IconCache,
+    // Exported at MENU_PATH
+    pub menu: DbusMenu,
+    // Taken when the bus thread ends, which disconnects the application.
+    pub event_tx: Option<EventSender>,
+}
+
+impl Item {
+    pub fn new(event_tx: EventSender, id: &str, title: &str, category: Category) -> Item {
+        Item {
+            id: id.to_string(),
+            title: title.to_string(),
+            category: category,
+            icon_name: String::new(),
+            icon_pixmaps: vec![],
+            icon_theme_path: String::new(),
+            icon_set_theme_path: None,
+            attention_icon_name: String::new(),
+            tooltip: Tooltip::default(),
+            tooltip_provider: None,
+            label: String::new(),
+            label_guide: String::new(),
+            visible: true,
+            attention: false,
+            icon_variants: None,
+            color_scheme: None,
+            icon_cache: IconCache::new(),
+            menu: DbusMenu::new(MENU_PATH),
+            event_tx: Some(event_tx),
+        }
+    }
+
+    pub fn theme_path(&self) -> &str {
+        self.icon_set_theme_path.as_ref().unwrap_or(&self.icon_theme_path)
+    }
+
+    fn status(&self) -> &'static str {
+        match (self.visible, self.attention) {
+            (false, _) => "Passive",
+            (true, true) => "NeedsAttention",
+            (true, false) => "Active"
+        }
+    }
+
+    // Every icon but those from icon sets goes through here.
+    pub fn set_icon(&mut self, name: &str, pixmaps: Vec<Pixmap>) {
+        self.icon_variants = None;
+        self.icon_set_theme_path = None;
+        self.icon_name = name.to_string();
+        self.icon_pixmaps = pixmaps;
+    }
+
+    // PNG renditions go out as pixmaps, of which hosts pick the size they
+    // need. An SVG rendition is written to a theme in the icon cache and
+    // named as the icon, which hosts prefer where they find it.
+    pub fn show_icon_set(&mut self, set: &IconSet) -> Result<(), SystrayError> {
+        let mut pixmaps = vec![];
+        for &(_, ref png) in set.pngs() {
+            pixmaps.push(pixmap::from_png(png)?);
+        }
+        let (name, theme_path) = match set.svg_data() {
+            Some(_) => {
+                let (theme_path, name) = self.icon_cache.theme_icon_for(set)?;
+                (name, Some(theme_path.to_string_lossy().into_owned()))
+            }
+            None => (String::new(), None)
+        };
+        self.icon_name = name;
+        self.icon_pixmaps = pixmaps;
+        self.icon_set_theme_path = theme_path;
+        Ok(())
+    }
+
+    // Without a preference from the portal, panels are taken to be light.
+    pub fn show_icon_variant(&mut self) -> Result<(), SystrayError> {
+        let set = match (&self.icon_variants, self.color_scheme) {
+            (&Some((_, ref dark)), Some(ColorScheme::Dark)) => dark.clone(),
+            (&Some((ref light, _)), _) => light.clone(),
+            (&None, _) => return Ok(())
+        };
+        self.show_icon_set(&set)
+    }
+
+    fn send_event(&self, event: SystrayEvent) {
+        if let Some(ref tx) = self.event_tx {
+            tx.send(event).ok();
+        }
+    }
+}
+
+fn category_name(category: Category) -> &'static str {
+    match category {
+        Category::ApplicationStatus => "ApplicationStatus",
+        Category::Communications => "Communications",
+        Category::SystemServices => "SystemServices",
+        Category::Hardware => "Hardware",
+    }
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<RefArg>> {
+    Variant(Box::new(value) as Box<RefArg>)
+}
+
+// The provider is called without the item locked, since it may well
+// change the item itself.
+fn property(item: &Mutex<Item>, name: &str) -> Option<Variant<Box<RefArg>>> {
+    if name == "ToolTip" {
+        let (tooltip, provider) = {
+            let item = item.lock().unwrap();
+            (item.tooltip.clone(), item.tooltip_provider.clone())
+        };
+        let tooltip = provider.map(|p| p.get()).unwrap_or(tooltip);
+        let no_pixmaps: Vec<Pixmap> = vec![];
+        return Some(variant((tooltip.icon.unwrap_or_default(), no_pixmaps, tooltip.title, tooltip.body)));
+    }
+    let item = item.lock().unwrap();
+    Some(match name {
+        "Category" => variant(category_name(item.category).to_string()),
+        "Id" => variant(item.id.clone()),
+        "Title" => variant(item.title.clone()),
+        "Status" => variant(item.status().to_string()),
+        "IconName" => variant(item.icon_name.clone()),
+        "IconPixmap" => variant(item.icon_pixmaps.clone()),
+        "IconThemePath" => variant(item.theme_path().to_string()),
+        "AttentionIconName" => variant(item.attention_icon_name.clone()),
+        "ItemIsMenu" => variant(false),
+        "Menu" => variant(::dbus::Path::from(MENU_PATH)),
+        "XAyatanaLabel" => variant(item.label.clone()),
+        "XAyatanaLabelGuide" => variant(item.label_guide.clone()),
+        _ => return None
+    })
+}
+
+fn error_reply(msg: &Message, name: &str, text: &str) -> Message {
+    msg.error(&name.into(), &CString::new(text).unwrap())
+}
+
+fn invalid_args(msg: &Message) -> Message {
+    error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs", "Invalid arguments")
+}
+
+// Hosts pass where the pointer was, or nothing useful, as 0, 0.
+fn pointer_event(msg: &Message, item: &Mutex<Item>, event: fn(Instant, Option<(i32, i32)>) -> SystrayEvent) -> Message {
+    match msg.read2::<i32, i32>() {
+        Ok((x, y)) => {
+            item.lock().unwrap().send_event(event(Instant::now(), Some((x, y))));
+            msg.method_return()
+        }
+        Err(_) => invalid_args(msg)
+    }
+}
+
+// Hosts scroll up and to the right with positive deltas.
+fn scroll_event(msg: &Message, item: &Mutex<Item>) -> Message {
+    let (delta, orientation) = match msg.read2::<i32, &str>() {
+        Ok(args) => args,
+        Err(_) => return invalid_args(msg)
+    };
+    let (delta, orientation) = match &*orientation.to_lowercase() {
+        "vertical" => (-delta, ScrollOrientation::Vertical),
+        "horizontal" => (delta, ScrollOrientation::Horizontal),
+        _ => return invalid_args(msg)
+    };
+    item.lock().unwrap().send_event(SystrayEvent::Scroll {
+        delta: delta,
+        orientation: orientation,
+        time: Instant::now(),
+        position: None,
+    });
+    msg.method_return()
+}
+
+fn handle_call(msg: &Message, item: &Mutex<Item>) -> Message {
+    let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+    let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+    match (&*interface, &*member) {
+        (ITEM, "Activate") => pointer_event(msg, item, |time, position| {
+            SystrayEvent::IconActivated { time: time, position: position }
+        }),
+        (ITEM, "SecondaryActivate") => pointer_event(msg, item, |time, position| {
+            SystrayEvent::SecondaryActivate { time: time, position: position }
+        }),
+        (ITEM, "ContextMenu") => pointer_event(msg, item, |time, position| {
+            SystrayEvent::ContextMenuRequested { time: time, position: position }
+        }),
+        (ITEM, "Scroll") => scroll_event(msg, item),
+        (PROPERTIES, "Get") => match msg.read2::<&str, &str>() {
+            Ok((ITEM, name)) => match property(item, name) {
+                Some(value) => msg.method_return().append1(value),
+                None => error_reply(msg, "org.freedesktop.DBus.Error.UnknownProperty",
+                                    &format!("No property {}", name))
+            },
+            Ok((interface, _)) => error_reply(msg, "org.freedesktop.DBus.Error.UnknownInterface",
+                                              &format!("No interface {}", interface)),
+            Err(_) => invalid_args(msg)
+        },
+        (PROPERTIES, "GetAll") => match msg.read1::<&str>() {
+            Ok(ITEM) => {
+                let all: ::dbus::arg::PropMap = PROPERTY_NAMES.iter()
+                    .filter_map(|&name| property(item, name).map(|v| (name.to_string(), v)))
+                    .collect();
+                msg.method_return().append1(all)
+            }
+            Ok(_) => msg.method_return().append1(::dbus::arg::PropMap::new()),
+            Err(_) => invalid_args(msg)
+        },
+        (PROPERTIES, "Set") => error_reply(msg, "org.freedesktop.DBus.Error.PropertyReadOnly",
+                                           "All properties are read only"),
+        (INTROSPECTABLE, "Introspect") => msg.method_return().append1(INTROSPECTION),
+        _ => error_reply(msg, "org.freedesktop.DBus.Error.UnknownMethod",
+                         &format!("No method {}.{}", interface, member))
+    }
+}
+
+fn signal_message(signal: Signal, item: &Item) -> Message {
+    let name = format!("{:?}", signal);
+    let msg = Message::new_signal(ITEM_PATH, ITEM, name).unwrap();
+    match signal {
+        Signal::NewStatus => msg.append1(item.status()),
+        Signal::NewIconThemePath => msg.append1(item.theme_path()),
+        Signal::XAyatanaNewLabel => msg.append2(&*item.label, &*item.label_guide),
+        _ => msg
+    }
+}
+
+// Registration goes by the well-known name of the item. It has to be
+// repeated whenever a new watcher comes up, like after a panel restart.
+fn register(conn: &Connection, name: &str) {
+    let proxy = conn.with_proxy(WATCHER, WATCHER_PATH, Duration::from_millis(CALL_TIMEOUT_MS));
+    let registered: Result<(), _> = proxy.method_call(WATCHER, "RegisterStatusNotifierItem", (name,));
+    if let Err(e) = registered {
+        warn!("Could not register with a StatusNotifierWatcher: {}", e);
+    }
+}
+
+fn bus_error(e: ::dbus::Error) -> SystrayError {
+    SystrayError::OsError(format!("D-Bus error: {}", e))
+}
+
+// Takes name on the session bus, so the item can be found there, and
+// answers for it and its menu from the calling thread until stop is set.
+// Sends the signals that come in, each once however often it was asked for.
+// ready learns whether the item made it onto the bus.
+pub fn serve(name: String, item: Arc<Mutex<Item>>, signals: Receiver<Signal>, stop: Arc<AtomicBool>,
+             ready: ::std::sync::mpsc::Sender<Result<(), SystrayError>>) {
+    let conn = match Connection::new_session()
+        .and_then(|c| c.request_name(&*name, false, true, true).map(|_| c)) {
+        Ok(c) => c,
+        Err(e) => {
+            ready.send(Err(bus_error(e))).ok();
+            return;
+        }
+    };
+    let call_item = item.clone();
+    conn.start_receive(MatchRule::new_method_call().with_path(ITEM_PATH), Box::new(move |msg, conn| {
+        conn.send(handle_call(&msg, &call_item)).ok();
+        true
+    }));
+    let menu_item = item.clone();
+    conn.start_receive(MatchRule::new_method_call().with_path(MENU_PATH), Box::new(move |msg, conn| {
+        let mut item = menu_item.lock().unwrap();
+        let (reply, events) = item.menu.handle_call(&msg);
+        for event in events {
+            item.send_event(event);
+        }
+        conn.send(reply).ok();
+        true
+    }));
+    let (watcher_tx, watcher_rx) = channel();
+    let rule = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged").with_sender("org.freedesktop.DBus");
+    let added = conn.add_match(rule, move |(bus_name, _, owner): (String, String, String), _, _| {
+        if bus_name == WATCHER && !owner.is_empty() {
+            watcher_tx.send(()).ok();
+        }
+        true
+    });
+    if let Err(e) = added {
+        ready.send(Err(bus_error(e))).ok();
+        return;
+    }
+    register(&conn, &name);
+    ready.send(Ok(())).ok();
+    while !stop.load(Ordering::SeqCst) {
+        if let Err(e) = conn.process(Duration::from_millis(POLL_MS)) {
+            warn!("Error serving the StatusNotifierItem: {}", e);
+            break;
+        }
+        let mut pending = vec![];
+        for signal in signals.try_iter() {
+            if !pending.contains(&signal) {
+                pending.push(signal);
+            }
+        }
+        {
+            let mut item = item.lock().unwrap();
+            for signal in pending {
+                conn.send(signal_message(signal, &item)).ok();
+            }
+            for signal in item.menu.take_signals() {
+                conn.send(signal).ok();
+            }
+        }
+        if watcher_rx.try_iter().count() > 0 {
+            register(&conn, &name);
+        }
+    }
+    // Off the bus first, so the item is gone once the application learns
+    // that it is.
+    drop(conn);
+    item.lock().unwrap().event_tx.take();
+}
@@ -0,0 +1,344 @@
+// StatusNotifierItem spoken over D-Bus directly, for Linux desktops
+// without GTK to go through. The item is served from a thread of its own,
+// which only ever talks to the bus.
+
+use std;
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use {SystrayError, Capability, Category, EventSender, IconSet, MenuNode, Tooltip, TooltipProvider};
+use dbusmenu::DbusMenu;
+use icon_cache::icon_file;
+use portal::ColorSchemeWatch;
+use reconcile::MenuBackend;
+
+mod item;
+mod pixmap;
+use self::item::{Item, Signal};
+
+// Numbers the items of this process, which each need a name of their own.
+static ITEM_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Changes the icon through f and tells hosts, about the theme path as well
+// when the icon took it along.
+fn change_icon<F>(item: &Mutex<Item>, signals: &Sender<Signal>, f: F) -> Result<(), SystrayError>
+    where F: FnOnce(&mut Item) -> Result<(), SystrayError> {
+    let theme_path_changed = {
+        let mut item = item.lock().unwrap();
+        let theme_path = item.theme_path().to_string();
+        f(&mut item)?;
+        item.theme_path() != theme_path
+    };
+    if theme_path_changed {
+        signals.send(Signal::NewIconThemePath).map_err(|_| SystrayError::Disconnected)?;
+    }
+    signals.send(Signal::NewIcon).map_err(|_| SystrayError::Disconnected)
+}
+
+// Everything lives in the item, so handles work from any thread. Hosts
+// learn about changes once the bus thread passes on the signals.
+#[derive(Clone)]
+pub struct Handle {
+    item: Arc<Mutex<Item>>,
+    signals: Sender<Signal>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Handle {
+    // Fails once the bus thread is gone.
+    fn emit(&self, signal: Signal) -> Result<(), SystrayError> {
+        self.signals.send(signal).map_err(|_| SystrayError::Disconnected)
+    }
+
+    fn change_icon<F>(&self, f: F) -> Result<(), SystrayError>
+        where F: FnOnce(&mut Item) -> Result<(), SystrayError> {
+        change_icon(&self.item, &self.signals, f)
+    }
+
+    // The menu is exported by the bus thread, which tells hosts about the
+    // change on its next round.
+    fn change_menu<F>(&self, f: F) -> Result<(), SystrayError>
+        where F: FnOnce(&mut DbusMenu) -> Result<(), SystrayError> {
+        f(&mut self.item.lock().unwrap().menu)
+    }
+
+    pub fn set_menu_entry_label(&self, item_idx: u32, item_name: &str) -> Result<(), SystrayError> {
+        self.change_menu(|menu| menu.set_menu_entry_label(item_idx, item_name))
+    }
+
+    pub fn set_menu_entry_label_nowait(&self, item_idx: u32, item_name: &str) {
+        if let Err(e) = self.set_menu_entry_label(item_idx, item_name) {
+            warn!("Error setting menu entry label: {}", e);
+        }
+    }
+
+    pub fn set_menu_entry_checked(&self, item_idx: u32, checked: bool) -> Result<(), SystrayError> {
+        self.change_menu(|menu| menu.set_menu_entry_checked(item_idx, checked))
+    }
+
+    // Takes icon names as well as files. Files are passed on by path as
+    // the icon name, like libappindicator does.
+    pub fn set_icon_from_file(&self, file: &str) -> Result<(), SystrayError> {
+        let icon = icon_file(file)?.unwrap_or_else(|| file.to_string());
+        self.change_icon(|item| {
+            item.set_icon(&icon, vec![]);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_from_file_nowait(&self, file: &str) {
+        if let Err(e) = self.set_icon_from_file(file) {
+            warn!("Error setting icon from file: {}", e);
+        }
+    }
+
+    pub fn set_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
+        {
+            let mut item = self.item.lock().unwrap();
+            item.tooltip = tooltip.clone();
+            item.tooltip_provider = None;
+        }
+        self.emit(Signal::NewToolTip)
+    }
+
+    pub fn request_attention(&self, icon: &str) -> Result<(), SystrayError> {
+        let icon = icon_file(icon)?.unwrap_or_else(|| icon.to_string());
+        {
+            let mut item = self.item.lock().unwrap();
+            item.attention_icon_name = icon;
+            item.attention = true;
+        }
+        self.emit(Signal::NewAttentionIcon)?;
+        self.emit(Signal::NewStatus)
+    }
+
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        self.item.lock().unwrap().attention = false;
+        self.emit(Signal::NewStatus)
+    }
+
+    pub fn set_icon_label(&self, label: &str, guide: &str) -> Result<(), SystrayError> {
+        {
+            let mut item = self.item.lock().unwrap();
+            item.label = label.to_string();
+            item.label_guide = guide.to_string();
+        }
+        self.emit(Signal::XAyatanaNewLabel)
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Label | Capability::NativeAttention | Capability::TooltipProvider => true
+        }
+    }
+
+    // Hosts drop passive items from the panel, while the item keeps
+    // everything for when it is active again.
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        self.item.lock().unwrap().visible = visible;
+        self.emit(Signal::NewStatus)
+    }
+
+    // The bus thread notices within a poll and leaves the bus, which takes
+    // the item off the panel and ends the application's events.
+    pub fn quit(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+pub struct Window {
+    handle: Handle,
+    bus_thread: RefCell<Option<thread::JoinHandle<()>>>,
+    // Follows the portal once set_icon_variants is first called
+    color_scheme_watch: RefCell<Option<ColorSchemeWatch>>,
+}
+
+impl Window {
+    pub fn new(event_tx: EventSender, id: &str, title: &str, category: Category) -> Result<Window, SystrayError> {
+        let name = format!("org.kde.StatusNotifierItem-{}-{}", std::process::id(),
+                           ITEM_COUNT.fetch_add(1, Ordering::SeqCst) + 1);
+        let item = Arc::new(Mutex::new(Item::new(event_tx, id, title, category)));
+        let (signals_tx, signals_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let bus_item = item.clone();
+        let bus_stop = stop.clone();
+        let bus_thread = thread::spawn(move || {
+            item::serve(name, bus_item, signals_rx, bus_stop, ready_tx);
+        });
+        ready_rx.recv()??;
+        Ok(Window {
+            handle: Handle {
+                item: item,
+                signals: signals_tx,
+                stop: stop,
+            },
+            bus_thread: RefCell::new(Some(bus_thread)),
+            color_scheme_watch: RefCell::new(None),
+        })
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    pub fn insert_menu_node(&self, parent: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        self.handle.change_menu(|menu| menu.insert_menu_node(parent, position, node))
+    }
+
+    pub fn set_radio_selected(&self, group_idx: u32, index: usize) -> Result<(), SystrayError> {
+        self.handle.change_menu(|menu| menu.set_radio_selected(group_idx, index))
+    }
+
+    pub fn set_menu_entry_checked(&self, item_idx: u32, checked: bool) -> Result<(), SystrayError> {
+        self.handle.set_menu_entry_checked(item_idx, checked)
+    }
+
+    pub fn set_menu_entry_label(&self, item_idx: u32, item_name: &str) -> Result<(), SystrayError> {
+        self.handle.set_menu_entry_label(item_idx, item_name)
+    }
+
+    pub fn set_menu_entry_icon(&self, item_idx: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        self.handle.change_menu(|menu| menu.set_menu_entry_icon(item_idx, icon))
+    }
+
+    pub fn set_menu_entry_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), SystrayError> {
+        self.handle.change_menu(|menu| menu.set_menu_entry_enabled(item_idx, enabled))
+    }
+
+    pub fn set_menu_entry_visible(&self, item_idx: u32, visible: bool) -> Result<(), SystrayError> {
+        self.handle.change_menu(|menu| menu.set_menu_entry_visible(item_idx, visible))
+    }
+
+    pub fn remove_menu_entry(&self, item_idx: u32) -> Result<(), SystrayError> {
+        self.handle.change_menu(|menu| menu.remove_menu_entry(item_idx))
+    }
+
+    // Hosts read the tooltip whenever they show it, which is when the
+    // provider gets asked.
+    pub fn set_tooltip_provider(&self, provider: Arc<TooltipProvider>) -> Result<(), SystrayError> {
+        self.handle.item.lock().unwrap().tooltip_provider = Some(provider);
+        self.handle.emit(Signal::NewToolTip)
+    }
+
+    pub fn set_icon_from_file(&self, file: &String) -> Result<(), SystrayError> {
+        self.handle.set_icon_from_file(file)
+    }
+
+    pub fn set_icon_from_png(&self, png: &[u8]) -> Result<(), SystrayError> {
+        let pixmap = pixmap::from_png(png)?;
+        self.handle.change_icon(|item| {
+            item.set_icon("", vec![pixmap]);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_from_rgba(&self, data: &[u8], width: u32, height: u32) -> Result<(), SystrayError> {
+        let pixmap = pixmap::from_rgba(data, width, height);
+        self.handle.change_icon(|item| {
+            item.set_icon("", vec![pixmap]);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_set(&self, set: &IconSet) -> Result<(), SystrayError> {
+        self.handle.change_icon(|item| {
+            item.icon_variants = None;
+            item.show_icon_set(set)
+        })
+    }
+
+    pub fn set_icon_variants(&self, light: &IconSet, dark: &IconSet) -> Result<(), SystrayError> {
+        self.handle.change_icon(|item| {
+            item.icon_variants = Some((light.clone(), dark.clone()));
+            item.show_icon_variant()
+        })?;
+        let mut watch = self.color_scheme_watch.borrow_mut();
+        if watch.is_none() {
+            let (item, signals) = (self.handle.item.clone(), self.handle.signals.clone());
+            *watch = Some(ColorSchemeWatch::new(move |scheme| {
+                let changed = change_icon(&item, &signals, |item| {
+                    item.color_scheme = scheme;
+                    item.show_icon_variant()
+                });
+                if let Err(e) = changed {
+                    warn!("Error switching icon variant: {}", e);
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    pub fn request_attention(&self, icon: &str) -> Result<(), SystrayError> {
+        self.handle.request_attention(icon)
+    }
+
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        self.handle.clear_attention()
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        self.handle.set_visible(visible)
+    }
+
+    pub fn set_icon_label(&self, label: &str, guide: &str) -> Result<(), SystrayError> {
+        self.handle.set_icon_label(label, guide)
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.handle.has_capability(capability)
+    }
+
+    pub fn set_icon_from_theme(&self, name: &str) -> Result<(), SystrayError> {
+        if name.is_empty() || name.contains('/') {
+            return Err(SystrayError::OsError(format!("Invalid icon name: {}", name)));
+        }
+        self.handle.change_icon(|item| {
+            item.set_icon(name, vec![]);
+            Ok(())
+        })
+    }
+
+    pub fn set_icon_theme_path(&self, dir: &str) -> Result<(), SystrayError> {
+        if !Path::new(dir).is_dir() {
+            return Err(SystrayError::OsError(format!("No icon theme directory at {}", dir)));
+        }
+        let shown = {
+            let mut item = self.handle.item.lock().unwrap();
+            item.icon_theme_path = dir.to_string();
+            item.icon_set_theme_path.is_none()
+        };
+        if shown {
+            self.handle.emit(Signal::NewIconThemePath)?;
+        }
+        Ok(())
+    }
+
+    // Icon resources are only compiled into Windows binaries.
+    pub fn set_icon_from_resource(&self, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+
+    // Leaves the bus, so hosts drop the item right away rather than once
+    // the process exits.
+    pub fn shutdown(&self) -> Result<(), SystrayError> {
+        self.color_scheme_watch.borrow_mut().take();
+        self.handle.quit();
+        if let Some(t) = self.bus_thread.borrow_mut().take() {
+            t.join().ok();
+        }
+        self.handle.item.lock().unwrap().icon_cache.clear();
+        Ok(())
+    }
+
+    pub fn set_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
+        self.handle.set_tooltip(tooltip)
+    }
+
+    pub fn quit(&self) {
+        self.handle.quit()
+    }
+}
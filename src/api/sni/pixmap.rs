@@ -0,0 +1,45 @@
+use png;
+use SystrayError;
+
+// An icon as StatusNotifierItem carries it: width, height and the pixels
+// row by row, each as ARGB32 in network byte order.
+pub type Pixmap = (i32, i32, Vec<u8>);
+
+fn decode_error(e: png::DecodingError) -> SystrayError {
+    SystrayError::OsError(format!("Error decoding icon: {}", e))
+}
+
+pub fn from_rgba(data: &[u8], width: u32, height: u32) -> Pixmap {
+    let mut argb = Vec::with_capacity(data.len());
+    for px in data.chunks(4) {
+        argb.extend_from_slice(&[px[3], px[0], px[1], px[2]]);
+    }
+    (width as i32, height as i32, argb)
+}
+
+// Hosts get no PNG, so it is decoded here, whatever its color type.
+pub fn from_png(data: &[u8]) -> Result<Pixmap, SystrayError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().map_err(decode_error)?;
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf).map_err(decode_error)?;
+    if info.bit_depth != png::BitDepth::Eight {
+        return Err(SystrayError::OsError(format!("Unsupported icon bit depth {:?}", info.bit_depth)));
+    }
+    let mut rgba = Vec::with_capacity(info.width as usize * info.height as usize * 4);
+    match info.color_type {
+        png::ColorType::RGBA => rgba = buf,
+        png::ColorType::RGB => for px in buf.chunks(3) {
+            rgba.extend_from_slice(&[px[0], px[1], px[2], 255]);
+        },
+        png::ColorType::GrayscaleAlpha => for px in buf.chunks(2) {
+            rgba.extend_from_slice(&[px[0], px[0], px[0], px[1]]);
+        },
+        png::ColorType::Grayscale => for &g in &buf {
+            rgba.extend_from_slice(&[g, g, g, 255]);
+        },
+        t => return Err(SystrayError::OsError(format!("Unsupported icon color type {:?}", t)))
+    }
+    Ok(from_rgba(&rgba, info.width, info.height))
+}
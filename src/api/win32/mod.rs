@@ -1,19 +1,26 @@
 mod winapipatch;
 use self::winapipatch::*;
-use {SystrayEvent, SystrayError};
+use {SystrayEvent, SystrayError, Capability, Category, ColorScheme, EventSender, IconSet, MenuNode, MenuNodeKind, Tooltip, TooltipProvider};
+use icon_theme::IconTheme;
 use std;
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::channel;
 use std::os::windows::ffi::OsStrExt;
 use std::ffi::OsStr;
 use std::thread;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use winapi;
 use winapi::{MENUITEMINFOW, UINT};
 use user32;
 use kernel32;
-use winapi::windef::{HWND, HMENU, HICON, HBRUSH, HBITMAP};
+use winapi::windef::{HWND, HMENU, HICON, HBRUSH, HBITMAP, HGDIOBJ};
 use winapi::winnt::{LPCWSTR};
 use winapi::minwindef::{DWORD, WPARAM, LPARAM, LRESULT, HINSTANCE, TRUE, PBYTE};
+use winapi::basetsd::UINT_PTR;
 use winapi::winuser::{WNDCLASSW, WS_OVERLAPPEDWINDOW, CW_USEDEFAULT, LR_DEFAULTCOLOR};
 
 
@@ -25,6 +32,12 @@ fn to_wstring(str : &str) -> Vec<u16> {
     OsStr::new(str).encode_wide().chain(Some(0).into_iter()).collect::<Vec<_>>()
 }
 
+// Size of icons in the notification area. Windows scales it with the DPI
+// setting, so loading icons at this size keeps them sharp.
+fn small_icon_size() -> i32 {
+    unsafe { user32::GetSystemMetrics(winapi::SM_CXSMICON) }
+}
+
 #[derive(Clone)]
 struct WindowInfo {
     pub hwnd: HWND,
@@ -35,10 +48,77 @@ struct WindowInfo {
 unsafe impl Send for WindowInfo {}
 unsafe impl Sync for WindowInfo {}
 
+// Radio entries by item id, as (group id, index in group). Written by the
+// Window when entries are added, read by the window proc on selection.
+type RadioItems = Arc<Mutex<HashMap<u32, (u32, usize)>>>;
+
+// Set through Window::set_tooltip_provider, cleared by Handle::set_tooltip.
+// Along with the provider goes what it last came up with, to skip
+// NIM_MODIFY when nothing changed.
+type TooltipSlot = Arc<Mutex<Option<(Arc<TooltipProvider>, Option<String>)>>>;
+// Icons for light and dark taskbars, switched as the color scheme changes
+type IconVariants = Arc<Mutex<Option<(IconSet, IconSet)>>>;
+
+// What the notification icon shows, kept to put it back after hiding it:
+// the icon shown normally, while attention is requested the one it blinks
+// with along with whether that one is up, and the tooltip. While hidden,
+// changes are only kept.
+struct IconState {
+    normal: HICON,
+    attention: Option<(HICON, bool)>,
+    tooltip: String,
+    visible: bool,
+}
+
+impl IconState {
+    fn shown(&self) -> HICON {
+        match self.attention {
+            Some((attention, true)) => attention,
+            _ => self.normal
+        }
+    }
+}
+
+unsafe impl Send for IconState {}
+
+type IconSlot = Arc<Mutex<IconState>>;
+
+// The notification area has no attention state, so the icon blinks instead.
+const ATTENTION_TIMER_ID: UINT_PTR = 1;
+const BLINK_INTERVAL_MS: UINT = 500;
+// Starts blinking if w_param is not 0, stops it otherwise. Timers belong to
+// the thread of their window, so other threads post this.
+const WM_BLINK: UINT = winapi::WM_USER + 2;
+
 #[derive(Clone)]
 struct WindowsLoopData {
     pub info: WindowInfo,
-    pub tx: Sender<SystrayEvent>
+    pub tx: EventSender,
+    pub radio_items: RadioItems,
+    pub tooltip_provider: TooltipSlot,
+    pub icon_variants: IconVariants,
+    pub icons: IconSlot,
+}
+
+unsafe fn select_radio_item(hmenu: HMENU, radio_items: &HashMap<u32, (u32, usize)>,
+                            group_idx: u32, member: usize) {
+    for (id, &(group, index)) in radio_items.iter() {
+        if group == group_idx {
+            user32::CheckMenuItem(hmenu, *id, MF_BYCOMMAND |
+                                  if index == member { winapi::MF_CHECKED } else { winapi::MF_UNCHECKED });
+        }
+    }
+}
+
+unsafe fn cursor_position() -> Option<(i32, i32)> {
+    let mut p = winapi::POINT {
+        x: 0,
+        y: 0
+    };
+    if user32::GetCursorPos(&mut p as *mut winapi::POINT) == 0 {
+        return None;
+    }
+    Some((p.x, p.y))
 }
 
 unsafe fn get_win_os_error(msg: &str) -> SystrayError {
@@ -55,49 +135,284 @@ unsafe extern "system" fn window_proc(h_wnd :HWND,
             let stash = stash.borrow();
             let stash = stash.as_ref();
             if let Some(stash) = stash {
-                let menu_id = GetMenuItemID(stash.info.hmenu,
-                                            w_param as i32) as i32;
-                if menu_id != -1 {
-                    stash.tx.send(SystrayEvent {
-                        menu_index: menu_id as u32,
-                    }).ok();
+                let mut item = get_menu_item_struct();
+                item.fMask = MIIM_ID | MIIM_STATE | MIIM_DATA;
+                // With MNS_NOTIFYBYPOS, w_param is the position of the item and
+                // l_param the menu, or submenu, containing it.
+                let hmenu = l_param as HMENU;
+                if GetMenuItemInfoW(hmenu,
+                                    w_param as UINT,
+                                    1,
+                                    &mut item as *mut winapi::MENUITEMINFOW) == 0 {
+                    return;
                 }
+                // Win32 does not toggle check marks by itself.
+                let checked = if item.dwItemData == CHECK_ITEM_DATA {
+                    let checked = item.fState & MFS_CHECKED == 0;
+                    user32::CheckMenuItem(hmenu, item.wID, MF_BYCOMMAND |
+                                          if checked { winapi::MF_CHECKED } else { winapi::MF_UNCHECKED });
+                    Some(checked)
+                } else {
+                    None
+                };
+                if item.dwItemData == RADIO_ITEM_DATA {
+                    let radio_items = stash.radio_items.lock().unwrap();
+                    if let Some(&(group, member)) = radio_items.get(&item.wID) {
+                        select_radio_item(hmenu, &radio_items, group, member);
+                        stash.tx.send(SystrayEvent::MenuItemActivated {
+                            id: group,
+                            checked: None,
+                            selected: Some(member),
+                            time: Instant::now(),
+                            position: cursor_position(),
+                        }).ok();
+                    }
+                    return;
+                }
+                stash.tx.send(SystrayEvent::MenuItemActivated {
+                    id: item.wID,
+                    checked: checked,
+                    selected: None,
+                    time: Instant::now(),
+                    position: cursor_position(),
+                }).ok();
             }
         });
     }
 
+    // Sent with "ImmersiveColorSet" when the user switches between light
+    // and dark mode.
+    if msg == winapi::winuser::WM_SETTINGCHANGE && l_param != 0 {
+        let name = l_param as *const u16;
+        let len = (0..).take_while(|&i| *name.offset(i) != 0).count();
+        let expected: Vec<u16> = OsStr::new("ImmersiveColorSet").encode_wide().collect();
+        if std::slice::from_raw_parts(name, len) == &expected[..] {
+            WININFO_STASH.with(|stash| {
+                let stash = stash.borrow();
+                if let Some(stash) = stash.as_ref() {
+                    if let Some((ref light, ref dark)) = *stash.icon_variants.lock().unwrap() {
+                        let set = match taskbar_color_scheme() {
+                            ColorScheme::Light => light,
+                            ColorScheme::Dark => dark
+                        };
+                        if let Err(e) = icon_from_set(set).and_then(|i| show_normal_icon(&stash.info.hwnd, &stash.icons, i)) {
+                            warn!("Error switching icon variant: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
     if msg == winapi::winuser::WM_USER + 1 {
-        if l_param as UINT == winapi::winuser::WM_LBUTTONUP ||
-            l_param as UINT == winapi::winuser::WM_RBUTTONUP {
-                let mut p = winapi::POINT {
-                    x: 0,
-                    y: 0
-                };
-                if user32::GetCursorPos(&mut p as *mut winapi::POINT) == 0 {
-                    return 1;
+        let button = l_param as UINT;
+        // The pointer moving over the icon is the only sign of a tooltip
+        // about to be shown.
+        if button == winapi::winuser::WM_MOUSEMOVE {
+            WININFO_STASH.with(|stash| {
+                let stash = stash.borrow();
+                let stash = stash.as_ref();
+                if let Some(stash) = stash {
+                    let mut slot = stash.tooltip_provider.lock().unwrap();
+                    if let Some((ref provider, ref mut shown)) = *slot {
+                        let tooltip = provider.get().to_plain_text();
+                        if shown.as_ref() != Some(&tooltip) {
+                            show_tooltip(&stash.info.hwnd, &stash.icons, &tooltip).ok();
+                            *shown = Some(tooltip);
+                        }
+                    }
                 }
-                user32::SetForegroundWindow(h_wnd);
+            });
+        }
+        if button == winapi::winuser::WM_LBUTTONUP ||
+            button == winapi::winuser::WM_RBUTTONUP ||
+            button == winapi::winuser::WM_MBUTTONUP {
+                let (x, y) = match cursor_position() {
+                    Some(p) => p,
+                    None => return 1
+                };
                 WININFO_STASH.with(|stash| {
                     let stash = stash.borrow();
                     let stash = stash.as_ref();
                     if let Some(stash) = stash {
+                        let position = Some((x, y));
+                        let event = if button == winapi::winuser::WM_LBUTTONUP {
+                            SystrayEvent::IconActivated { time: Instant::now(), position: position }
+                        } else if button == winapi::winuser::WM_RBUTTONUP {
+                            SystrayEvent::ContextMenuRequested { time: Instant::now(), position: position }
+                        } else {
+                            SystrayEvent::SecondaryActivate { time: Instant::now(), position: position }
+                        };
+                        stash.tx.send(event).ok();
+                        if button == winapi::winuser::WM_MBUTTONUP {
+                            return;
+                        }
+                        user32::SetForegroundWindow(h_wnd);
+                        stash.tx.send(SystrayEvent::MenuOpened { time: Instant::now(), position: position }).ok();
+                        // Only returns once the menu is closed again.
                         TrackPopupMenu(stash.info.hmenu,
                                        0,
-                                       p.x,
-                                       p.y,
+                                       x,
+                                       y,
                                        (TPM_BOTTOMALIGN | TPM_LEFTALIGN) as i32,
                                        h_wnd,
                                        std::ptr::null_mut());
+                        stash.tx.send(SystrayEvent::MenuClosed { time: Instant::now(), position: cursor_position() }).ok();
                     }
                 });
             }
     }
+    if msg == WM_BLINK {
+        if w_param != 0 {
+            SetTimer(h_wnd, ATTENTION_TIMER_ID, BLINK_INTERVAL_MS, None);
+        } else {
+            KillTimer(h_wnd, ATTENTION_TIMER_ID);
+        }
+    }
+
+    if msg == winapi::WM_TIMER && w_param as UINT_PTR == ATTENTION_TIMER_ID {
+        WININFO_STASH.with(|stash| {
+            let stash = stash.borrow();
+            if let Some(stash) = stash.as_ref() {
+                let mut icons = stash.icons.lock().unwrap();
+                let normal = icons.normal;
+                let visible = icons.visible;
+                if let Some((attention, ref mut up)) = icons.attention {
+                    *up = !*up;
+                    if visible {
+                        set_notify_icon(&stash.info.hwnd, if *up { attention } else { normal }).ok();
+                    }
+                }
+            }
+        });
+    }
+
     if msg == winapi::winuser::WM_DESTROY {
         user32::PostQuitMessage(0);
     }
     return user32::DefWindowProcW(h_wnd, msg, w_param, l_param);
 }
 
+// szTip holds 128 UTF-16 units, including the terminating zero.
+fn fill_tip(nid: &mut NOTIFYICONDATAW, tooltip: &str) {
+    let tt = to_wstring(tooltip);
+    let len = std::cmp::min(tt.len(), nid.szTip.len() - 1);
+    nid.szTip[..len].copy_from_slice(&tt[..len]);
+    nid.szTip[len] = 0;
+}
+
+fn set_notify_tooltip(hwnd: &HWND, tooltip: &str) -> Result<(), SystrayError> {
+    debug!("Setting tooltip to {}", tooltip);
+    let mut nid = get_nid_struct(hwnd);
+    fill_tip(&mut nid, tooltip);
+    nid.uFlags = winapi::NIF_TIP;
+    unsafe {
+        if Shell_NotifyIconW(winapi::NIM_MODIFY,
+                                      &mut nid as *mut NOTIFYICONDATAW) == 0 {
+            return Err(get_win_os_error("Error setting tooltip"));
+        }
+    }
+    Ok(())
+}
+
+fn set_notify_icon(hwnd: &HWND, icon: HICON) -> Result<(), SystrayError> {
+    let mut nid = get_nid_struct(hwnd);
+    nid.uFlags = winapi::NIF_ICON;
+    nid.hIcon = icon;
+    unsafe {
+        if Shell_NotifyIconW(winapi::NIM_MODIFY,
+                                      &mut nid as *mut NOTIFYICONDATAW) == 0 {
+            return Err(get_win_os_error("Error setting icon"));
+        }
+    }
+    Ok(())
+}
+
+// Takes over as the normal icon. While the attention icon is up, it only
+// shows at the next blink.
+fn show_normal_icon(hwnd: &HWND, icons: &IconSlot, icon: HICON) -> Result<(), SystrayError> {
+    let mut icons = icons.lock().unwrap();
+    icons.normal = icon;
+    if !icons.visible || icons.shown() != icon {
+        return Ok(());
+    }
+    set_notify_icon(hwnd, icon)
+}
+
+fn show_tooltip(hwnd: &HWND, icons: &IconSlot, tooltip: &str) -> Result<(), SystrayError> {
+    let mut icons = icons.lock().unwrap();
+    icons.tooltip = tooltip.to_string();
+    if !icons.visible {
+        return Ok(());
+    }
+    set_notify_tooltip(hwnd, tooltip)
+}
+
+// The notification area keeps a copy of the icons it is given, so one no
+// longer kept here can go even while it still shows.
+fn destroy_icon(icon: HICON) {
+    unsafe {
+        user32::DestroyIcon(icon);
+    }
+}
+
+fn load_icon_file(icon_file: &str) -> Result<HICON, SystrayError> {
+    let wstr_icon_file = to_wstring(icon_file);
+    unsafe {
+        let hicon = user32::LoadImageW(std::ptr::null_mut() as HINSTANCE, wstr_icon_file.as_ptr(),
+                                       winapi::IMAGE_ICON, small_icon_size(), small_icon_size(),
+                                       winapi::LR_LOADFROMFILE) as HICON;
+        if hicon == std::ptr::null_mut() as HICON {
+            return Err(get_win_os_error("Error loading icon from file"));
+        }
+        Ok(hicon)
+    }
+}
+
+// SVG renditions are left out, Windows has nothing to render them with.
+// The chosen rendition is scaled to the exact size.
+fn icon_from_set(set: &IconSet) -> Result<HICON, SystrayError> {
+    let size = small_icon_size();
+    let png = match set.best_png(size as u32) {
+        Some((_, png)) => png,
+        None => return Err(SystrayError::OsError("Icon set has no PNG rendition".to_string()))
+    };
+    let hicon = unsafe {
+        user32::CreateIconFromResourceEx(png.as_ptr() as PBYTE,
+                                         png.len() as DWORD,
+                                         TRUE,
+                                         0x30000,
+                                         size,
+                                         size,
+                                         LR_DEFAULTCOLOR)
+    };
+    if hicon == std::ptr::null_mut() as HICON {
+        return Err(unsafe { get_win_os_error("Error creating icon from icon set") });
+    }
+    Ok(hicon)
+}
+
+// The taskbar follows the system color scheme, not the one of apps. Windows
+// before 10 1903 have no light taskbar, nor the value.
+fn taskbar_color_scheme() -> ColorScheme {
+    let mut light: DWORD = 0;
+    let mut size = std::mem::size_of::<DWORD>() as DWORD;
+    let found = unsafe {
+        RegGetValueW(winapi::HKEY_CURRENT_USER,
+                     to_wstring("Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize").as_ptr(),
+                     to_wstring("SystemUsesLightTheme").as_ptr(),
+                     RRF_RT_REG_DWORD,
+                     std::ptr::null_mut(),
+                     &mut light as *mut DWORD as winapi::PVOID,
+                     &mut size)
+    } == 0;
+    if found && light != 0 {
+        ColorScheme::Light
+    } else {
+        ColorScheme::Dark
+    }
+}
+
 fn get_nid_struct(hwnd : &HWND) -> NOTIFYICONDATAW {
     NOTIFYICONDATAW {
         cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as DWORD,
@@ -123,6 +438,10 @@ fn get_nid_struct(hwnd : &HWND) -> NOTIFYICONDATAW {
     }
 }
 
+// Stored in dwItemData so the window proc can tell check items apart.
+const CHECK_ITEM_DATA: winapi::ULONG_PTR = 1;
+const RADIO_ITEM_DATA: winapi::ULONG_PTR = 2;
+
 fn get_menu_item_struct() -> MENUITEMINFOW {
     winapi::MENUITEMINFOW {
         cbSize: std::mem::size_of::<winapi::MENUITEMINFOW>() as UINT,
@@ -140,8 +459,45 @@ fn get_menu_item_struct() -> MENUITEMINFOW {
     }
 }
 
-unsafe fn init_window() -> Result<WindowInfo, SystrayError> {
-    let class_name = to_wstring("my_window");
+unsafe fn delete_menu_bitmap(bitmap: HBITMAP) {
+    DeleteObject(bitmap as HGDIOBJ);
+}
+
+fn load_menu_bitmap(file: &str) -> Result<HBITMAP, SystrayError> {
+    unsafe {
+        let bitmap = user32::LoadImageW(std::ptr::null_mut() as HINSTANCE, to_wstring(file).as_ptr(),
+                                        winapi::IMAGE_BITMAP, 0, 0,
+                                        winapi::LR_LOADFROMFILE | winapi::LR_LOADTRANSPARENT) as HBITMAP;
+        if bitmap == std::ptr::null_mut() as HBITMAP {
+            return Err(get_win_os_error("Error loading menu item image"));
+        }
+        Ok(bitmap)
+    }
+}
+
+// Creates a popup menu that reports selections through WM_MENUCOMMAND. Used
+// for the tray menu itself as well as for submenus.
+unsafe fn create_menu() -> Result<HMENU, SystrayError> {
+    let hmenu = user32::CreatePopupMenu();
+    let m = MENUINFO {
+        cbSize: std::mem::size_of::<MENUINFO>() as DWORD,
+        fMask: MIM_APPLYTOSUBMENUS | MIM_STYLE,
+        dwStyle: MNS_NOTIFYBYPOS,
+        cyMax: 0 as UINT,
+        hbrBack: 0 as HBRUSH,
+        dwContextHelpID: 0 as DWORD,
+        dwMenuData: 0 as winapi::ULONG_PTR
+    };
+    if SetMenuInfo(hmenu, &m as *const MENUINFO) == 0 {
+        return Err(get_win_os_error("Error setting up menu"));
+    }
+    Ok(hmenu)
+}
+
+// The id names the window class and the title the window, which also
+// starts out as the tooltip.
+unsafe fn init_window(id: &str, title: &str) -> Result<WindowInfo, SystrayError> {
+    let class_name = to_wstring(id);
     let hinstance : HINSTANCE = kernel32::GetModuleHandleA(std::ptr::null_mut());
     let wnd = WNDCLASSW {
         style: 0,
@@ -162,7 +518,7 @@ unsafe fn init_window() -> Result<WindowInfo, SystrayError> {
     }
     let hwnd = user32::CreateWindowExW(0,
                                        class_name.as_ptr(),
-                                       to_wstring("rust_systray_window").as_ptr(),
+                                       to_wstring(title).as_ptr(),
                                        WS_OVERLAPPEDWINDOW,
                                        CW_USEDEFAULT,
                                        0,
@@ -177,26 +533,15 @@ unsafe fn init_window() -> Result<WindowInfo, SystrayError> {
     }
     let mut nid = get_nid_struct(&hwnd);
     nid.uID = 0x1;
-    nid.uFlags = winapi::NIF_MESSAGE;
+    nid.uFlags = winapi::NIF_MESSAGE | winapi::NIF_TIP;
+    fill_tip(&mut nid, title);
     nid.uCallbackMessage = winapi::WM_USER + 1;
     if Shell_NotifyIconW(winapi::NIM_ADD,
                                   &mut nid as *mut NOTIFYICONDATAW) == 0 {
         return Err(get_win_os_error("Error adding menu icon"));
     }
     // Setup menu
-    let hmenu = user32::CreatePopupMenu();
-    let m = MENUINFO {
-        cbSize: std::mem::size_of::<MENUINFO>() as DWORD,
-        fMask: MIM_APPLYTOSUBMENUS | MIM_STYLE,
-        dwStyle: MNS_NOTIFYBYPOS,
-        cyMax: 0 as UINT,
-        hbrBack: 0 as HBRUSH,
-        dwContextHelpID: 0 as DWORD,
-        dwMenuData: 0 as winapi::ULONG_PTR
-    };
-    if SetMenuInfo(hmenu, &m as *const MENUINFO) == 0 {
-        return Err(get_win_os_error("Error setting up menu"));
-    }
+    let hmenu = create_menu()?;
 
     Ok(WindowInfo {
         hwnd: hwnd,
@@ -227,17 +572,243 @@ unsafe fn run_loop() {
     debug!("Leaving windows run loop");
 }
 
-pub struct Window {
+struct MenuEntryInfo {
+    id: u32,
+    // None for entries of the top level menu
+    parent: Option<u32>,
+    // None for separators
+    label: Option<String>,
+    // None for items without a check box
+    checked: Option<bool>,
+    radio: bool,
+    // Popup menu owned by this entry, for submenus
+    submenu: Option<HMENU>,
+    // Bitmap shown next to the label
+    icon: Option<HBITMAP>,
+    enabled: bool,
+    visible: bool,
+}
+
+// Menus and bitmaps are not tied to the thread that created them.
+unsafe impl Send for MenuEntryInfo {}
+
+// The part of the window that other threads may use. Menus and the
+// notification icon can be changed from any thread, so the handle calls
+// into Win32 directly, like the Window does, with the entry list shared
+// behind a mutex.
+#[derive(Clone)]
+pub struct Handle {
     info: WindowInfo,
+    entries: Arc<Mutex<Vec<MenuEntryInfo>>>,
+    tooltip_provider: TooltipSlot,
+    icon_variants: IconVariants,
+    icons: IconSlot,
+}
+
+impl Handle {
+    // Notification area tooltips are plain text, but may span lines.
+    pub fn set_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
+        *self.tooltip_provider.lock().unwrap() = None;
+        show_tooltip(&self.info.hwnd, &self.icons, &tooltip.to_plain_text())
+    }
+
+    fn menu_handle(&self, entries: &[MenuEntryInfo], parent: Option<u32>) -> HMENU {
+        parent.and_then(|p| entries.iter().find(|e| e.id == p))
+            .and_then(|e| e.submenu)
+            .unwrap_or(self.info.hmenu)
+    }
+
+    // Looks up an entry together with the HMENU it lives in.
+    fn find_entry(&self, entries: &[MenuEntryInfo], item_idx: u32) -> Option<(usize, HMENU)> {
+        entries.iter().position(|e| e.id == item_idx).map(|i| {
+            (i, self.menu_handle(entries, entries[i].parent))
+        })
+    }
+
+    pub fn set_menu_entry_checked(&self, item_idx: u32, checked: bool) -> Result<(), SystrayError> {
+        let mut entries = self.entries.lock().unwrap();
+        let (i, hmenu) = match self.find_entry(&entries, item_idx) {
+            Some(e) => e,
+            None => return Ok(())
+        };
+        let entry = &mut entries[i];
+        if entry.checked.is_none() {
+            return Ok(());
+        }
+        entry.checked = Some(checked);
+        if !entry.visible {
+            return Ok(());
+        }
+        let flags = MF_BYCOMMAND | if checked { winapi::MF_CHECKED } else { winapi::MF_UNCHECKED };
+        unsafe {
+            if user32::CheckMenuItem(hmenu, item_idx, flags) == 0xFFFFFFFF {
+                return Err(get_win_os_error("Error checking menu item"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_entry_label(&self, item_idx: u32, item_name: &str) -> Result<(), SystrayError> {
+        let mut entries = self.entries.lock().unwrap();
+        let (i, hmenu) = match self.find_entry(&entries, item_idx) {
+            Some(e) => e,
+            None => return Ok(())
+        };
+        let entry = &mut entries[i];
+        if entry.label.is_none() {
+            return Ok(());
+        }
+        entry.label = Some(item_name.to_string());
+        if !entry.visible {
+            return Ok(());
+        }
+        let mut st = to_wstring(item_name);
+        let mut item = get_menu_item_struct();
+        item.fMask = MIIM_STRING;
+        item.dwTypeData = st.as_mut_ptr();
+        item.cch = (item_name.len() * 2) as u32;
+        unsafe {
+            if SetMenuItemInfoW(hmenu,
+                                item_idx,
+                                0,
+                                &item as *const winapi::MENUITEMINFOW) == 0 {
+                return Err(get_win_os_error("Error setting menu item label"));
+            }
+        }
+        Ok(())
+    }
+
+    // Any icon set from outside replaces the variants.
+    fn set_icon(&self, icon: HICON) -> Result<(), SystrayError> {
+        *self.icon_variants.lock().unwrap() = None;
+        show_normal_icon(&self.info.hwnd, &self.icons, icon)
+    }
+
+    pub fn set_icon_from_file(&self, icon_file: &str) -> Result<(), SystrayError> {
+        self.set_icon(load_icon_file(icon_file)?)
+    }
+
+    // Shows the attention icon right away, then blinks it with the normal
+    // one until cleared.
+    pub fn request_attention(&self, icon_file: &str) -> Result<(), SystrayError> {
+        let icon = load_icon_file(icon_file)?;
+        let mut icons = self.icons.lock().unwrap();
+        if let Some((replaced, _)) = std::mem::replace(&mut icons.attention, Some((icon, true))) {
+            destroy_icon(replaced);
+        }
+        if icons.visible {
+            set_notify_icon(&self.info.hwnd, icon)?;
+        }
+        unsafe {
+            user32::PostMessageW(self.info.hwnd, WM_BLINK, 1 as WPARAM, 0 as LPARAM);
+        }
+        Ok(())
+    }
+
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        let mut icons = self.icons.lock().unwrap();
+        match icons.attention.take() {
+            Some((attention, _)) => destroy_icon(attention),
+            None => return Ok(())
+        }
+        unsafe {
+            user32::PostMessageW(self.info.hwnd, WM_BLINK, 0 as WPARAM, 0 as LPARAM);
+        }
+        if !icons.visible {
+            return Ok(());
+        }
+        set_notify_icon(&self.info.hwnd, icons.normal)
+    }
+
+    // The notification area only shows icons.
+    pub fn set_icon_label(&self, _: &str, _: &str) -> Result<(), SystrayError> {
+        Err(SystrayError::NotImplementedError)
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Label | Capability::NativeAttention => false,
+            Capability::TooltipProvider => true
+        }
+    }
+
+    // The notification area forgets removed icons, so showing it again
+    // adds it anew, with what it showed before.
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        let mut icons = self.icons.lock().unwrap();
+        if icons.visible == visible {
+            return Ok(());
+        }
+        let mut nid = get_nid_struct(&self.info.hwnd);
+        unsafe {
+            if visible {
+                nid.uFlags = winapi::NIF_MESSAGE | winapi::NIF_ICON | winapi::NIF_TIP;
+                nid.uCallbackMessage = winapi::WM_USER + 1;
+                nid.hIcon = icons.shown();
+                fill_tip(&mut nid, &icons.tooltip);
+                if Shell_NotifyIconW(winapi::NIM_ADD, &mut nid as *mut NOTIFYICONDATAW) == 0 {
+                    return Err(get_win_os_error("Error adding icon"));
+                }
+            } else if Shell_NotifyIconW(winapi::NIM_DELETE, &mut nid as *mut NOTIFYICONDATAW) == 0 {
+                return Err(get_win_os_error("Error removing icon"));
+            }
+        }
+        icons.visible = visible;
+        Ok(())
+    }
+
+    // Win32 calls finish right away, so these only differ in where the
+    // error goes.
+    pub fn set_menu_entry_label_nowait(&self, item_idx: u32, item_name: &str) {
+        if let Err(e) = self.set_menu_entry_label(item_idx, item_name) {
+            warn!("Error setting menu item label: {}", e);
+        }
+    }
+
+    pub fn set_icon_from_file_nowait(&self, icon_file: &str) {
+        if let Err(e) = self.set_icon_from_file(icon_file) {
+            warn!("Error setting icon from file: {}", e);
+        }
+    }
+
+    // Only asks the loop to end, without waiting for it like Window::quit.
+    pub fn quit(&self) {
+        unsafe {
+            user32::PostMessageW(self.info.hwnd, winapi::WM_DESTROY,
+                                 0 as WPARAM, 0 as LPARAM);
+        }
+    }
+}
+
+pub struct Window {
+    handle: Handle,
     windows_loop: Option<thread::JoinHandle<()>>,
+    radio_items: RadioItems,
+    icon_theme_path: RefCell<Option<PathBuf>>,
 }
 
 impl Window {
-    pub fn new(event_tx: Sender<SystrayEvent>) -> Result<Window, SystrayError> {
+    // Windows has nothing like a category, so it goes unused.
+    pub fn new(event_tx: EventSender, id: &str, title: &str, _: Category) -> Result<Window, SystrayError> {
         let (tx, rx) = channel();
+        let id = id.to_string();
+        let title = title.to_string();
+        let radio_items: RadioItems = Arc::new(Mutex::new(HashMap::new()));
+        let loop_radio_items = radio_items.clone();
+        let tooltip_provider: TooltipSlot = Arc::new(Mutex::new(None));
+        let loop_tooltip_provider = tooltip_provider.clone();
+        let icon_variants: IconVariants = Arc::new(Mutex::new(None));
+        let loop_icon_variants = icon_variants.clone();
+        let icons: IconSlot = Arc::new(Mutex::new(IconState {
+            normal: std::ptr::null_mut(),
+            attention: None,
+            tooltip: title.clone(),
+            visible: true,
+        }));
+        let loop_icons = icons.clone();
         let windows_loop = thread::spawn(move || {
             unsafe {
-                let i = init_window();
+                let i = init_window(&id, &title);
                 let k;
                 match i {
                     Ok(j) => {
@@ -253,7 +824,11 @@ impl Window {
                 WININFO_STASH.with(|stash| {
                     let data = WindowsLoopData {
                         info: k,
-                        tx: event_tx
+                        tx: event_tx,
+                        radio_items: loop_radio_items,
+                        tooltip_provider: loop_tooltip_provider,
+                        icon_variants: loop_icon_variants,
+                        icons: loop_icons,
                     };
                     (*stash.borrow_mut()) = Some(data);
                 });
@@ -267,54 +842,93 @@ impl Window {
             }
         };
         let w = Window {
-            info: info,
+            handle: Handle {
+                info: info,
+                entries: Arc::new(Mutex::new(Vec::new())),
+                tooltip_provider: tooltip_provider,
+                icon_variants: icon_variants,
+                icons: icons,
+            },
             windows_loop: Some(windows_loop),
+            radio_items: radio_items,
+            icon_theme_path: RefCell::new(None),
         };
         Ok(w)
     }
 
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
     pub fn quit(&mut self) {
-        unsafe {
-            user32::PostMessageW(self.info.hwnd, winapi::WM_DESTROY,
-                                 0 as WPARAM, 0 as LPARAM);
-        }
+        self.handle.quit();
         if let Some(t) = self.windows_loop.take() {
             t.join().ok();
         }
     }
 
+    pub fn set_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
+        self.handle.set_tooltip(tooltip)
+    }
 
-    pub fn set_tooltip(&self, tooltip: &String) -> Result<(), SystrayError> {
-        // Add Tooltip
-        debug!("Setting tooltip to {}", tooltip);
-        // Gross way to convert String to [i8; 128]
-        // TODO: Clean up conversion, test for length so we don't panic at runtime
-        let tt = tooltip.as_bytes().clone();
-        let mut nid = get_nid_struct(&self.info.hwnd);
-        for i in 0..tt.len() {
-            nid.szTip[i] = tt[i] as u16;
-        }
-        nid.uFlags = winapi::NIF_TIP;
-        unsafe {
-            if Shell_NotifyIconW(winapi::NIM_MODIFY,
-                                          &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error setting tooltip"));
-            }
-        }
+    // Nothing is computed until the pointer moves over the icon.
+    pub fn set_tooltip_provider(&self, provider: Arc<TooltipProvider>) -> Result<(), SystrayError> {
+        *self.handle.tooltip_provider.lock().unwrap() = Some((provider, None));
         Ok(())
     }
 
-    pub fn add_menu_entry(&self, item_idx: u32, item_name: &String) -> Result<(), SystrayError> {
-        let mut st = to_wstring(item_name);
+    // Win32 has no hidden menu items, so hidden entries are kept out of the
+    // HMENU entirely. An entry's position is the number of visible entries in
+    // front of it that share its parent.
+    fn menu_position(entries: &[MenuEntryInfo], parent: Option<u32>, item_idx: u32) -> UINT {
+        entries.iter()
+            .take_while(|e| e.id != item_idx)
+            .filter(|e| e.parent == parent && e.visible)
+            .count() as UINT
+    }
+
+    fn insert_menu_entry(&self, hmenu: HMENU, position: UINT, entry: &MenuEntryInfo) -> Result<(), SystrayError> {
         let mut item = get_menu_item_struct();
-        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
-        item.fType = MFT_STRING;
-        item.wID = item_idx;
-        item.dwTypeData = st.as_mut_ptr();
-        item.cch = (item_name.len() * 2) as u32;
+        item.wID = entry.id;
+        // Keep the string alive until InsertMenuItemW returns.
+        let mut st;
+        match entry.label {
+            Some(ref label) => {
+                st = to_wstring(label);
+                item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
+                item.fType = MFT_STRING;
+                item.fState = if entry.enabled { MFS_ENABLED } else { MFS_DISABLED };
+                if let Some(checked) = entry.checked {
+                    item.fMask |= MIIM_DATA;
+                    if entry.radio {
+                        item.fType |= MFT_RADIOCHECK;
+                        item.dwItemData = RADIO_ITEM_DATA;
+                    } else {
+                        item.dwItemData = CHECK_ITEM_DATA;
+                    }
+                    if checked {
+                        item.fState |= MFS_CHECKED;
+                    }
+                }
+                if let Some(submenu) = entry.submenu {
+                    item.fMask |= MIIM_SUBMENU;
+                    item.hSubMenu = submenu;
+                }
+                if let Some(icon) = entry.icon {
+                    item.fMask |= MIIM_BITMAP;
+                    item.hbmpItem = icon;
+                }
+                item.dwTypeData = st.as_mut_ptr();
+                item.cch = (label.len() * 2) as u32;
+            }
+            None => {
+                item.fMask = MIIM_FTYPE | MIIM_ID;
+                item.fType = MFT_SEPARATOR;
+            }
+        }
         unsafe {
-            if user32::InsertMenuItemW(self.info.hmenu,
-                                       item_idx,
+            if user32::InsertMenuItemW(hmenu,
+                                       position,
                                        1,
                                        &item as *const winapi::MENUITEMINFOW) == 0 {
                 return Err(get_win_os_error("Error inserting menu item"));
@@ -323,62 +937,256 @@ impl Window {
         Ok(())
     }
 
-    pub fn add_menu_separator(&self, item_idx: u32) -> Result<(), SystrayError> {
-        let mut item = get_menu_item_struct();
-        item.fMask = MIIM_FTYPE;
-        item.fType = MFT_SEPARATOR;
-        item.wID = item_idx;
+    pub fn insert_menu_node(&self, parent_idx: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        let mut entry = MenuEntryInfo {
+            id: node.id,
+            parent: parent_idx,
+            label: Some(node.label.clone()),
+            checked: None,
+            radio: false,
+            submenu: None,
+            icon: match node.icon {
+                Some(ref icon) => Some(load_menu_bitmap(icon)?),
+                None => None
+            },
+            enabled: node.enabled,
+            visible: node.visible,
+        };
+        match node.kind {
+            MenuNodeKind::Item => (),
+            MenuNodeKind::Separator => entry.label = None,
+            MenuNodeKind::Check { checked } => entry.checked = Some(checked),
+            MenuNodeKind::Radio { selected, .. } => {
+                entry.checked = Some(selected);
+                entry.radio = true;
+            }
+            MenuNodeKind::Submenu => match unsafe { create_menu() } {
+                Ok(submenu) => entry.submenu = Some(submenu),
+                Err(e) => {
+                    if let Some(icon) = entry.icon {
+                        unsafe {
+                            delete_menu_bitmap(icon);
+                        }
+                    }
+                    return Err(e);
+                }
+            },
+        }
+        {
+            let mut entries = self.handle.entries.lock().unwrap();
+            // Siblings keep their menu order within the entry list, so the
+            // new entry goes right in front of the one it displaces.
+            let at = entries.iter().enumerate()
+                .filter(|&(_, e)| e.parent == parent_idx)
+                .nth(position)
+                .map(|(i, _)| i)
+                .unwrap_or(entries.len());
+            let hmenu = self.handle.menu_handle(&entries, parent_idx);
+            if entry.visible {
+                let visible_position = entries[..at].iter()
+                    .filter(|e| e.parent == parent_idx && e.visible)
+                    .count() as UINT;
+                if let Err(e) = self.insert_menu_entry(hmenu, visible_position, &entry) {
+                    unsafe {
+                        if let Some(submenu) = entry.submenu {
+                            user32::DestroyMenu(submenu);
+                        }
+                        if let Some(icon) = entry.icon {
+                            delete_menu_bitmap(icon);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+            entries.insert(at, entry);
+        }
+        if let MenuNodeKind::Radio { group, index, .. } = node.kind {
+            self.radio_items.lock().unwrap().insert(node.id, (group, index));
+        }
+        for (i, child) in node.children.iter().enumerate() {
+            self.insert_menu_node(Some(node.id), i, child)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_radio_selected(&self, group_idx: u32, member: usize) -> Result<(), SystrayError> {
+        let radio_items = self.radio_items.lock().unwrap();
+        let mut entries = self.handle.entries.lock().unwrap();
+        let mut parent = None;
+        for entry in entries.iter_mut() {
+            if let Some(&(group, index)) = radio_items.get(&entry.id) {
+                if group == group_idx {
+                    entry.checked = Some(index == member);
+                    parent = entry.parent;
+                }
+            }
+        }
+        let hmenu = self.handle.menu_handle(&entries, parent);
         unsafe {
-            if user32::InsertMenuItemW(self.info.hmenu,
-                                       item_idx,
-                                       1,
-                                       &item as *const winapi::MENUITEMINFOW) == 0 {
-                return Err(get_win_os_error("Error inserting separator"));
+            select_radio_item(hmenu, &radio_items, group_idx, member);
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_entry_checked(&self, item_idx: u32, checked: bool) -> Result<(), SystrayError> {
+        self.handle.set_menu_entry_checked(item_idx, checked)
+    }
+
+    pub fn set_menu_entry_label(&self, item_idx: u32, item_name: &str) -> Result<(), SystrayError> {
+        self.handle.set_menu_entry_label(item_idx, item_name)
+    }
+
+    pub fn set_menu_entry_icon(&self, item_idx: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        let mut entries = self.handle.entries.lock().unwrap();
+        let (i, hmenu) = match self.handle.find_entry(&entries, item_idx) {
+            Some(e) => e,
+            None => return Ok(())
+        };
+        let bitmap = match icon {
+            Some(icon) => Some(load_menu_bitmap(icon)?),
+            None => None
+        };
+        let entry = &mut entries[i];
+        if entry.visible {
+            let mut item = get_menu_item_struct();
+            item.fMask = MIIM_BITMAP;
+            item.hbmpItem = bitmap.unwrap_or(std::ptr::null_mut());
+            unsafe {
+                if SetMenuItemInfoW(hmenu,
+                                    item_idx,
+                                    0,
+                                    &item as *const winapi::MENUITEMINFOW) == 0 {
+                    if let Some(bitmap) = bitmap {
+                        delete_menu_bitmap(bitmap);
+                    }
+                    return Err(get_win_os_error("Error setting menu item image"));
+                }
+            }
+        }
+        if let Some(old) = std::mem::replace(&mut entry.icon, bitmap) {
+            unsafe {
+                delete_menu_bitmap(old);
             }
         }
         Ok(())
     }
 
-    fn set_icon(&self, icon: HICON) -> Result<(), SystrayError> {
+    pub fn set_menu_entry_enabled(&self, item_idx: u32, enabled: bool) -> Result<(), SystrayError> {
+        let mut entries = self.handle.entries.lock().unwrap();
+        let (i, hmenu) = match self.handle.find_entry(&entries, item_idx) {
+            Some(e) => e,
+            None => return Ok(())
+        };
+        let entry = &mut entries[i];
+        entry.enabled = enabled;
+        if !entry.visible {
+            return Ok(());
+        }
+        let flags = MF_BYCOMMAND | if enabled { winapi::MF_ENABLED } else { winapi::MF_GRAYED };
         unsafe {
-            let mut nid = get_nid_struct(&self.info.hwnd);
-            nid.uFlags = winapi::NIF_ICON;
-            nid.hIcon = icon;
-            if Shell_NotifyIconW(winapi::NIM_MODIFY,
-                                          &mut nid as *mut NOTIFYICONDATAW) == 0 {
-                return Err(get_win_os_error("Error setting icon"));
+            if user32::EnableMenuItem(hmenu, item_idx, flags) == -1 {
+                return Err(get_win_os_error("Error enabling menu item"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_menu_entry_visible(&self, item_idx: u32, visible: bool) -> Result<(), SystrayError> {
+        let mut entries = self.handle.entries.lock().unwrap();
+        let (i, hmenu) = match self.handle.find_entry(&entries, item_idx) {
+            Some(e) => e,
+            None => return Ok(())
+        };
+        let position = Window::menu_position(&entries, entries[i].parent, item_idx);
+        let entry = &mut entries[i];
+        if entry.visible == visible {
+            return Ok(());
+        }
+        if visible {
+            self.insert_menu_entry(hmenu, position, entry)?;
+        } else {
+            // The user may have toggled the item since we last touched it.
+            if entry.checked.is_some() {
+                let mut item = get_menu_item_struct();
+                item.fMask = MIIM_STATE;
+                unsafe {
+                    if GetMenuItemInfoW(hmenu,
+                                        item_idx,
+                                        0,
+                                        &mut item as *mut winapi::MENUITEMINFOW) != 0 {
+                        entry.checked = Some(item.fState & MFS_CHECKED != 0);
+                    }
+                }
+            }
+            // RemoveMenu keeps the submenu of the entry alive, unlike
+            // DeleteMenu, so it can be inserted again later.
+            unsafe {
+                if RemoveMenu(hmenu, item_idx, MF_BYCOMMAND) == 0 {
+                    return Err(get_win_os_error("Error hiding menu item"));
+                }
+            }
+        }
+        entry.visible = visible;
+        Ok(())
+    }
+
+    pub fn remove_menu_entry(&self, item_idx: u32) -> Result<(), SystrayError> {
+        let mut entries = self.handle.entries.lock().unwrap();
+        let (i, hmenu) = match self.handle.find_entry(&entries, item_idx) {
+            Some(e) => e,
+            None => return Ok(())
+        };
+        unsafe {
+            if entries[i].visible {
+                // Also destroys the submenu, if any.
+                if user32::DeleteMenu(hmenu, item_idx, MF_BYCOMMAND) == 0 {
+                    return Err(get_win_os_error("Error removing menu item"));
+                }
+            } else if let Some(submenu) = entries[i].submenu {
+                user32::DestroyMenu(submenu);
+            }
+        }
+        // Forget the entry along with everything nested below it.
+        let mut removed = vec![item_idx];
+        let mut n = 0;
+        while n < removed.len() {
+            let parent = removed[n];
+            removed.extend(entries.iter().filter(|e| e.parent == Some(parent)).map(|e| e.id));
+            n += 1;
+        }
+        for entry in entries.iter().filter(|e| removed.contains(&e.id)) {
+            if let Some(icon) = entry.icon {
+                unsafe {
+                    delete_menu_bitmap(icon);
+                }
             }
         }
+        entries.retain(|e| !removed.contains(&e.id));
+        let mut radio_items = self.radio_items.lock().unwrap();
+        for id in removed {
+            radio_items.remove(&id);
+        }
         Ok(())
     }
 
     pub fn set_icon_from_resource(&self, resource_name: &String) -> Result<(), SystrayError> {
         let icon;
         unsafe {
-            icon = user32::LoadImageW(self.info.hinstance,
+            icon = user32::LoadImageW(self.handle.info.hinstance,
                                       to_wstring(&resource_name).as_ptr(),
                                       winapi::IMAGE_ICON,
-                                      64,
-                                      64,
+                                      small_icon_size(),
+                                      small_icon_size(),
                                       0) as HICON;
             if icon == std::ptr::null_mut() as HICON {
                 return Err(get_win_os_error("Error setting icon from resource"));
             }
         }
-        self.set_icon(icon)
+        self.handle.set_icon(icon)
     }
 
     pub fn set_icon_from_file(&self, icon_file: &String) -> Result<(), SystrayError> {
-        let wstr_icon_file = to_wstring(&icon_file);
-        let hicon;
-        unsafe {
-            hicon = user32::LoadImageW(std::ptr::null_mut() as HINSTANCE, wstr_icon_file.as_ptr(),
-                                       winapi::IMAGE_ICON, 64, 64, winapi::LR_LOADFROMFILE) as HICON;
-            if hicon == std::ptr::null_mut() as HICON {
-                return Err(get_win_os_error("Error setting icon from file"));
-            }
-        }
-        self.set_icon(hicon)
+        self.handle.set_icon_from_file(icon_file)
     }
 
     pub fn set_icon_from_buffer(&self, buffer: &[u8], width: u32, height: u32) -> Result<(), SystrayError> {
@@ -410,15 +1218,114 @@ impl Window {
                 return Err( unsafe { get_win_os_error("Cannot load icon from the buffer") } );
             }
 
-            self.set_icon(hicon)
+            self.handle.set_icon(hicon)
         } else {
             Err( unsafe { get_win_os_error("Error setting icon from buffer") })
         }
     }
 
+    pub fn set_icon_from_rgba(&self, data: &[u8], width: u32, height: u32) -> Result<(), SystrayError> {
+        // 32 bit icons take their pixels in BGRA order. The AND mask, one
+        // bit per pixel with rows padded to 16 bits, is left clear so the
+        // alpha channel decides.
+        let mut bgra = data.to_vec();
+        for px in bgra.chunks_mut(4) {
+            px.swap(0, 2);
+        }
+        let mask = vec![0u8; (width as usize + 15) / 16 * 2 * height as usize];
+        let hicon = unsafe {
+            CreateIcon(self.handle.info.hinstance, width as i32, height as i32, 1, 32,
+                       mask.as_ptr(), bgra.as_ptr())
+        };
+        if hicon == std::ptr::null_mut() as HICON {
+            return Err(unsafe { get_win_os_error("Error creating icon from pixels") });
+        }
+        self.handle.set_icon(hicon)
+    }
+
+    // Icon resources may hold PNG images since Vista, so no decoding is
+    // needed. Zero sizes keep the size of the image.
+    pub fn set_icon_from_png(&self, png: &[u8]) -> Result<(), SystrayError> {
+        let hicon = unsafe {
+            user32::CreateIconFromResourceEx(png.as_ptr() as PBYTE,
+                                             png.len() as DWORD,
+                                             TRUE,
+                                             0x30000,
+                                             0,
+                                             0,
+                                             LR_DEFAULTCOLOR)
+        };
+        if hicon == std::ptr::null_mut() as HICON {
+            return Err(unsafe { get_win_os_error("Error creating icon from PNG") });
+        }
+        self.handle.set_icon(hicon)
+    }
+
+    pub fn request_attention(&self, icon_file: &str) -> Result<(), SystrayError> {
+        self.handle.request_attention(icon_file)
+    }
+
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        self.handle.clear_attention()
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        self.handle.set_visible(visible)
+    }
+
+    pub fn set_icon_label(&self, label: &str, guide: &str) -> Result<(), SystrayError> {
+        self.handle.set_icon_label(label, guide)
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.handle.has_capability(capability)
+    }
+
+    pub fn set_icon_set(&self, set: &IconSet) -> Result<(), SystrayError> {
+        self.handle.set_icon(icon_from_set(set)?)
+    }
+
+    pub fn set_icon_variants(&self, light: &IconSet, dark: &IconSet) -> Result<(), SystrayError> {
+        let icon = icon_from_set(match taskbar_color_scheme() {
+            ColorScheme::Light => light,
+            ColorScheme::Dark => dark
+        })?;
+        *self.handle.icon_variants.lock().unwrap() = Some((light.clone(), dark.clone()));
+        show_normal_icon(&self.handle.info.hwnd, &self.handle.icons, icon)
+    }
+
+    // There is no icon theme on Windows, so names are looked up in hicolor
+    // below the theme path, at the size of small icons. Only PNG files
+    // can be loaded.
+    pub fn set_icon_from_theme(&self, name: &str) -> Result<(), SystrayError> {
+        let mut theme = IconTheme::new("hicolor").extensions(&["png"]);
+        if let Some(ref dir) = *self.icon_theme_path.borrow() {
+            theme = theme.prepend_dir(dir);
+        }
+        let file = match theme.lookup(name, small_icon_size() as u32, 1) {
+            Some(f) => f,
+            None => return Err(SystrayError::OsError(format!("No icon named {}", name)))
+        };
+        let png = fs::read(&file)
+            .map_err(|e| SystrayError::OsError(format!("Error reading {}: {}", file.display(), e)))?;
+        self.set_icon_from_png(&png)
+    }
+
+    pub fn set_icon_theme_path(&self, dir: &str) -> Result<(), SystrayError> {
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            return Err(SystrayError::OsError(format!("No icon theme directory at {}", dir.display())));
+        }
+        *self.icon_theme_path.borrow_mut() = Some(dir);
+        Ok(())
+    }
+
     pub fn shutdown(&self) -> Result<(), SystrayError> {
+        if !self.handle.icons.lock().unwrap().visible {
+            return Ok(());
+        }
         unsafe {
-            let mut nid = get_nid_struct(&self.info.hwnd);
+            let mut nid = get_nid_struct(&self.handle.info.hwnd);
             nid.uFlags = winapi::NIF_ICON;
             if Shell_NotifyIconW(winapi::NIM_DELETE,
                                           &mut nid as *mut NOTIFYICONDATAW) == 0 {
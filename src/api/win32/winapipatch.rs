@@ -2,8 +2,12 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
-use winapi::{DWORD, LPMENUITEMINFOA, LPMENUITEMINFOW, c_int, RECT, UINT, BOOL, ULONG_PTR, CHAR, GUID, WCHAR};
-use winapi::windef::{HWND, HMENU, HICON, HBRUSH, HBITMAP};
+use winapi::{BYTE, DWORD, LPMENUITEMINFOA, LPMENUITEMINFOW, LPCMENUITEMINFOW, c_int, RECT, UINT, BOOL, ULONG_PTR, CHAR, GUID, WCHAR};
+use winapi::windef::{HWND, HMENU, HICON, HBRUSH, HBITMAP, HGDIOBJ};
+use winapi::minwindef::HINSTANCE;
+use winapi::{HKEY, LONG, LPCWSTR, LPDWORD, PVOID, UINT_PTR};
+
+pub type TIMERPROC = Option<unsafe extern "system" fn(HWND, UINT, UINT_PTR, DWORD)>;
 
 macro_rules! UNION {
     ($base:ident, $field:ident, $variant:ident, $variantmut:ident, $fieldtype:ty) => {
@@ -45,15 +49,34 @@ extern "system" {
     pub fn GetMenuItemID(hMenu: HMENU, nPos: c_int) -> UINT;
     pub fn GetMenuItemInfoA(hMenu: HMENU, uItem: UINT, fByPosition: BOOL, lpmii: LPMENUITEMINFOA) -> BOOL;
     pub fn GetMenuItemInfoW(hMenu: HMENU, uItem: UINT, fByPosition: BOOL, lpmii: LPMENUITEMINFOW) -> BOOL;
+    pub fn RemoveMenu(hMenu: HMENU, uPosition: UINT, uFlags: UINT) -> BOOL;
     pub fn SetMenuInfo(hMenu: HMENU, lpcmi: LPCMENUINFO) -> BOOL;
+    pub fn SetMenuItemInfoW(hMenu: HMENU, uItem: UINT, fByPosition: BOOL, lpmii: LPCMENUITEMINFOW) -> BOOL;
     pub fn TrackPopupMenu(hMenu: HMENU, uFlags: UINT, x: c_int, y: c_int, nReserved: c_int,
                           hWnd: HWND, prcRect: *const RECT);
     pub fn TrackPopupMenuEx(hMenu: HMENU, fuFlags: UINT, x: c_int, y: c_int, hWnd: HWND,
                             lptpm: LPTPMPARAMS);
     pub fn Shell_NotifyIconA(dwMessage: DWORD, lpData: PNOTIFYICONDATAA) -> BOOL;
     pub fn Shell_NotifyIconW(dwMessage: DWORD, lpData: PNOTIFYICONDATAW) -> BOOL;
+    pub fn CreateIcon(hInstance: HINSTANCE, nWidth: c_int, nHeight: c_int, cPlanes: BYTE,
+                      cBitsPixel: BYTE, lpbANDbits: *const BYTE, lpbXORbits: *const BYTE) -> HICON;
+    pub fn SetTimer(hWnd: HWND, nIDEvent: UINT_PTR, uElapse: UINT, lpTimerFunc: TIMERPROC) -> UINT_PTR;
+    pub fn KillTimer(hWnd: HWND, uIDEvent: UINT_PTR) -> BOOL;
 }
 
+#[link(name = "gdi32")]
+extern "system" {
+    pub fn DeleteObject(ho: HGDIOBJ) -> BOOL;
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    pub fn RegGetValueW(hkey: HKEY, lpSubKey: LPCWSTR, lpValue: LPCWSTR, dwFlags: DWORD,
+                        pdwType: LPDWORD, pvData: PVOID, pcbData: LPDWORD) -> LONG;
+}
+
+pub const RRF_RT_REG_DWORD: DWORD = 0x00000010;
+
 
 pub const NIM_ADD: DWORD = 0x00000000;
 pub const NIM_MODIFY: DWORD = 0x00000001;
@@ -133,6 +156,9 @@ pub const MFT_RIGHTORDER: UINT = 0x00002000;
 pub const MFT_SEPARATOR: UINT = 0x00000800;
 pub const MFT_STRING: UINT = 0x00000000;
 
+pub const MF_BYCOMMAND: UINT = 0x00000000;
+pub const MF_BYPOSITION: UINT = 0x00000400;
+
 pub const MFS_CHECKED: UINT = 0x00000008;
 pub const MFS_DEFAULT: UINT = 0x00001000;
 pub const MFS_DISABLED: UINT = 0x00000003;
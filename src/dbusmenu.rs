@@ -0,0 +1,609 @@
+//! The menu of an `Application` exported over D-Bus through the
+//! com.canonical.dbusmenu interface, as StatusNotifierItem hosts and global
+//! menu bars read it.
+//!
+//! `DbusMenu` holds no connection of its own. Whoever owns the connection
+//! passes it the method calls for its object path, forwards the events
+//! that come back, and sends the signals from `take_signals` after every
+//! change.
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::Message;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::time::Instant;
+use reconcile::MenuBackend;
+use {find_node, find_node_mut, remove_node, walk_nodes, walk_nodes_mut, MenuNode, MenuNodeKind, SystrayError,
+     SystrayEvent};
+
+const DBUSMENU: &'static str = "com.canonical.dbusmenu";
+const PROPERTIES: &'static str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE: &'static str = "org.freedesktop.DBus.Introspectable";
+// Version of the interface implemented here.
+const VERSION: u32 = 3;
+
+const INTROSPECTION: &'static str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="com.canonical.dbusmenu">
+    <property name="Version" type="u" access="read"/>
+    <property name="TextDirection" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconThemePath" type="as" access="read"/>
+    <method name="GetLayout">
+      <arg name="parentId" type="i" direction="in"/>
+      <arg name="recursionDepth" type="i" direction="in"/>
+      <arg name="propertyNames" type="as" direction="in"/>
+      <arg name="revision" type="u" direction="out"/>
+      <arg name="layout" type="(ia{sv}av)" direction="out"/>
+    </method>
+    <method name="GetGroupProperties">
+      <arg name="ids" type="ai" direction="in"/>
+      <arg name="propertyNames" type="as" direction="in"/>
+      <arg name="properties" type="a(ia{sv})" direction="out"/>
+    </method>
+    <method name="GetProperty">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="Event">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="eventId" type="s" direction="in"/>
+      <arg name="data" type="v" direction="in"/>
+      <arg name="timestamp" type="u" direction="in"/>
+    </method>
+    <method name="EventGroup">
+      <arg name="events" type="a(isvu)" direction="in"/>
+      <arg name="idErrors" type="ai" direction="out"/>
+    </method>
+    <method name="AboutToShow">
+      <arg name="id" type="i" direction="in"/>
+      <arg name="needUpdate" type="b" direction="out"/>
+    </method>
+    <method name="AboutToShowGroup">
+      <arg name="ids" type="ai" direction="in"/>
+      <arg name="updatesNeeded" type="ai" direction="out"/>
+      <arg name="idErrors" type="ai" direction="out"/>
+    </method>
+    <signal name="ItemsPropertiesUpdated">
+      <arg name="updatedProps" type="a(ia{sv})"/>
+      <arg name="removedProps" type="a(ias)"/>
+    </signal>
+    <signal name="LayoutUpdated">
+      <arg name="revision" type="u"/>
+      <arg name="parent" type="i"/>
+    </signal>
+    <signal name="ItemActivationRequested">
+      <arg name="id" type="i"/>
+      <arg name="timestamp" type="u"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get"><arg type="s" direction="in"/><arg type="s" direction="in"/><arg type="v" direction="out"/></method>
+    <method name="GetAll"><arg type="s" direction="in"/><arg type="a{sv}" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg type="s" direction="out"/></method>
+  </interface>
+</node>
+"#;
+
+// A menu item with its properties and children, as GetLayout returns it.
+type Layout = (i32, PropMap, Vec<Variant<Box<RefArg>>>);
+
+/// Id of the menu item standing for the node with id `node_id`. Item 0 is
+/// the menu itself.
+pub fn item_id(node_id: u32) -> i32 {
+    node_id as i32 + 1
+}
+
+fn node_id(item_id: i32) -> Option<u32> {
+    if item_id > 0 {
+        Some(item_id as u32 - 1)
+    } else {
+        None
+    }
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<RefArg>> {
+    Variant(Box::new(value) as Box<RefArg>)
+}
+
+// Underscores mark mnemonics in dbusmenu labels, which the labels of the
+// other backends do not have.
+fn escape_label(label: &str) -> String {
+    label.replace('_', "__")
+}
+
+// PNG files are sent along as icon data. Anything else is passed on by
+// path as the icon name, for hosts that can load it.
+fn load_icon(file: &str) -> Result<Option<Vec<u8>>, SystrayError> {
+    let data = fs::read(file).map_err(|_| SystrayError::OsError(format!("No icon file at {}", file)))?;
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Ok(Some(data))
+    } else {
+        Ok(None)
+    }
+}
+
+// Where the node with this id sits: None for the top level, otherwise the
+// id of the submenu.
+fn parent_of(nodes: &[MenuNode], parent: Option<u32>, id: u32) -> Option<Option<u32>> {
+    for node in nodes {
+        if node.id == id {
+            return Some(parent);
+        }
+        if let Some(found) = parent_of(&node.children, Some(node.id), id) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+fn error_reply(msg: &Message, name: &str, text: &str) -> Message {
+    msg.error(&name.into(), &CString::new(text).unwrap())
+}
+
+fn invalid_args(msg: &Message) -> Message {
+    error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs", "Invalid arguments")
+}
+
+/// A menu as exported at one object path.
+///
+/// Menu changes come in through `MenuBackend`, the same operations the
+/// backends get from `Application`, or all at once through `set_menu`.
+/// Changes to the structure of the menu bump the revision.
+pub struct DbusMenu {
+    path: String,
+    revision: u32,
+    menu: Vec<MenuNode>,
+    // Contents of the PNG icons, by node id
+    icons: HashMap<u32, Vec<u8>>,
+    // Items whose children and whose properties changed since the last
+    // take_signals, the latter along with the names of the properties they
+    // had before
+    layout_changed: Vec<i32>,
+    properties_changed: Vec<(i32, Vec<String>)>,
+}
+
+impl DbusMenu {
+    pub fn new(path: &str) -> DbusMenu {
+        DbusMenu {
+            path: path.to_string(),
+            revision: 1,
+            menu: vec![],
+            icons: HashMap::new(),
+            layout_changed: vec![],
+            properties_changed: vec![],
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+
+    /// The menu as it stands, including check and radio entries toggled
+    /// through the menu.
+    pub fn menu(&self) -> &[MenuNode] {
+        &self.menu
+    }
+
+    /// Replaces the whole menu, like with the result of
+    /// `Application::menu`.
+    pub fn set_menu(&mut self, menu: &[MenuNode]) -> Result<(), SystrayError> {
+        let mut icons = HashMap::new();
+        let mut loaded = Ok(());
+        walk_nodes(menu, &mut |node: &MenuNode| {
+            if let Some(ref icon) = node.icon {
+                match load_icon(icon) {
+                    Ok(Some(data)) => {
+                        icons.insert(node.id, data);
+                    }
+                    Ok(None) => (),
+                    Err(e) => loaded = Err(e)
+                }
+            }
+        });
+        loaded?;
+        self.menu = menu.to_vec();
+        self.icons = icons;
+        self.layout_updated(None);
+        Ok(())
+    }
+
+    fn layout_updated(&mut self, parent: Option<u32>) {
+        self.revision = self.revision.wrapping_add(1);
+        let id = parent.map(item_id).unwrap_or(0);
+        if !self.layout_changed.contains(&id) {
+            self.layout_changed.push(id);
+        }
+    }
+
+    // Called before the properties of the node change, so that hosts can
+    // be told about those it no longer has.
+    fn properties_changing(&mut self, id: u32) {
+        let id = item_id(id);
+        if self.properties_changed.iter().any(|&(changed, _)| changed == id) {
+            return;
+        }
+        let names = match self.item(id) {
+            Some((props, _)) => props.keys().cloned().collect(),
+            None => vec![]
+        };
+        self.properties_changed.push((id, names));
+    }
+
+    fn node_properties(&self, node: &MenuNode) -> PropMap {
+        let mut props = PropMap::new();
+        props.insert("visible".to_string(), variant(node.visible));
+        props.insert("enabled".to_string(), variant(node.enabled));
+        if node.kind == MenuNodeKind::Separator {
+            props.insert("type".to_string(), variant("separator".to_string()));
+            return props;
+        }
+        props.insert("label".to_string(), variant(escape_label(&node.label)));
+        match node.kind {
+            MenuNodeKind::Check { checked } => {
+                props.insert("toggle-type".to_string(), variant("checkmark".to_string()));
+                props.insert("toggle-state".to_string(), variant(checked as i32));
+            }
+            MenuNodeKind::Radio { selected, .. } => {
+                props.insert("toggle-type".to_string(), variant("radio".to_string()));
+                props.insert("toggle-state".to_string(), variant(selected as i32));
+            }
+            MenuNodeKind::Submenu => {
+                props.insert("children-display".to_string(), variant("submenu".to_string()));
+            }
+            MenuNodeKind::Item | MenuNodeKind::Separator => ()
+        }
+        match (self.icons.get(&node.id), &node.icon) {
+            (Some(data), _) => {
+                props.insert("icon-data".to_string(), variant(data.clone()));
+            }
+            (None, &Some(ref icon)) => {
+                props.insert("icon-name".to_string(), variant(icon.clone()));
+            }
+            (None, &None) => ()
+        }
+        props
+    }
+
+    // The properties of an item, and the entries below it. None if there is
+    // no such item.
+    fn item(&self, id: i32) -> Option<(PropMap, &[MenuNode])> {
+        match node_id(id) {
+            None if id == 0 => {
+                let mut props = PropMap::new();
+                props.insert("children-display".to_string(), variant("submenu".to_string()));
+                Some((props, &self.menu))
+            }
+            None => None,
+            Some(n) => find_node(&self.menu, n).map(|node| (self.node_properties(node), &node.children[..]))
+        }
+    }
+
+    // Leaves only the properties asked for. Asking for none asks for all.
+    fn filter(mut props: PropMap, names: &[String]) -> PropMap {
+        if !names.is_empty() {
+            props.retain(|name, _| names.contains(name));
+        }
+        props
+    }
+
+    // Goes depth levels down, or all the way for -1.
+    fn layout(&self, id: i32, depth: i32, names: &[String]) -> Option<Layout> {
+        let (props, children) = self.item(id)?;
+        let mut layout = vec![];
+        if depth != 0 {
+            let depth = if depth > 0 { depth - 1 } else { depth };
+            for child in children {
+                if let Some(child) = self.layout(item_id(child.id), depth, names) {
+                    layout.push(variant(child));
+                }
+            }
+        }
+        Some((id, DbusMenu::filter(props, names), layout))
+    }
+
+    // What a click on the entry does. Check and radio entries toggle here,
+    // since no one else will. Disabled entries and those only holding other
+    // entries do nothing.
+    fn clicked(&mut self, id: u32) -> Option<SystrayEvent> {
+        let (kind, enabled) = find_node(&self.menu, id).map(|n| (n.kind.clone(), n.enabled))?;
+        if !enabled {
+            return None;
+        }
+        let (id, checked, selected) = match kind {
+            MenuNodeKind::Item => (id, None, None),
+            MenuNodeKind::Check { checked } => {
+                self.set_menu_entry_checked(id, !checked).ok();
+                (id, Some(!checked), None)
+            }
+            MenuNodeKind::Radio { group, index, .. } => {
+                self.set_radio_selected(group, index).ok();
+                (group, None, Some(index))
+            }
+            MenuNodeKind::Separator | MenuNodeKind::Submenu => return None
+        };
+        Some(SystrayEvent::MenuItemActivated {
+            id: id,
+            checked: checked,
+            selected: selected,
+            time: Instant::now(),
+            position: None,
+        })
+    }
+
+    // Hosts only tell about opening and closing the menu itself here.
+    fn event(&mut self, id: i32, event: &str) -> Result<Option<SystrayEvent>, ()> {
+        let time = Instant::now();
+        match (node_id(id), event) {
+            (None, "opened") if id == 0 => Ok(Some(SystrayEvent::MenuOpened { time: time, position: None })),
+            (None, "closed") if id == 0 => Ok(Some(SystrayEvent::MenuClosed { time: time, position: None })),
+            (None, _) if id == 0 => Ok(None),
+            (Some(n), "clicked") if find_node(&self.menu, n).is_some() => Ok(self.clicked(n)),
+            (Some(n), _) if find_node(&self.menu, n).is_some() => Ok(None),
+            _ => Err(())
+        }
+    }
+
+    fn property(&self, name: &str) -> Option<Variant<Box<RefArg>>> {
+        Some(match name {
+            "Version" => variant(VERSION),
+            "TextDirection" => variant("ltr".to_string()),
+            "Status" => variant("normal".to_string()),
+            "IconThemePath" => variant(Vec::<String>::new()),
+            _ => return None
+        })
+    }
+
+    /// Answers a method call to the menu, returning the reply along with
+    /// what happened to the menu, like clicks on its entries.
+    pub fn handle_call(&mut self, msg: &Message) -> (Message, Vec<SystrayEvent>) {
+        let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let mut events = vec![];
+        let reply = match (&*interface, &*member) {
+            (DBUSMENU, "GetLayout") => match msg.read3::<i32, i32, Vec<String>>() {
+                Ok((parent, depth, names)) => match self.layout(parent, depth, &names) {
+                    Some(layout) => msg.method_return().append2(self.revision, layout),
+                    None => error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs",
+                                        &format!("No menu item {}", parent))
+                },
+                Err(_) => invalid_args(msg)
+            },
+            (DBUSMENU, "GetGroupProperties") => match msg.read2::<Vec<i32>, Vec<String>>() {
+                Ok((ids, names)) => {
+                    let props: Vec<(i32, PropMap)> = ids.into_iter()
+                        .filter_map(|id| self.item(id).map(|(props, _)| (id, DbusMenu::filter(props, &names))))
+                        .collect();
+                    msg.method_return().append1(props)
+                }
+                Err(_) => invalid_args(msg)
+            },
+            (DBUSMENU, "GetProperty") => match msg.read2::<i32, &str>() {
+                Ok((id, name)) => match self.item(id).and_then(|(mut props, _)| props.remove(name)) {
+                    Some(value) => msg.method_return().append1(value),
+                    None => error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs",
+                                        &format!("No property {} on menu item {}", name, id))
+                },
+                Err(_) => invalid_args(msg)
+            },
+            (DBUSMENU, "Event") => match msg.read2::<i32, &str>() {
+                Ok((id, event)) => match self.event(id, event) {
+                    Ok(event) => {
+                        events.extend(event);
+                        msg.method_return()
+                    }
+                    Err(()) => error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs",
+                                           &format!("No menu item {}", id))
+                },
+                Err(_) => invalid_args(msg)
+            },
+            (DBUSMENU, "EventGroup") => match msg.read1::<Vec<(i32, String, Variant<Box<RefArg>>, u32)>>() {
+                Ok(group) => {
+                    let mut errors = vec![];
+                    for (id, event, _, _) in group {
+                        match self.event(id, &event) {
+                            Ok(event) => events.extend(event),
+                            Err(()) => errors.push(id)
+                        }
+                    }
+                    msg.method_return().append1(errors)
+                }
+                Err(_) => invalid_args(msg)
+            },
+            // The menu is always up to date, so hosts never need to wait.
+            (DBUSMENU, "AboutToShow") => match msg.read1::<i32>() {
+                Ok(id) if self.item(id).is_some() => msg.method_return().append1(false),
+                Ok(id) => error_reply(msg, "org.freedesktop.DBus.Error.InvalidArgs",
+                                      &format!("No menu item {}", id)),
+                Err(_) => invalid_args(msg)
+            },
+            (DBUSMENU, "AboutToShowGroup") => match msg.read1::<Vec<i32>>() {
+                Ok(ids) => {
+                    let errors: Vec<i32> = ids.into_iter().filter(|&id| self.item(id).is_none()).collect();
+                    msg.method_return().append2(Vec::<i32>::new(), errors)
+                }
+                Err(_) => invalid_args(msg)
+            },
+            (PROPERTIES, "Get") => match msg.read2::<&str, &str>() {
+                Ok((DBUSMENU, name)) => match self.property(name) {
+                    Some(value) => msg.method_return().append1(value),
+                    None => error_reply(msg, "org.freedesktop.DBus.Error.UnknownProperty",
+                                        &format!("No property {}", name))
+                },
+                Ok((interface, _)) => error_reply(msg, "org.freedesktop.DBus.Error.UnknownInterface",
+                                                  &format!("No interface {}", interface)),
+                Err(_) => invalid_args(msg)
+            },
+            (PROPERTIES, "GetAll") => match msg.read1::<&str>() {
+                Ok(DBUSMENU) => {
+                    let all: PropMap = ["Version", "TextDirection", "Status", "IconThemePath"].iter()
+                        .filter_map(|&name| self.property(name).map(|v| (name.to_string(), v)))
+                        .collect();
+                    msg.method_return().append1(all)
+                }
+                Ok(_) => msg.method_return().append1(PropMap::new()),
+                Err(_) => invalid_args(msg)
+            },
+            (PROPERTIES, "Set") => error_reply(msg, "org.freedesktop.DBus.Error.PropertyReadOnly",
+                                               "All properties are read only"),
+            (INTROSPECTABLE, "Introspect") => msg.method_return().append1(INTROSPECTION),
+            _ => error_reply(msg, "org.freedesktop.DBus.Error.UnknownMethod",
+                             &format!("No method {}.{}", interface, member))
+        };
+        (reply, events)
+    }
+
+    /// The signals telling hosts about changes since the last call, to be
+    /// sent in order.
+    pub fn take_signals(&mut self) -> Vec<Message> {
+        let mut signals = vec![];
+        for parent in self.layout_changed.drain(..) {
+            signals.push(Message::new_signal(&*self.path, DBUSMENU, "LayoutUpdated").unwrap()
+                         .append2(self.revision, parent));
+        }
+        let changed: Vec<(i32, Vec<String>)> = self.properties_changed.drain(..).collect();
+        let mut updated: Vec<(i32, PropMap)> = vec![];
+        let mut removed: Vec<(i32, Vec<String>)> = vec![];
+        for (id, before) in changed {
+            if let Some((props, _)) = self.item(id) {
+                let gone: Vec<String> = before.into_iter().filter(|name| !props.contains_key(name)).collect();
+                if !gone.is_empty() {
+                    removed.push((id, gone));
+                }
+                updated.push((id, props));
+            }
+        }
+        if !updated.is_empty() {
+            signals.push(Message::new_signal(&*self.path, DBUSMENU, "ItemsPropertiesUpdated").unwrap()
+                         .append2(updated, removed));
+        }
+        signals
+    }
+}
+
+impl MenuBackend for DbusMenu {
+    fn insert_menu_node(&mut self, parent: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        let mut icons = vec![];
+        let mut loaded = Ok(());
+        walk_nodes(&[node.clone()], &mut |node: &MenuNode| {
+            if let Some(ref icon) = node.icon {
+                match load_icon(icon) {
+                    Ok(Some(data)) => icons.push((node.id, data)),
+                    Ok(None) => (),
+                    Err(e) => loaded = Err(e)
+                }
+            }
+        });
+        loaded?;
+        {
+            let siblings = match parent {
+                None => &mut self.menu,
+                Some(p) => match find_node_mut(&mut self.menu, p) {
+                    Some(n) => &mut n.children,
+                    None => return Err(SystrayError::OsError(format!("No submenu with id {}", p)))
+                }
+            };
+            let position = ::std::cmp::min(position, siblings.len());
+            siblings.insert(position, node.clone());
+        }
+        self.icons.extend(icons);
+        self.layout_updated(parent);
+        Ok(())
+    }
+
+    fn remove_menu_entry(&mut self, id: u32) -> Result<(), SystrayError> {
+        let parent = match parent_of(&self.menu, None, id) {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        if let Some(removed) = remove_node(&mut self.menu, id) {
+            let icons = &mut self.icons;
+            walk_nodes(&[removed], &mut |node: &MenuNode| {
+                icons.remove(&node.id);
+            });
+        }
+        self.layout_updated(parent);
+        Ok(())
+    }
+
+    fn set_menu_entry_label(&mut self, id: u32, label: &str) -> Result<(), SystrayError> {
+        self.properties_changing(id);
+        if let Some(node) = find_node_mut(&mut self.menu, id) {
+            node.label = label.to_string();
+        }
+        Ok(())
+    }
+
+    fn set_menu_entry_icon(&mut self, id: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        let data = match icon {
+            Some(icon) => load_icon(icon)?,
+            None => None
+        };
+        self.properties_changing(id);
+        match data {
+            Some(data) => self.icons.insert(id, data),
+            None => self.icons.remove(&id)
+        };
+        if let Some(node) = find_node_mut(&mut self.menu, id) {
+            node.icon = icon.map(|i| i.to_string());
+        }
+        Ok(())
+    }
+
+    fn set_menu_entry_enabled(&mut self, id: u32, enabled: bool) -> Result<(), SystrayError> {
+        self.properties_changing(id);
+        if let Some(node) = find_node_mut(&mut self.menu, id) {
+            node.enabled = enabled;
+        }
+        Ok(())
+    }
+
+    fn set_menu_entry_visible(&mut self, id: u32, visible: bool) -> Result<(), SystrayError> {
+        self.properties_changing(id);
+        if let Some(node) = find_node_mut(&mut self.menu, id) {
+            node.visible = visible;
+        }
+        Ok(())
+    }
+
+    fn set_menu_entry_checked(&mut self, id: u32, checked: bool) -> Result<(), SystrayError> {
+        self.properties_changing(id);
+        if let Some(node) = find_node_mut(&mut self.menu, id) {
+            if let MenuNodeKind::Check { .. } = node.kind {
+                node.kind = MenuNodeKind::Check { checked: checked };
+            }
+        }
+        Ok(())
+    }
+
+    fn set_radio_selected(&mut self, group_idx: u32, member: usize) -> Result<(), SystrayError> {
+        let mut members = vec![];
+        walk_nodes(&self.menu, &mut |node: &MenuNode| {
+            if let MenuNodeKind::Radio { group, .. } = node.kind {
+                if group == group_idx {
+                    members.push(node.id);
+                }
+            }
+        });
+        for id in members {
+            self.properties_changing(id);
+        }
+        walk_nodes_mut(&mut self.menu, &mut |node: &mut MenuNode| {
+            if let MenuNodeKind::Radio { group, index, ref mut selected } = node.kind {
+                if group == group_idx {
+                    *selected = index == member;
+                }
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+use std;
+use std::collections::BTreeSet;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use {IconSet, SystrayError};
+
+const IMAGE_EXTENSIONS: [&'static str; 6] = ["png", "svg", "svgz", "xpm", "ico", "jpg"];
+
+// Icons kept before the least recently used one is removed. Enough for the
+// frames of an animated icon to be reused on every round.
+const MAX_ICONS: usize = 64;
+
+// Sizes listed in the index of the icon theme even before any icon uses
+// them, the same as in hicolor.
+const THEME_SIZES: [u32; 6] = [16, 22, 24, 32, 48, 64];
+
+// Names to try for the cache directory before giving up.
+const DIR_ATTEMPTS: u32 = 16;
+
+// Icons set from memory, written out for backends that only take paths.
+//
+// Files are named by a hash of their contents, so an icon that comes back
+// reuses its file, and changed contents always get a new name. Hosts tend
+// to hold on to what they loaded for a name, and would otherwise keep
+// showing the old icon.
+//
+// Icon sets go into an icon theme below the cache directory instead, so
+// the host can pick the size it needs.
+//
+// Every cache has a directory of its own, which clear removes without
+// touching the icons of other caches in the same process.
+pub struct IconCache {
+    // Created along with the first icon, see dir
+    dir: Option<PathBuf>,
+    // By content hash, most recently used last
+    icons: Vec<(u64, Vec<PathBuf>)>,
+    theme_sizes: BTreeSet<u32>,
+}
+
+// Icons given by file have a directory or an image extension; anything
+// else is an icon name. Files are made absolute, since the host looks for
+// them from a directory of its own.
+pub fn icon_file(icon: &str) -> Result<Option<String>, SystrayError> {
+    let path = Path::new(icon);
+    let is_image = path.extension().and_then(|e| e.to_str())
+        .map_or(false, |e| IMAGE_EXTENSIONS.contains(&&*e.to_lowercase()));
+    if !icon.contains('/') && !is_image {
+        return Ok(None);
+    }
+    if !path.is_file() {
+        return Err(SystrayError::OsError(format!("No icon file at {}", icon)));
+    }
+    let path = path.canonicalize().map_err(|e| SystrayError::OsError(format!("No icon file at {}: {}", icon, e)))?;
+    Ok(Some(path.to_string_lossy().into_owned()))
+}
+
+// The cache directory may end up in the shared temporary directory, so its
+// name must not be guessable ahead of time.
+fn random_name() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u64(now.as_secs());
+        hasher.write_u32(now.subsec_nanos());
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+// Fails if anything is at path already, a symlink included, so whatever
+// someone else put there is never used. What gets created is then ours,
+// which is checked against the owner of a file made in it.
+fn create_private_dir(path: &Path) -> io::Result<()> {
+    fs::DirBuilder::new().mode(0o700).create(path)?;
+    let meta = fs::symlink_metadata(path)?;
+    let probe = path.join(".owner");
+    let owner = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&probe)?.metadata()?.uid();
+    fs::remove_file(&probe)?;
+    if !meta.is_dir() || meta.uid() != owner || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "icon directory is not private"));
+    }
+    Ok(())
+}
+
+// Files are always created anew, removing any earlier one first.
+// create_new refuses to follow a symlink at file.
+fn write_file(file: &Path, data: &[u8]) -> Result<(), SystrayError> {
+    let error = |e: io::Error| SystrayError::OsError(format!("Error writing icon file: {}", e));
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(file.parent().unwrap())
+        .map_err(|e| SystrayError::OsError(format!("Error creating icon directory: {}", e)))?;
+    if let Err(e) = fs::remove_file(file) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(error(e));
+        }
+    }
+    let mut f = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(file).map_err(&error)?;
+    f.write_all(data).map_err(&error)
+}
+
+impl IconCache {
+    // Nothing touches the disk until the first icon comes in.
+    pub fn new() -> IconCache {
+        IconCache {
+            dir: None,
+            icons: vec![],
+            theme_sizes: THEME_SIZES.iter().cloned().collect(),
+        }
+    }
+
+    // The cache directory, created first if it is not there yet.
+    fn dir(&mut self) -> Result<PathBuf, SystrayError> {
+        if let Some(ref dir) = self.dir {
+            return Ok(dir.clone());
+        }
+        let base = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        for _ in 0..DIR_ATTEMPTS {
+            let dir = base.join(format!("systray-rs-{}", random_name()));
+            match create_private_dir(&dir) {
+                Ok(()) => {
+                    self.dir = Some(dir.clone());
+                    return Ok(dir);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(SystrayError::OsError(format!("Error creating icon directory: {}", e)))
+            }
+        }
+        Err(SystrayError::OsError("Error creating icon directory: no free name".to_string()))
+    }
+
+    // The files of the icon with this hash, if they are still there.
+    fn reuse(&mut self, hash: u64) -> Option<Vec<PathBuf>> {
+        let i = self.icons.iter().position(|f| f.0 == hash)?;
+        let entry = self.icons.remove(i);
+        let files = entry.1.clone();
+        self.icons.push(entry);
+        Some(files)
+    }
+
+    fn add(&mut self, hash: u64, files: Vec<PathBuf>) {
+        if self.icons.len() >= MAX_ICONS {
+            let (_, old) = self.icons.remove(0);
+            for file in old {
+                fs::remove_file(file).ok();
+            }
+        }
+        self.icons.push((hash, files));
+    }
+
+    // The file holding png, written first if it is not there yet. Only the
+    // GTK backend takes single icons by path.
+    #[cfg(any(test, all(feature = "appindicator", not(feature = "sni"))))]
+    pub fn file_for(&mut self, png: &[u8]) -> Result<PathBuf, SystrayError> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(png);
+        let hash = hasher.finish();
+        if let Some(mut files) = self.reuse(hash) {
+            return Ok(files.remove(0));
+        }
+        let file = self.dir()?.join(format!("{:016x}.png", hash));
+        write_file(&file, png)?;
+        self.add(hash, vec![file.clone()]);
+        Ok(file)
+    }
+
+    // The icon theme path and icon name to show set by, written first if
+    // it is not there yet. The renditions go into the hicolor theme, in
+    // the directories hicolor itself uses, so hosts find them whichever
+    // index of hicolor they read.
+    pub fn theme_icon_for(&mut self, set: &IconSet) -> Result<(PathBuf, String), SystrayError> {
+        let mut hasher = DefaultHasher::new();
+        for &(size, ref png) in set.pngs() {
+            hasher.write_u32(size);
+            hasher.write(png);
+        }
+        if let Some(svg) = set.svg_data() {
+            hasher.write(b"svg");
+            hasher.write(svg);
+        }
+        let hash = hasher.finish();
+        let theme_path = self.dir()?.join("icons");
+        let name = format!("systray-rs-{:016x}", hash);
+        if self.reuse(hash).is_some() {
+            return Ok((theme_path, name));
+        }
+        let hicolor = theme_path.join("hicolor");
+        let mut files = vec![];
+        for &(size, ref png) in set.pngs() {
+            let file = hicolor.join(format!("{0}x{0}/apps/{1}.png", size, name));
+            write_file(&file, png)?;
+            files.push(file);
+            self.theme_sizes.insert(size);
+        }
+        if let Some(svg) = set.svg_data() {
+            let file = hicolor.join(format!("scalable/apps/{}.svg", name));
+            write_file(&file, svg)?;
+            files.push(file);
+        }
+        write_file(&hicolor.join("index.theme"), self.theme_index().as_bytes())?;
+        self.add(hash, files);
+        Ok((theme_path, name))
+    }
+
+    fn theme_index(&self) -> String {
+        let dirs: Vec<String> = self.theme_sizes.iter().map(|s| format!("{0}x{0}/apps", s)).collect();
+        let mut index = format!("[Icon Theme]\nName=Hicolor\nDirectories={},scalable/apps\n", dirs.join(","));
+        for (dir, size) in dirs.iter().zip(self.theme_sizes.iter()) {
+            index.push_str(&format!("\n[{}]\nSize={}\nType=Threshold\n", dir, size));
+        }
+        index.push_str("\n[scalable/apps]\nSize=64\nMinSize=8\nMaxSize=512\nType=Scalable\n");
+        index
+    }
+
+    // Removes every file along with the directory. Icons coming in later
+    // go to a new one.
+    pub fn clear(&mut self) {
+        self.icons.clear();
+        self.theme_sizes = THEME_SIZES.iter().cloned().collect();
+        if let Some(dir) = self.dir.take() {
+            fs::remove_dir_all(dir).ok();
+        }
+    }
+}
+
+impl Drop for IconCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_private_dir, IconCache, MAX_ICONS};
+    use std::collections::hash_map::DefaultHasher;
+    use std::fs;
+    use std::hash::Hasher;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::PathBuf;
+    use IconSet;
+
+    fn dir_of(cache: &IconCache) -> PathBuf {
+        cache.dir.clone().expect("no cache directory")
+    }
+
+    #[test]
+    fn same_contents_share_a_file() {
+        let mut cache = IconCache::new();
+        let a = cache.file_for(b"first").unwrap();
+        let b = cache.file_for(b"second").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(cache.file_for(b"first").unwrap(), a);
+        assert_eq!(fs::read(&a).unwrap(), b"first");
+        assert_eq!(fs::read(&b).unwrap(), b"second");
+        assert_eq!(fs::read_dir(dir_of(&cache)).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn directory_is_private_and_made_lazily() {
+        let mut cache = IconCache::new();
+        assert!(cache.dir.is_none());
+        let file = cache.file_for(b"icon").unwrap();
+        let dir = dir_of(&cache);
+        assert_eq!(file.parent().unwrap(), dir);
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn existing_paths_are_not_taken_over() {
+        let mut cache = IconCache::new();
+        cache.file_for(b"icon").unwrap();
+        let dir = dir_of(&cache);
+        let taken = dir.join("taken");
+        fs::create_dir(&taken).unwrap();
+        assert!(create_private_dir(&taken).is_err());
+        let link = dir.join("link");
+        symlink(&taken, &link).unwrap();
+        assert!(create_private_dir(&link).is_err());
+    }
+
+    #[test]
+    fn planted_symlinks_are_replaced_not_followed() {
+        let mut cache = IconCache::new();
+        cache.file_for(b"other").unwrap();
+        let dir = dir_of(&cache);
+        let mut hasher = DefaultHasher::new();
+        hasher.write(b"icon");
+        let file = dir.join(format!("{:016x}.png", hasher.finish()));
+        let target = dir.join("target");
+        fs::write(&target, b"untouched").unwrap();
+        symlink(&target, &file).unwrap();
+        assert_eq!(cache.file_for(b"icon").unwrap(), file);
+        assert!(!fs::symlink_metadata(&file).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&file).unwrap(), b"icon");
+        assert_eq!(fs::read(&target).unwrap(), b"untouched");
+    }
+
+    #[test]
+    fn least_recently_used_icon_is_dropped() {
+        let mut cache = IconCache::new();
+        let first = cache.file_for(b"0").unwrap();
+        let second = cache.file_for(b"1").unwrap();
+        for i in 2..MAX_ICONS {
+            cache.file_for(format!("{}", i).as_bytes()).unwrap();
+        }
+        // Using the first again makes the second the oldest.
+        cache.file_for(b"0").unwrap();
+        cache.file_for(b"new").unwrap();
+        assert!(first.exists());
+        assert!(!second.exists());
+        assert_eq!(cache.file_for(b"1").unwrap(), second);
+        assert_eq!(fs::read(&second).unwrap(), b"1");
+    }
+
+    #[test]
+    fn icon_sets_become_a_theme() {
+        let mut cache = IconCache::new();
+        let set = IconSet::new().png(16, b"small").png(96, b"large").svg(b"<svg/>");
+        let (theme_path, name) = cache.theme_icon_for(&set).unwrap();
+        assert_eq!(cache.theme_icon_for(&set).unwrap(), (theme_path.clone(), name.clone()));
+        let hicolor = theme_path.join("hicolor");
+        assert_eq!(fs::read(hicolor.join(format!("16x16/apps/{}.png", name))).unwrap(), b"small");
+        assert_eq!(fs::read(hicolor.join(format!("96x96/apps/{}.png", name))).unwrap(), b"large");
+        assert_eq!(fs::read(hicolor.join(format!("scalable/apps/{}.svg", name))).unwrap(), b"<svg/>");
+        let index = fs::read_to_string(hicolor.join("index.theme")).unwrap();
+        assert!(index.contains("[96x96/apps]\nSize=96\n"));
+        assert!(index.contains("[22x22/apps]\nSize=22\n"));
+
+        let (_, other) = cache.theme_icon_for(&IconSet::new().png(16, b"other")).unwrap();
+        assert_ne!(other, name);
+    }
+
+    #[test]
+    fn clear_removes_only_its_own_directory() {
+        let mut one = IconCache::new();
+        let mut other = IconCache::new();
+        let kept = other.file_for(b"icon").unwrap();
+        let gone = one.file_for(b"icon").unwrap();
+        let dir = dir_of(&one);
+        assert_ne!(dir, dir_of(&other));
+        one.clear();
+        assert!(!dir.exists());
+        assert!(!gone.exists());
+        assert!(kept.exists());
+        assert!(one.file_for(b"icon").unwrap().exists());
+        drop(other);
+        assert!(!kept.exists());
+    }
+}
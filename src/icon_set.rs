@@ -0,0 +1,59 @@
+// Several renditions of one icon, for backends to choose from.
+
+/// One icon in several sizes, for `Application::set_icon_set`.
+///
+/// Renditions are PNG images, usually 16, 22, 24, 32, 48 and 64 pixels
+/// square, along with an optional SVG image for hosts that can scale it.
+/// Each backend picks the rendition that best fits its panel size and
+/// scale factor, rather than scaling a single image.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IconSet {
+    // By size, smallest first
+    pngs: Vec<(u32, Vec<u8>)>,
+    svg: Option<Vec<u8>>,
+}
+
+impl IconSet {
+    pub fn new() -> IconSet {
+        IconSet::default()
+    }
+
+    /// Adds the PNG image `png`, `size` pixels square. It replaces any
+    /// rendition of the same size.
+    pub fn png(mut self, size: u32, png: &[u8]) -> IconSet {
+        match self.pngs.binary_search_by_key(&size, |r| r.0) {
+            Ok(i) => self.pngs[i].1 = png.to_vec(),
+            Err(i) => self.pngs.insert(i, (size, png.to_vec()))
+        }
+        self
+    }
+
+    /// Adds the SVG image `svg`, used at any size by hosts that can.
+    pub fn svg(mut self, svg: &[u8]) -> IconSet {
+        self.svg = Some(svg.to_vec());
+        self
+    }
+
+    /// The PNG renditions along with their sizes, smallest first.
+    pub fn pngs(&self) -> &[(u32, Vec<u8>)] {
+        &self.pngs
+    }
+
+    pub fn svg_data(&self) -> Option<&[u8]> {
+        self.svg.as_ref().map(|s| &s[..])
+    }
+
+    /// The PNG rendition best suited to `size` pixels: the smallest one at
+    /// least that large, since scaling down looks better than scaling up,
+    /// or else the largest one.
+    pub fn best_png(&self, size: u32) -> Option<(u32, &[u8])> {
+        self.pngs.iter()
+            .find(|r| r.0 >= size)
+            .or_else(|| self.pngs.last())
+            .map(|r| (r.0, &r.1[..]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pngs.is_empty() && self.svg.is_none()
+    }
+}
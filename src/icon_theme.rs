@@ -0,0 +1,233 @@
+//! Icon lookup following the freedesktop.org Icon Theme Specification, for
+//! backends that need the pixels of a named icon rather than just its name.
+
+use std;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Where a theme keeps icons of one size, from its index.theme.
+struct ThemeDir {
+    path: String,
+    size: u32,
+    scale: u32,
+    kind: DirKind,
+}
+
+enum DirKind {
+    Fixed,
+    Scalable { min: u32, max: u32 },
+    Threshold(u32),
+}
+
+impl ThemeDir {
+    fn matches_size(&self, size: u32, scale: u32) -> bool {
+        if self.scale != scale {
+            return false;
+        }
+        match self.kind {
+            DirKind::Fixed => self.size == size,
+            DirKind::Scalable { min, max } => min <= size && size <= max,
+            DirKind::Threshold(t) => self.size.saturating_sub(t) <= size && size <= self.size + t
+        }
+    }
+
+    fn size_distance(&self, size: u32, scale: u32) -> u32 {
+        let wanted = size * scale;
+        let (min, max) = match self.kind {
+            DirKind::Fixed => (self.size, self.size),
+            DirKind::Scalable { min, max } => (min, max),
+            DirKind::Threshold(t) => (self.size.saturating_sub(t), self.size + t)
+        };
+        if wanted < min * self.scale {
+            min * self.scale - wanted
+        } else if wanted > max * self.scale {
+            wanted - max * self.scale
+        } else {
+            0
+        }
+    }
+}
+
+struct ThemeIndex {
+    parents: Vec<String>,
+    dirs: Vec<ThemeDir>,
+}
+
+// Just enough of the desktop entry format for index.theme.
+fn parse_index(text: &str) -> ThemeIndex {
+    let mut sections: Vec<(String, Vec<(String, String)>)> = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push((line[1..line.len() - 1].to_string(), vec![]));
+        } else if let (Some(section), Some(eq)) = (sections.last_mut(), line.find('=')) {
+            section.1.push((line[..eq].trim().to_string(), line[eq + 1..].trim().to_string()));
+        }
+    }
+    let get = |section: &str, key: &str| -> Option<String> {
+        sections.iter()
+            .find(|s| s.0 == section)
+            .and_then(|s| s.1.iter().find(|kv| kv.0 == key))
+            .map(|kv| kv.1.clone())
+    };
+    let list = |value: Option<String>| -> Vec<String> {
+        value.map(|v| v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default()
+    };
+    let number = |section: &str, key: &str| get(section, key).and_then(|v| v.parse().ok());
+    let mut names = list(get("Icon Theme", "Directories"));
+    names.extend(list(get("Icon Theme", "ScaledDirectories")));
+    let dirs = names.into_iter().filter_map(|name| {
+        let size = number(&name, "Size")?;
+        let kind = match get(&name, "Type").as_ref().map(|t| t.as_str()) {
+            Some("Fixed") => DirKind::Fixed,
+            Some("Scalable") => DirKind::Scalable {
+                min: number(&name, "MinSize").unwrap_or(size),
+                max: number(&name, "MaxSize").unwrap_or(size),
+            },
+            _ => DirKind::Threshold(number(&name, "Threshold").unwrap_or(2))
+        };
+        Some(ThemeDir {
+            size: size,
+            scale: number(&name, "Scale").unwrap_or(1),
+            kind: kind,
+            path: name,
+        })
+    }).collect();
+    ThemeIndex {
+        parents: list(get("Icon Theme", "Inherits")),
+        dirs: dirs,
+    }
+}
+
+/// A named icon theme, along with where to look for it.
+pub struct IconTheme {
+    name: String,
+    search_path: Vec<PathBuf>,
+    extensions: Vec<String>,
+}
+
+impl IconTheme {
+    /// The theme called `name`, looked for in the standard places: the
+    /// `icons` directories below `$XDG_DATA_HOME` and `$XDG_DATA_DIRS`,
+    /// `~/.icons` and `/usr/share/pixmaps`.
+    pub fn new(name: &str) -> IconTheme {
+        let mut search_path = vec![];
+        let home = env::var_os("HOME").map(PathBuf::from);
+        match env::var_os("XDG_DATA_HOME") {
+            Some(dir) => search_path.push(PathBuf::from(dir).join("icons")),
+            None => if let Some(ref home) = home {
+                search_path.push(home.join(".local/share/icons"));
+            }
+        }
+        if let Some(ref home) = home {
+            search_path.push(home.join(".icons"));
+        }
+        let data_dirs = env::var("XDG_DATA_DIRS").ok()
+            .filter(|d| !d.is_empty())
+            .unwrap_or_else(|| "/usr/local/share/:/usr/share/".to_string());
+        for dir in data_dirs.split(':').filter(|d| !d.is_empty()) {
+            search_path.push(Path::new(dir).join("icons"));
+        }
+        search_path.push(PathBuf::from("/usr/share/pixmaps"));
+        IconTheme {
+            name: name.to_string(),
+            search_path: search_path,
+            extensions: vec!["png".to_string(), "svg".to_string(), "xpm".to_string()],
+        }
+    }
+
+    /// Looks in `dir` before the standard places. It holds themes in the
+    /// usual layout, like `dir/hicolor/48x48/apps/name.png`.
+    pub fn prepend_dir<P: AsRef<Path>>(mut self, dir: P) -> IconTheme {
+        self.search_path.insert(0, dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Only considers files with these extensions, in order of preference.
+    /// The default is png, svg and xpm.
+    pub fn extensions(mut self, extensions: &[&str]) -> IconTheme {
+        self.extensions = extensions.iter().map(|e| e.to_string()).collect();
+        self
+    }
+
+    /// Finds the file for icon `name` at `size` pixels and `scale`, in this
+    /// theme, the themes it inherits from, hicolor and finally among the
+    /// unthemed icons, in that order.
+    pub fn lookup(&self, name: &str, size: u32, scale: u32) -> Option<PathBuf> {
+        let mut seen = HashSet::new();
+        self.find_in_theme(&self.name, name, size, scale, &mut seen)
+            .or_else(|| self.find_in_theme("hicolor", name, size, scale, &mut seen))
+            .or_else(|| self.find_unthemed(name))
+    }
+
+    fn index(&self, theme: &str) -> Option<ThemeIndex> {
+        self.search_path.iter()
+            .filter_map(|dir| fs::read_to_string(dir.join(theme).join("index.theme")).ok())
+            .next()
+            .map(|text| parse_index(&text))
+    }
+
+    fn find_in_theme(&self, theme: &str, name: &str, size: u32, scale: u32,
+                     seen: &mut HashSet<String>) -> Option<PathBuf> {
+        if !seen.insert(theme.to_string()) {
+            return None;
+        }
+        let index = self.index(theme)?;
+        if let Some(file) = self.lookup_icon(theme, &index, name, size, scale) {
+            return Some(file);
+        }
+        for parent in &index.parents {
+            if let Some(file) = self.find_in_theme(parent, name, size, scale, seen) {
+                return Some(file);
+            }
+        }
+        None
+    }
+
+    // An exact size match if there is one, the closest size otherwise.
+    fn lookup_icon(&self, theme: &str, index: &ThemeIndex, name: &str, size: u32, scale: u32) -> Option<PathBuf> {
+        let mut closest = None;
+        let mut closest_distance = std::u32::MAX;
+        for dir in &index.dirs {
+            let matches = dir.matches_size(size, scale);
+            let distance = dir.size_distance(size, scale);
+            if !matches && distance >= closest_distance {
+                continue;
+            }
+            for base in &self.search_path {
+                for ext in &self.extensions {
+                    let file = base.join(theme).join(&dir.path).join(format!("{}.{}", name, ext));
+                    if !file.is_file() {
+                        continue;
+                    }
+                    if matches {
+                        return Some(file);
+                    }
+                    if distance < closest_distance {
+                        closest = Some(file);
+                        closest_distance = distance;
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    fn find_unthemed(&self, name: &str) -> Option<PathBuf> {
+        for base in &self.search_path {
+            for ext in &self.extensions {
+                let file = base.join(format!("{}.{}", name, ext));
+                if file.is_file() {
+                    return Some(file);
+                }
+            }
+        }
+        None
+    }
+}
@@ -10,18 +10,50 @@ extern crate kernel32;
 extern crate user32;
 #[cfg(target_os = "windows")]
 extern crate libc;
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "appindicator"))]
 extern crate gtk;
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "appindicator"))]
+extern crate gtk_sys;
+#[cfg(all(target_os = "linux", feature = "appindicator"))]
 extern crate glib;
+#[cfg(all(target_os = "linux", feature = "appindicator"))]
+extern crate gobject_sys;
+#[cfg(all(target_os = "linux", feature = "appindicator"))]
+extern crate libappindicator_sys;
 #[cfg(target_os = "linux")]
-extern crate libappindicator;
+extern crate png;
+#[cfg(all(target_os = "linux", feature = "dbus"))]
+extern crate dbus;
+#[cfg(feature = "async")]
+extern crate futures_core;
 
 pub mod api;
+#[cfg(all(target_os = "linux", feature = "dbus"))]
+pub mod dbusmenu;
+#[cfg(target_os = "linux")]
+mod icon_cache;
+pub mod icon_theme;
+mod icon_set;
+#[cfg(all(target_os = "linux", feature = "dbus"))]
+pub mod portal;
+pub mod reconcile;
+mod tooltip;
+#[cfg(feature = "async")]
+mod stream;
 
+pub use icon_set::IconSet;
+pub use tooltip::{Tooltip, TooltipProvider};
+#[cfg(feature = "async")]
+pub use stream::{EventStream, Run};
+
+use reconcile::MenuBackend;
 use std::collections::HashMap;
-use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
-use std::time::Duration;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender, TryRecvError};
+#[cfg(feature = "async")]
+use std::task::Waker;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug)]
 pub enum SystrayError {
@@ -31,8 +63,144 @@ pub enum SystrayError {
     Timeout,
 }
 
-pub struct SystrayEvent {
-    menu_index: u32,
+/// What kind of application the icon belongs to. Hosts may use it to
+/// group and order icons.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    ApplicationStatus,
+    Communications,
+    SystemServices,
+    Hardware,
+}
+
+/// Features only some backends have, see `Application::has_capability`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// A text label next to the icon, see `Application::set_icon_label`.
+    /// This only means the backend exports the label. Whether it shows is
+    /// up to the host, which cannot be asked.
+    Label,
+    /// An attention state of the icon's own. Without it, `request_attention`
+    /// makes the icon blink.
+    NativeAttention,
+    /// Asking for the tooltip when it is about to be shown, see
+    /// `Application::set_tooltip_provider`.
+    TooltipProvider,
+}
+
+/// Whether the desktop, or the panel the icon is in, is light or dark.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+/// Axis of a `SystrayEvent::Scroll`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrollOrientation {
+    Horizontal,
+    Vertical,
+}
+
+/// Something that happened to the tray icon or its menu.
+///
+/// Every event carries the time it was received from the platform and, where
+/// the platform reports it, the pointer position in screen coordinates.
+/// Not every backend can report every kind of event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SystrayEvent {
+    /// A menu entry was clicked. Its callback, if any, has been run by the
+    /// time the event is returned from `wait_for_message`.
+    MenuItemActivated {
+        /// Id of the entry, or of the group for radio entries.
+        id: u32,
+        /// New state of a check entry.
+        checked: Option<bool>,
+        /// Index of the chosen entry of a radio group.
+        selected: Option<usize>,
+        time: Instant,
+        position: Option<(i32, i32)>,
+    },
+    /// The icon was clicked with the primary button.
+    IconActivated { time: Instant, position: Option<(i32, i32)> },
+    /// The icon was middle clicked.
+    SecondaryActivate { time: Instant, position: Option<(i32, i32)> },
+    /// The menu was asked for, usually with a right click.
+    ContextMenuRequested { time: Instant, position: Option<(i32, i32)> },
+    /// The mouse wheel was turned over the icon. Positive deltas scroll down
+    /// or to the right.
+    Scroll {
+        delta: i32,
+        orientation: ScrollOrientation,
+        time: Instant,
+        position: Option<(i32, i32)>,
+    },
+    MenuOpened { time: Instant, position: Option<(i32, i32)> },
+    MenuClosed { time: Instant, position: Option<(i32, i32)> },
+}
+
+impl SystrayEvent {
+    pub fn time(&self) -> Instant {
+        match *self {
+            SystrayEvent::MenuItemActivated { time, .. } |
+            SystrayEvent::IconActivated { time, .. } |
+            SystrayEvent::SecondaryActivate { time, .. } |
+            SystrayEvent::ContextMenuRequested { time, .. } |
+            SystrayEvent::Scroll { time, .. } |
+            SystrayEvent::MenuOpened { time, .. } |
+            SystrayEvent::MenuClosed { time, .. } => time
+        }
+    }
+
+    pub fn position(&self) -> Option<(i32, i32)> {
+        match *self {
+            SystrayEvent::MenuItemActivated { position, .. } |
+            SystrayEvent::IconActivated { position, .. } |
+            SystrayEvent::SecondaryActivate { position, .. } |
+            SystrayEvent::ContextMenuRequested { position, .. } |
+            SystrayEvent::Scroll { position, .. } |
+            SystrayEvent::MenuOpened { position, .. } |
+            SystrayEvent::MenuClosed { position, .. } => position
+        }
+    }
+}
+
+/// Sending half of the event channel, handed to the backend when the
+/// `Application` is created.
+///
+/// With the `async` feature, sending also wakes up the task polling an
+/// `EventStream`, if there is one.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: Sender<SystrayEvent>,
+    #[cfg(feature = "async")]
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl EventSender {
+    pub fn send(&self, event: SystrayEvent) -> Result<(), SendError<SystrayEvent>> {
+        let result = self.tx.send(event);
+        #[cfg(feature = "async")]
+        self.wake();
+        result
+    }
+
+    #[cfg(feature = "async")]
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for EventSender {
+    // Every clone wakes the task as it goes, since any of them may be the
+    // last one, whose going ends the stream. For the others the task only
+    // looks once more and finds nothing.
+    fn drop(&mut self) {
+        self.wake();
+    }
 }
 
 impl std::fmt::Display for SystrayError {
@@ -51,6 +219,11 @@ impl From<std::sync::mpsc::RecvError> for SystrayError {
         SystrayError::Disconnected
     }
 }
+impl From<TryRecvError> for SystrayError {
+    fn from(_: TryRecvError) -> SystrayError {
+        SystrayError::Disconnected
+    }
+}
 impl From<RecvTimeoutError> for SystrayError {
     fn from(e: RecvTimeoutError) -> SystrayError {
         match e {
@@ -59,106 +232,1203 @@ impl From<RecvTimeoutError> for SystrayError {
         }
     }
 }
+/// Kind of a `MenuNode`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MenuNodeKind {
+    Item,
+    Separator,
+    Check { checked: bool },
+    /// Entry `index` of the radio group with id `group`. The members of a
+    /// group are siblings; the group itself has no node of its own.
+    Radio { group: u32, index: usize, selected: bool },
+    /// Entry opening a nested menu made of its `children`.
+    Submenu,
+}
+
+/// A menu entry with its id assigned, as handed to the backends.
+///
+/// `Application` keeps the current menu as a tree of these, whether it was
+/// built through `set_menu` or through the `add_*` calls.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MenuNode {
+    pub id: u32,
+    /// Identity given through `MenuEntry::key`, used to match entries up
+    /// when the menu is replaced.
+    pub key: Option<String>,
+    pub kind: MenuNodeKind,
+    pub label: String,
+    /// Path of an image file shown next to the label.
+    pub icon: Option<String>,
+    pub enabled: bool,
+    pub visible: bool,
+    pub children: Vec<MenuNode>,
+}
+
+// Changes a node through the backend, and then in the model once the
+// backend took the change. Both happen under the update lock, see
+// Application::menu_update.
+fn update_node<B, F>(menu: &Mutex<Vec<MenuNode>>, update: &Mutex<()>, idx: u32, backend: B, f: F)
+                     -> Result<(), SystrayError>
+    where B: FnOnce() -> Result<(), SystrayError>,
+          F: FnOnce(&mut MenuNode) {
+    let _update = update.lock().unwrap();
+    backend()?;
+    if let Some(node) = find_node_mut(&mut menu.lock().unwrap(), idx) {
+        f(node);
+    }
+    Ok(())
+}
+
+fn select_radio(menu: &Mutex<Vec<MenuNode>>, group_idx: u32, member: usize) {
+    walk_nodes_mut(&mut menu.lock().unwrap(), &mut |node: &mut MenuNode| {
+        if let MenuNodeKind::Radio { group, index, ref mut selected } = node.kind {
+            if group == group_idx {
+                *selected = index == member;
+            }
+        }
+    });
+}
+
+fn find_node(nodes: &[MenuNode], idx: u32) -> Option<&MenuNode> {
+    for node in nodes {
+        if node.id == idx {
+            return Some(node);
+        }
+        if let Some(found) = find_node(&node.children, idx) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+fn find_node_mut(nodes: &mut [MenuNode], idx: u32) -> Option<&mut MenuNode> {
+    for node in nodes.iter_mut() {
+        if node.id == idx {
+            return Some(node);
+        }
+        if let Some(found) = find_node_mut(&mut node.children, idx) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+fn remove_node(nodes: &mut Vec<MenuNode>, idx: u32) -> Option<MenuNode> {
+    if let Some(i) = nodes.iter().position(|n| n.id == idx) {
+        return Some(nodes.remove(i));
+    }
+    for node in nodes.iter_mut() {
+        if let Some(removed) = remove_node(&mut node.children, idx) {
+            return Some(removed);
+        }
+    }
+    None
+}
+
+// Calls f on every node of the tree, depth first, in menu order.
+fn walk_nodes<F>(nodes: &[MenuNode], f: &mut F)
+    where F: FnMut(&MenuNode) {
+    for node in nodes {
+        f(node);
+        walk_nodes(&node.children, f);
+    }
+}
+
+fn walk_nodes_mut<F>(nodes: &mut [MenuNode], f: &mut F)
+    where F: FnMut(&mut MenuNode) {
+    for node in nodes.iter_mut() {
+        f(node);
+        walk_nodes_mut(&mut node.children, f);
+    }
+}
+
+/// Handle to an entry in the tray menu, returned by
+/// `Application::add_menu_item` and `Application::add_menu_separator`.
+///
+/// Handles are plain ids, so they can be stored freely and used from inside
+/// menu callbacks, which receive the `Application` they belong to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MenuItem {
+    id: u32,
+}
+
+impl MenuItem {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn set_label(&self, app: &mut Application, label: &str) -> Result<(), SystrayError> {
+        update_node(&app.menu, &app.menu_update, self.id,
+                    || app.window.set_menu_entry_label(self.id, label),
+                    |node| node.label = label.to_string())
+    }
+
+    /// Like `set_label`, but returns without waiting for the backend to
+    /// apply the change. Errors are only logged. Meant for labels that change
+    /// often, like progress counters.
+    pub fn set_label_nowait(&self, app: &mut Application, label: &str) {
+        update_node(&app.menu, &app.menu_update, self.id, || {
+            app.window.handle().set_menu_entry_label_nowait(self.id, label);
+            Ok(())
+        }, |node| node.label = label.to_string()).ok();
+    }
+
+    pub fn set_enabled(&self, app: &mut Application, enabled: bool) -> Result<(), SystrayError> {
+        update_node(&app.menu, &app.menu_update, self.id,
+                    || app.window.set_menu_entry_enabled(self.id, enabled),
+                    |node| node.enabled = enabled)
+    }
+
+    pub fn set_visible(&self, app: &mut Application, visible: bool) -> Result<(), SystrayError> {
+        update_node(&app.menu, &app.menu_update, self.id,
+                    || app.window.set_menu_entry_visible(self.id, visible),
+                    |node| node.visible = visible)
+    }
+
+    /// Removes the entry from the menu and drops its callback. The handle is
+    /// consumed, since the id is no longer valid afterwards.
+    pub fn remove(self, app: &mut Application) -> Result<(), SystrayError> {
+        app.remove_entry(self.id)
+    }
+}
+
+/// Handle to a checkable menu entry, returned by `Application::add_check_item`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CheckItem {
+    item: MenuItem,
+}
+
+impl CheckItem {
+    /// The underlying entry, for relabeling, disabling or removing it.
+    pub fn item(&self) -> MenuItem {
+        self.item
+    }
+
+    pub fn is_checked(&self, app: &Application) -> bool {
+        match find_node(&app.menu.lock().unwrap(), self.item.id) {
+            Some(&MenuNode { kind: MenuNodeKind::Check { checked }, .. }) => checked,
+            _ => false
+        }
+    }
+
+    /// Changes the state without running the item's callback.
+    pub fn set_checked(&self, app: &mut Application, checked: bool) -> Result<(), SystrayError> {
+        update_node(&app.menu, &app.menu_update, self.item.id,
+                    || app.window.set_menu_entry_checked(self.item.id, checked),
+                    |node| node.kind = MenuNodeKind::Check { checked: checked })
+    }
+}
+
+/// Handle to a set of mutually exclusive menu entries, returned by
+/// `Application::add_radio_group`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RadioGroup {
+    id: u32,
+}
+
+impl RadioGroup {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The entries of the group, in the order they were added.
+    pub fn items(&self, app: &Application) -> Vec<MenuItem> {
+        let mut items = vec![];
+        walk_nodes(&app.menu.lock().unwrap(), &mut |node: &MenuNode| {
+            if let MenuNodeKind::Radio { group, .. } = node.kind {
+                if group == self.id {
+                    items.push(MenuItem { id: node.id });
+                }
+            }
+        });
+        items
+    }
+
+    pub fn selected(&self, app: &Application) -> usize {
+        let mut selected = 0;
+        walk_nodes(&app.menu.lock().unwrap(), &mut |node: &MenuNode| {
+            if let MenuNodeKind::Radio { group, index, selected: true } = node.kind {
+                if group == self.id {
+                    selected = index;
+                }
+            }
+        });
+        selected
+    }
+
+    /// Changes the selection without running the group's callback.
+    pub fn set_selected(&self, app: &mut Application, index: usize) -> Result<(), SystrayError> {
+        let len = self.items(app).len();
+        if len == 0 {
+            return Ok(());
+        }
+        if index >= len {
+            return Err(SystrayError::OsError(format!("Radio group has no item {}", index)));
+        }
+        let _update = app.menu_update.lock().unwrap();
+        app.window.set_radio_selected(self.id, index)?;
+        select_radio(&app.menu, self.id, index);
+        Ok(())
+    }
+
+    /// Removes every entry of the group from the menu.
+    pub fn remove(self, app: &mut Application) -> Result<(), SystrayError> {
+        for item in self.items(app) {
+            app.remove_entry(item.id)?;
+        }
+        app.callback.remove(&self.id);
+        Ok(())
+    }
+}
+
+/// Handle to a nested menu, returned by `Application::add_submenu`.
+///
+/// Entries added through the handle behave exactly like top level ones, and
+/// their callbacks are run by the same `wait_for_message` loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubmenuHandle {
+    item: MenuItem,
+}
+
+impl SubmenuHandle {
+    /// The entry that opens the submenu, for relabeling, disabling or
+    /// removing it. Removing it also removes everything inside.
+    pub fn item(&self) -> MenuItem {
+        self.item
+    }
+
+    pub fn add_item<F>(&self, app: &mut Application, item_name: &str, f: F) -> Result<MenuItem, SystrayError>
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        app.add_menu_item_to(Some(self.item.id), item_name, f)
+    }
+
+    pub fn add_check_item<F>(&self, app: &mut Application, item_name: &str, checked: bool, f: F) -> Result<CheckItem, SystrayError>
+        where F: std::ops::Fn(&mut Application, bool) -> () + 'static {
+        app.add_check_item_to(Some(self.item.id), item_name, checked, f)
+    }
+
+    pub fn add_radio_group<F>(&self, app: &mut Application, item_names: &[&str], selected: usize, f: F) -> Result<RadioGroup, SystrayError>
+        where F: std::ops::Fn(&mut Application, RadioGroup, usize) -> () + 'static {
+        app.add_radio_group_to(Some(self.item.id), item_names, selected, f)
+    }
+
+    pub fn add_separator(&self, app: &mut Application) -> Result<MenuItem, SystrayError> {
+        app.add_menu_separator_to(Some(self.item.id))
+    }
+
+    pub fn add_submenu(&self, app: &mut Application, item_name: &str) -> Result<SubmenuHandle, SystrayError> {
+        app.add_submenu_to(Some(self.item.id), item_name)
+    }
+}
+
+enum MenuEntryKind {
+    Item(Callback),
+    Separator,
+    Check(bool, Callback),
+    Radio(Vec<String>, usize, Callback),
+    Submenu(Menu),
+}
+
+/// One entry of a `Menu`.
+///
+/// Created through the constructors below, then adjusted with the builder
+/// methods, e.g. `MenuEntry::item("Quit", |app| app.quit()).icon("quit.png")`.
+pub struct MenuEntry {
+    kind: MenuEntryKind,
+    key: Option<String>,
+    label: String,
+    icon: Option<String>,
+    enabled: bool,
+    visible: bool,
+}
+
+impl MenuEntry {
+    fn new(kind: MenuEntryKind, label: &str) -> MenuEntry {
+        MenuEntry {
+            kind: kind,
+            key: None,
+            label: label.to_string(),
+            icon: None,
+            enabled: true,
+            visible: true,
+        }
+    }
+
+    pub fn item<F>(label: &str, f: F) -> MenuEntry
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        MenuEntry::new(MenuEntryKind::Item(make_callback(f)), label)
+    }
+
+    pub fn separator() -> MenuEntry {
+        MenuEntry::new(MenuEntryKind::Separator, "")
+    }
+
+    /// Entry with a check box. The callback receives the new state.
+    pub fn check<F>(label: &str, checked: bool, f: F) -> MenuEntry
+        where F: std::ops::Fn(&mut Application, bool) -> () + 'static {
+        MenuEntry::new(MenuEntryKind::Check(checked, make_check_callback(f)), label)
+    }
+
+    /// A radio group, expanded into one entry per label. Builder settings
+    /// apply to every one of them.
+    pub fn radio<F>(labels: &[&str], selected: usize, f: F) -> MenuEntry
+        where F: std::ops::Fn(&mut Application, RadioGroup, usize) -> () + 'static {
+        let labels = labels.iter().map(|l| l.to_string()).collect();
+        MenuEntry::new(MenuEntryKind::Radio(labels, selected, make_radio_callback(f)), "")
+    }
+
+    pub fn submenu(label: &str, menu: Menu) -> MenuEntry {
+        MenuEntry::new(MenuEntryKind::Submenu(menu), label)
+    }
+
+    /// Identifies the entry across calls to `Application::set_menu`.
+    ///
+    /// Without a key, an entry takes over an entry of the same kind from the
+    /// previous menu, preferably one with the same label. Keys keep entries
+    /// apart when they are inserted, removed or reordered.
+    pub fn key(mut self, key: &str) -> MenuEntry {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    /// Shows the image at `path` next to the label.
+    pub fn icon(mut self, path: &str) -> MenuEntry {
+        self.icon = Some(path.to_string());
+        self
+    }
+
+    pub fn enabled(mut self, enabled: bool) -> MenuEntry {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn visible(mut self, visible: bool) -> MenuEntry {
+        self.visible = visible;
+        self
+    }
+}
+
+/// Description of a whole tray menu, applied with `Application::set_menu`.
+///
+/// Lets the menu be built as data from the application's own state instead
+/// of through a sequence of `add_*` calls.
+#[derive(Default)]
+pub struct Menu {
+    entries: Vec<MenuEntry>,
+}
+
+impl Menu {
+    pub fn new() -> Menu {
+        Menu { entries: vec![] }
+    }
+
+    /// Appends an entry, builder style.
+    pub fn entry(mut self, entry: MenuEntry) -> Menu {
+        self.entries.push(entry);
+        self
+    }
+
+    pub fn push(&mut self, entry: MenuEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
 pub struct Application {
     window: api::api::Window,
     menu_idx: u32,
     callback: HashMap<u32, Callback>,
+    // Current state of the menu, as far as the backend took it. Kept up to
+    // date both from code and from events coming back from the backend.
+    // Shared with TrayHandle.
+    menu: Arc<Mutex<Vec<MenuNode>>>,
+    // Held by whoever changes the menu, from before telling the backend
+    // until the model follows, so that changes from several threads reach
+    // both in the same order. The model lock itself is never held across
+    // backend calls, so reading the menu does not wait for the backend.
+    menu_update: Arc<Mutex<()>>,
     // Each platform-specific window module will set up its own thread for
     // dealing with the OS main loop. Use this channel for receiving events from
     // that thread.
     rx: Receiver<SystrayEvent>,
+    // Where EventSender finds the task to wake, see EventStream.
+    #[cfg(feature = "async")]
+    waker: Arc<Mutex<Option<Waker>>>,
 }
 
-type Callback = Box<(Fn(&mut Application) -> () + 'static)>;
+// Callbacks are reference counted so a callback can remove its own menu item
+// while it is running.
+type Callback = Rc<(Fn(&mut Application, &SystrayEvent) -> () + 'static)>;
 
 fn make_callback<F>(f: F) -> Callback
     where F: std::ops::Fn(&mut Application) -> () + 'static {
-    Box::new(f) as Callback
+    Rc::new(move |app: &mut Application, _: &SystrayEvent| f(app)) as Callback
 }
 
-impl Application {
-    pub fn new() -> Result<Application, SystrayError> {
+fn make_check_callback<F>(f: F) -> Callback
+    where F: std::ops::Fn(&mut Application, bool) -> () + 'static {
+    Rc::new(move |app: &mut Application, event: &SystrayEvent| {
+        if let SystrayEvent::MenuItemActivated { checked, .. } = *event {
+            f(app, checked.unwrap_or(false))
+        }
+    }) as Callback
+}
+
+fn make_radio_callback<F>(f: F) -> Callback
+    where F: std::ops::Fn(&mut Application, RadioGroup, usize) -> () + 'static {
+    Rc::new(move |app: &mut Application, event: &SystrayEvent| {
+        if let SystrayEvent::MenuItemActivated { id, selected, .. } = *event {
+            f(app, RadioGroup { id: id }, selected.unwrap_or(0))
+        }
+    }) as Callback
+}
+
+fn new_node(idx: u32, kind: MenuNodeKind, label: &str) -> MenuNode {
+    MenuNode {
+        id: idx,
+        key: None,
+        kind: kind,
+        label: label.to_string(),
+        icon: None,
+        enabled: true,
+        visible: true,
+        children: vec![],
+    }
+}
+
+// Passes the changes set_menu makes on to the backend, and follows each
+// one the backend took in a copy of the model. Should one fail, the copy
+// is where the backend got to.
+struct Applied<'a> {
+    window: &'a mut api::api::Window,
+    menu: Vec<MenuNode>,
+}
+
+impl<'a> Applied<'a> {
+    fn update<F>(&mut self, idx: u32, f: F)
+        where F: FnOnce(&mut MenuNode) {
+        if let Some(node) = find_node_mut(&mut self.menu, idx) {
+            f(node);
+        }
+    }
+}
+
+impl<'a> MenuBackend for Applied<'a> {
+    fn insert_menu_node(&mut self, parent: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        MenuBackend::insert_menu_node(self.window, parent, position, node)?;
+        let siblings = match parent {
+            None => &mut self.menu,
+            Some(p) => match find_node_mut(&mut self.menu, p) {
+                Some(n) => &mut n.children,
+                None => return Ok(())
+            }
+        };
+        let position = std::cmp::min(position, siblings.len());
+        siblings.insert(position, node.clone());
+        Ok(())
+    }
+
+    fn remove_menu_entry(&mut self, id: u32) -> Result<(), SystrayError> {
+        MenuBackend::remove_menu_entry(self.window, id)?;
+        remove_node(&mut self.menu, id);
+        Ok(())
+    }
+
+    fn set_menu_entry_label(&mut self, id: u32, label: &str) -> Result<(), SystrayError> {
+        MenuBackend::set_menu_entry_label(self.window, id, label)?;
+        self.update(id, |node| node.label = label.to_string());
+        Ok(())
+    }
+
+    fn set_menu_entry_icon(&mut self, id: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        MenuBackend::set_menu_entry_icon(self.window, id, icon)?;
+        self.update(id, |node| node.icon = icon.map(|i| i.to_string()));
+        Ok(())
+    }
+
+    fn set_menu_entry_enabled(&mut self, id: u32, enabled: bool) -> Result<(), SystrayError> {
+        MenuBackend::set_menu_entry_enabled(self.window, id, enabled)?;
+        self.update(id, |node| node.enabled = enabled);
+        Ok(())
+    }
+
+    fn set_menu_entry_visible(&mut self, id: u32, visible: bool) -> Result<(), SystrayError> {
+        MenuBackend::set_menu_entry_visible(self.window, id, visible)?;
+        self.update(id, |node| node.visible = visible);
+        Ok(())
+    }
+
+    fn set_menu_entry_checked(&mut self, id: u32, checked: bool) -> Result<(), SystrayError> {
+        MenuBackend::set_menu_entry_checked(self.window, id, checked)?;
+        self.update(id, |node| node.kind = MenuNodeKind::Check { checked: checked });
+        Ok(())
+    }
+
+    fn set_radio_selected(&mut self, group_idx: u32, member: usize) -> Result<(), SystrayError> {
+        MenuBackend::set_radio_selected(self.window, group_idx, member)?;
+        walk_nodes_mut(&mut self.menu, &mut |node: &mut MenuNode| {
+            if let MenuNodeKind::Radio { group, index, ref mut selected } = node.kind {
+                if group == group_idx {
+                    *selected = index == member;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+// Turns menu entries into nodes, registering their callbacks under
+// placeholder ids for reconcile::assign_ids to replace.
+fn build_nodes(entries: Vec<MenuEntry>, placeholder: &mut u32,
+               callbacks: &mut HashMap<u32, Callback>) -> Result<Vec<MenuNode>, SystrayError> {
+    let mut nodes = vec![];
+    for entry in entries {
+        *placeholder += 1;
+        let mut node = new_node(*placeholder, MenuNodeKind::Item, &entry.label);
+        node.key = entry.key;
+        node.icon = entry.icon;
+        node.enabled = entry.enabled;
+        node.visible = entry.visible;
+        match entry.kind {
+            MenuEntryKind::Item(f) => {
+                callbacks.insert(node.id, f);
+            }
+            MenuEntryKind::Separator => {
+                node.kind = MenuNodeKind::Separator;
+            }
+            MenuEntryKind::Check(checked, f) => {
+                node.kind = MenuNodeKind::Check { checked: checked };
+                callbacks.insert(node.id, f);
+            }
+            MenuEntryKind::Radio(labels, selected, f) => {
+                if selected >= labels.len() {
+                    return Err(SystrayError::OsError(format!("Radio group has no item {}", selected)));
+                }
+                let group_idx = node.id;
+                callbacks.insert(group_idx, f);
+                for (i, label) in labels.into_iter().enumerate() {
+                    let mut member = node.clone();
+                    *placeholder += 1;
+                    member.id = *placeholder;
+                    member.label = label;
+                    member.kind = MenuNodeKind::Radio { group: group_idx, index: i, selected: i == selected };
+                    nodes.push(member);
+                }
+                continue;
+            }
+            MenuEntryKind::Submenu(menu) => {
+                node.kind = MenuNodeKind::Submenu;
+                node.children = build_nodes(menu.entries, placeholder, callbacks)?;
+            }
+        }
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+/// Creates an `Application` along with what hosts should know about it
+/// from the start.
+pub struct ApplicationBuilder {
+    id: Option<String>,
+    title: String,
+    category: Category,
+    icon: Option<String>,
+    tooltip: Option<Tooltip>,
+}
+
+impl ApplicationBuilder {
+    pub fn new() -> ApplicationBuilder {
+        ApplicationBuilder {
+            id: None,
+            title: String::new(),
+            category: Category::ApplicationStatus,
+            icon: None,
+            tooltip: None,
+        }
+    }
+
+    /// Names the application to hosts, which remember things like the
+    /// position of its icon and whether the user hid it by this name. It
+    /// should be unique and stay the same across runs. Defaults to the name
+    /// of the executable.
+    pub fn id(mut self, id: &str) -> ApplicationBuilder {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Name of the application for people, shown where hosts list their
+    /// icons. Windows and libappindicator show it as the tooltip until one
+    /// is set.
+    pub fn title(mut self, title: &str) -> ApplicationBuilder {
+        self.title = title.to_string();
+        self
+    }
+
+    /// Defaults to `Category::ApplicationStatus`.
+    pub fn category(mut self, category: Category) -> ApplicationBuilder {
+        self.category = category;
+        self
+    }
+
+    /// Icon name or file to start with, like for `set_icon_from_file`.
+    pub fn icon(mut self, icon: &str) -> ApplicationBuilder {
+        self.icon = Some(icon.to_string());
+        self
+    }
+
+    pub fn tooltip<T: Into<Tooltip>>(mut self, tooltip: T) -> ApplicationBuilder {
+        self.tooltip = Some(tooltip.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Application, SystrayError> {
         let (event_tx, event_rx) = channel();
-        match api::api::Window::new(event_tx) {
-            Ok(w) => Ok(Application {
-                window: w,
-                menu_idx: 0,
-                callback: HashMap::new(),
-                rx: event_rx
-            }),
-            Err(e) => Err(e)
+        #[cfg(feature = "async")]
+        let waker = Arc::new(Mutex::new(None));
+        let event_tx = EventSender {
+            tx: event_tx,
+            #[cfg(feature = "async")]
+            waker: waker.clone(),
+        };
+        let id = self.id.unwrap_or_else(|| {
+            std::env::current_exe().ok()
+                .and_then(|exe| exe.file_stem().map(|s| s.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| "systray-rs".to_string())
+        });
+        let app = Application {
+            window: api::api::Window::new(event_tx, &id, &self.title, self.category)?,
+            menu_idx: 0,
+            callback: HashMap::new(),
+            menu: Arc::new(Mutex::new(vec![])),
+            menu_update: Arc::new(Mutex::new(())),
+            rx: event_rx,
+            #[cfg(feature = "async")]
+            waker: waker
+        };
+        if let Some(ref icon) = self.icon {
+            app.set_icon_from_file(icon)?;
         }
+        if let Some(ref tooltip) = self.tooltip {
+            app.set_rich_tooltip(tooltip)?;
+        }
+        Ok(app)
     }
+}
 
-    pub fn add_menu_item<F>(&mut self, item_name: &String, f: F) -> Result<u32, SystrayError>
-        where F: std::ops::Fn(&mut Application) -> () + 'static {
+impl Application {
+    /// An application with everything left at the defaults of
+    /// `ApplicationBuilder`.
+    pub fn new() -> Result<Application, SystrayError> {
+        ApplicationBuilder::new().build()
+    }
+
+    fn next_idx(&mut self) -> u32 {
         let idx = self.menu_idx;
-        if let Err(e) = self.window.add_menu_entry(idx, item_name) {
-            return Err(e);
+        self.menu_idx += 1;
+        idx
+    }
+
+    // Appends a node, and everything below it, to the end of a menu.
+    fn insert_node(&mut self, parent: Option<u32>, node: MenuNode) -> Result<(), SystrayError> {
+        let _update = self.menu_update.lock().unwrap();
+        let position = match parent {
+            None => self.menu.lock().unwrap().len(),
+            Some(p) => match find_node(&self.menu.lock().unwrap(), p) {
+                Some(n) => n.children.len(),
+                None => return Err(SystrayError::OsError(format!("No submenu with id {}", p)))
+            }
+        };
+        self.window.insert_menu_node(parent, position, &node)?;
+        let mut menu = self.menu.lock().unwrap();
+        match parent {
+            None => menu.push(node),
+            Some(p) => if let Some(n) = find_node_mut(&mut menu, p) {
+                n.children.push(node);
+            }
         }
+        Ok(())
+    }
+
+    // Removes an entry along with everything nested below it, and drops the
+    // callbacks nobody can trigger anymore.
+    fn remove_entry(&mut self, idx: u32) -> Result<(), SystrayError> {
+        let removed = {
+            let _update = self.menu_update.lock().unwrap();
+            if find_node(&self.menu.lock().unwrap(), idx).is_none() {
+                return Ok(());
+            }
+            self.window.remove_menu_entry(idx)?;
+            match remove_node(&mut self.menu.lock().unwrap(), idx) {
+                Some(n) => n,
+                None => return Ok(())
+            }
+        };
+        let mut groups = vec![];
+        walk_nodes(&[removed], &mut |node: &MenuNode| {
+            self.callback.remove(&node.id);
+            if let MenuNodeKind::Radio { group, .. } = node.kind {
+                groups.push(group);
+            }
+        });
+        for group in groups {
+            if (RadioGroup { id: group }).items(self).is_empty() {
+                self.callback.remove(&group);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_menu_item_to<F>(&mut self, parent: Option<u32>, item_name: &str, f: F) -> Result<MenuItem, SystrayError>
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        let idx = self.next_idx();
+        self.insert_node(parent, new_node(idx, MenuNodeKind::Item, item_name))?;
         self.callback.insert(idx, make_callback(f));
-        self.menu_idx += 1;
-        Ok(idx)
+        Ok(MenuItem { id: idx })
     }
 
-    pub fn add_menu_separator(&mut self) -> Result<u32, SystrayError> {
-        let idx = self.menu_idx;
-        if let Err(e) = self.window.add_menu_separator(idx) {
-            return Err(e);
+    fn add_check_item_to<F>(&mut self, parent: Option<u32>, item_name: &str, checked: bool, f: F) -> Result<CheckItem, SystrayError>
+        where F: std::ops::Fn(&mut Application, bool) -> () + 'static {
+        let idx = self.next_idx();
+        self.insert_node(parent, new_node(idx, MenuNodeKind::Check { checked: checked }, item_name))?;
+        self.callback.insert(idx, make_check_callback(f));
+        Ok(CheckItem { item: MenuItem { id: idx } })
+    }
+
+    fn add_radio_group_to<F>(&mut self, parent: Option<u32>, item_names: &[&str], selected: usize, f: F) -> Result<RadioGroup, SystrayError>
+        where F: std::ops::Fn(&mut Application, RadioGroup, usize) -> () + 'static {
+        if selected >= item_names.len() {
+            return Err(SystrayError::OsError(format!("Radio group has no item {}", selected)));
         }
-        self.menu_idx += 1;
-        Ok(idx)
+        let group_idx = self.next_idx();
+        for (i, name) in item_names.iter().enumerate() {
+            let idx = self.next_idx();
+            let kind = MenuNodeKind::Radio { group: group_idx, index: i, selected: i == selected };
+            self.insert_node(parent, new_node(idx, kind, name))?;
+        }
+        self.callback.insert(group_idx, make_radio_callback(f));
+        Ok(RadioGroup { id: group_idx })
+    }
+
+    fn add_menu_separator_to(&mut self, parent: Option<u32>) -> Result<MenuItem, SystrayError> {
+        let idx = self.next_idx();
+        self.insert_node(parent, new_node(idx, MenuNodeKind::Separator, ""))?;
+        Ok(MenuItem { id: idx })
+    }
+
+    fn add_submenu_to(&mut self, parent: Option<u32>, item_name: &str) -> Result<SubmenuHandle, SystrayError> {
+        let idx = self.next_idx();
+        self.insert_node(parent, new_node(idx, MenuNodeKind::Submenu, item_name))?;
+        Ok(SubmenuHandle { item: MenuItem { id: idx } })
+    }
+
+    pub fn add_menu_item<F>(&mut self, item_name: &String, f: F) -> Result<MenuItem, SystrayError>
+        where F: std::ops::Fn(&mut Application) -> () + 'static {
+        self.add_menu_item_to(None, item_name, f)
+    }
+
+    /// Adds a menu entry with a check box. The callback receives the new
+    /// state each time the user toggles the entry.
+    pub fn add_check_item<F>(&mut self, item_name: &str, checked: bool, f: F) -> Result<CheckItem, SystrayError>
+        where F: std::ops::Fn(&mut Application, bool) -> () + 'static {
+        self.add_check_item_to(None, item_name, checked, f)
+    }
+
+    /// Adds one entry per label, of which exactly one is selected at a time.
+    /// The callback receives the group and the index of the entry the user
+    /// picked.
+    pub fn add_radio_group<F>(&mut self, item_names: &[&str], selected: usize, f: F) -> Result<RadioGroup, SystrayError>
+        where F: std::ops::Fn(&mut Application, RadioGroup, usize) -> () + 'static {
+        self.add_radio_group_to(None, item_names, selected, f)
     }
 
+    pub fn add_menu_separator(&mut self) -> Result<MenuItem, SystrayError> {
+        self.add_menu_separator_to(None)
+    }
+
+    /// Adds an entry that opens a nested menu. Use the returned handle to
+    /// fill it.
+    pub fn add_submenu(&mut self, item_name: &str) -> Result<SubmenuHandle, SystrayError> {
+        self.add_submenu_to(None, item_name)
+    }
+
+    /// Replaces the whole tray menu with `menu`.
+    ///
+    /// Only the differences to the current menu reach the backend, so an
+    /// open menu stays open and entries that did not change are left alone.
+    /// Entries carried over keep their id, and so do handles to them; see
+    /// `MenuEntry::key` for how they are matched.
+    pub fn set_menu(&mut self, menu: Menu) -> Result<(), SystrayError> {
+        let mut placeholder = 0;
+        let mut callbacks = HashMap::new();
+        let mut nodes = build_nodes(menu.entries, &mut placeholder, &mut callbacks)?;
+        let update = self.menu_update.clone();
+        let _update = update.lock().unwrap();
+        let current = self.menu.lock().unwrap().clone();
+        let ids = {
+            let menu_idx = &mut self.menu_idx;
+            reconcile::assign_ids(&current, &mut nodes, &mut || {
+                let idx = *menu_idx;
+                *menu_idx += 1;
+                idx
+            })
+        };
+        let (applied, result) = {
+            let mut backend = Applied { window: &mut self.window, menu: current.clone() };
+            let result = reconcile::reconcile(&mut backend, None, &current, &nodes);
+            (backend.menu, result)
+        };
+        *self.menu.lock().unwrap() = applied;
+        let mut callback: HashMap<u32, Callback> = callbacks.into_iter().map(|(idx, f)| (ids[&idx], f)).collect();
+        // Entries the backend did not get to replacing keep their callbacks.
+        if result.is_err() {
+            for (idx, f) in self.callback.drain() {
+                callback.entry(idx).or_insert(f);
+            }
+        }
+        self.callback = callback;
+        result
+    }
+
+    /// A copy of the menu as it currently stands, including changes the
+    /// user made by toggling check or radio entries, and changes made
+    /// through a `TrayHandle`.
+    pub fn menu(&self) -> Vec<MenuNode> {
+        self.menu.lock().unwrap().clone()
+    }
+
+    /// A handle for updating the tray from other threads.
+    pub fn handle(&self) -> TrayHandle {
+        TrayHandle {
+            handle: self.window.handle(),
+            menu: self.menu.clone(),
+            menu_update: self.menu_update.clone(),
+        }
+    }
+
+    /// Waits for the backend to load the icon, so a missing file is
+    /// reported here rather than lost. On Linux, names with neither a
+    /// directory nor an image extension are taken for icon names.
     pub fn set_icon_from_file(&self, file: &String) -> Result<(), SystrayError> {
         self.window.set_icon_from_file(file)
     }
 
+    /// Like `set_icon_from_file`, but returns without waiting for the
+    /// backend. Errors are only logged. Meant for animated icons.
+    pub fn set_icon_from_file_nowait(&self, file: &str) {
+        self.window.handle().set_icon_from_file_nowait(file)
+    }
+
     pub fn set_icon_from_resource(&self, resource: &String) -> Result<(), SystrayError> {
         self.window.set_icon_from_resource(resource)
     }
 
+    /// Sets the icon from `width` by `height` pixels, given row by row as
+    /// four bytes each in RGBA order.
+    pub fn set_icon_from_rgba(&self, data: &[u8], width: u32, height: u32) -> Result<(), SystrayError> {
+        if width == 0 || height == 0 || data.len() as u64 != width as u64 * height as u64 * 4 {
+            return Err(SystrayError::OsError(format!("{} bytes are no {}x{} RGBA image",
+                                                     data.len(), width, height)));
+        }
+        self.window.set_icon_from_rgba(data, width, height)
+    }
+
+    /// Sets the icon from the contents of a PNG file, such as one embedded
+    /// with `include_bytes!`.
+    pub fn set_icon_from_png(&self, png: &[u8]) -> Result<(), SystrayError> {
+        self.window.set_icon_from_png(png)
+    }
+
+    /// Sets the icon from several renditions, of which each backend picks
+    /// the best for its panel size and scale factor. On Linux the host
+    /// picks, from an icon theme the renditions are written to. Windows
+    /// picks a PNG rendition for the notification area icon size.
+    pub fn set_icon_set(&self, set: &IconSet) -> Result<(), SystrayError> {
+        if set.is_empty() {
+            return Err(SystrayError::OsError("Icon set is empty".to_string()));
+        }
+        self.window.set_icon_set(set)
+    }
+
+    /// Sets the icon to `light` on light panels and to `dark` on dark ones,
+    /// switching whenever the color scheme changes. `dark` is the one
+    /// drawn in light colors, to stand out on a dark panel.
+    ///
+    /// On Linux the color scheme comes from the XDG settings portal. Where
+    /// the portal has no preference, the appindicator backend goes by the
+    /// GTK theme, which follows XSettings, and the sni backend takes panels
+    /// to be light. Without the `dbus` feature, appindicator only goes by
+    /// the GTK theme. On Windows the color scheme is the one of the
+    /// taskbar. Setting any other icon ends the switching.
+    pub fn set_icon_variants(&self, light: &IconSet, dark: &IconSet) -> Result<(), SystrayError> {
+        if light.is_empty() || dark.is_empty() {
+            return Err(SystrayError::OsError("Icon set is empty".to_string()));
+        }
+        self.window.set_icon_variants(light, dark)
+    }
+
+    /// Sets the icon by its name in the freedesktop icon theme, like
+    /// "network-offline-symbolic". On Linux the host looks it up in its own
+    /// theme, after the directory given to `set_icon_theme_path`. Other
+    /// backends look it up in hicolor, below that directory first, see
+    /// `icon_theme::IconTheme`.
+    pub fn set_icon_from_theme(&self, name: &str) -> Result<(), SystrayError> {
+        self.window.set_icon_from_theme(name)
+    }
+
+    /// Sets a directory of icon themes in the usual layout, like
+    /// `dir/hicolor/48x48/apps/name.png`, that `set_icon_from_theme` looks in
+    /// before the standard places. Meant for icons shipped with the
+    /// application.
+    pub fn set_icon_theme_path(&self, dir: &str) -> Result<(), SystrayError> {
+        self.window.set_icon_theme_path(dir)
+    }
+
     pub fn shutdown(&self) -> Result<(), SystrayError> {
         self.window.shutdown()
     }
 
+    /// Sets the text shown when hovering the icon. With libappindicator it
+    /// becomes the title of the indicator, which StatusNotifierItem hosts
+    /// show instead of a tooltip. Backends that can't show one return
+    /// `SystrayError::NotImplementedError`.
     pub fn set_tooltip(&self, tooltip: &String) -> Result<(), SystrayError> {
+        self.window.set_tooltip(&Tooltip::new(tooltip))
+    }
+
+    /// Like `set_tooltip`, with a body and an icon. Backends that only show
+    /// plain text show `Tooltip::to_plain_text`.
+    pub fn set_rich_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
         self.window.set_tooltip(tooltip)
     }
 
+    /// Has the backend call `f` for the tooltip only when it is about to be
+    /// shown, reusing the result for up to `ttl`. Stays in place until the
+    /// next call to one of the `set_*tooltip` methods.
+    ///
+    /// `f` runs on the backend's thread. Where the host never asks for the
+    /// tooltip, as with libappindicator, this fails with
+    /// `NotImplementedError`; see `Capability::TooltipProvider`.
+    pub fn set_tooltip_provider<F>(&self, ttl: Duration, f: F) -> Result<(), SystrayError>
+        where F: FnMut() -> Tooltip + Send + 'static {
+        self.window.set_tooltip_provider(Arc::new(TooltipProvider::new(ttl, f)))
+    }
+
+    /// Flags the icon, e.g. for unread messages, by showing `icon` instead,
+    /// an icon name or file like for `set_icon_from_file`. On Windows,
+    /// which has no such state, the icon blinks between the two. Lasts
+    /// until `clear_attention`.
+    pub fn request_attention(&self, icon: &str) -> Result<(), SystrayError> {
+        self.window.request_attention(icon)
+    }
+
+    /// Goes back to the normal icon after `request_attention`.
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        self.window.clear_attention()
+    }
+
+    /// Shows `label` next to the icon, like a counter, where the panel
+    /// supports it, see `Capability::Label`. `guide` is the longest text
+    /// the label is expected to hold, e.g. "000.0 GB/s", so the panel can
+    /// keep its width steady. An empty label removes it.
+    pub fn set_icon_label(&self, label: &str, guide: &str) -> Result<(), SystrayError> {
+        self.window.set_icon_label(label, guide)
+    }
+
+    /// Whether the backend supports `capability`.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.window.has_capability(capability)
+    }
+
+    /// Hides or shows the icon again. Unlike `shutdown`, the application
+    /// keeps running while it is hidden: the menu, its callbacks, the icon
+    /// and tooltip are all still there when it comes back, including any
+    /// changes made in between.
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        self.window.set_visible(visible)
+    }
+
     pub fn quit(&mut self) {
         self.window.quit()
     }
 
-    pub fn wait_for_message(&mut self) -> Result<(), SystrayError> {
+    fn dispatch(&mut self, msg: &SystrayEvent) {
+        let (id, checked, selected) = match *msg {
+            SystrayEvent::MenuItemActivated { id, checked, selected, .. } => (id, checked, selected),
+            _ => return
+        };
+        {
+            // In order with changes from other threads, see menu_update.
+            let _update = self.menu_update.lock().unwrap();
+            if let Some(checked) = checked {
+                if let Some(node) = find_node_mut(&mut self.menu.lock().unwrap(), id) {
+                    node.kind = MenuNodeKind::Check { checked: checked };
+                }
+            }
+            if let Some(selected) = selected {
+                select_radio(&self.menu, id, selected);
+            }
+        }
+        let f = match self.callback.get(&id) {
+            Some(f) => f.clone(),
+            None => return
+        };
+        f(self, msg);
+    }
+
+    /// Blocks until the next event, runs the menu callback it belongs to, if
+    /// any, and returns it.
+    pub fn wait_for_message(&mut self) -> Result<SystrayEvent, SystrayError> {
         let msg = self.rx.recv()?;
+        self.dispatch(&msg);
+        Ok(msg)
+    }
 
-        if self.callback.contains_key(&msg.menu_index) {
-            let f = self.callback.remove(&msg.menu_index).unwrap();
-            f(self);
-            self.callback.insert(msg.menu_index, f);
+    /// Like `wait_for_message`, but gives up after `timeout`, returning None.
+    pub fn wait_for_message_timeout(&mut self, timeout: Duration) -> Result<Option<SystrayEvent>, SystrayError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(msg) => {
+                self.dispatch(&msg);
+                Ok(Some(msg))
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(e) => Err(SystrayError::from(e))
         }
-
-        Ok(())
     }
 
-    pub fn wait_for_message_timeout(&mut self, timeout: Duration) -> Result<(), SystrayError> {
-        let msg = match self.rx.recv_timeout(timeout) {
-            Ok(msg) => Some(msg),
-            Err(RecvTimeoutError::Timeout) => None,
-            Err(e) => { return Err(SystrayError::from(e)); }
-        };
+    /// Dispatches the next event if one is already waiting, without
+    /// blocking. Returns None when there is none.
+    pub fn try_dispatch(&mut self) -> Result<Option<SystrayEvent>, SystrayError> {
+        match self.rx.try_recv() {
+            Ok(msg) => {
+                self.dispatch(&msg);
+                Ok(Some(msg))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(e) => Err(SystrayError::from(e))
+        }
+    }
 
-        Ok(match msg {
-            Some(msg) => {
-                if self.callback.contains_key(&msg.menu_index) {
-                    let f = self.callback.remove(&msg.menu_index).unwrap();
-                    f(self);
-                    self.callback.insert(msg.menu_index, f);
+    /// Dispatches every event that is already waiting, without blocking,
+    /// and returns how many there were. Meant to be called from an
+    /// application's own main loop.
+    ///
+    /// A disconnect is only reported once no events are left to hand out.
+    pub fn dispatch_pending(&mut self) -> Result<usize, SystrayError> {
+        let mut count = 0;
+        loop {
+            match self.try_dispatch() {
+                Ok(Some(_)) => count += 1,
+                Ok(None) => return Ok(count),
+                Err(e) => {
+                    if count > 0 {
+                        return Ok(count);
+                    }
+                    return Err(e);
                 }
-            },
-            None => ()
-        })
+            }
+        }
+    }
+
+    /// Blocking iterator over events, dispatching each before it is yielded.
+    /// Ends once the backend shuts down.
+    pub fn events<'a>(&'a mut self) -> Events<'a> {
+        Events { app: self }
+    }
+}
+
+/// Controls the tray from any thread, returned by `Application::handle`.
+///
+/// Commands are passed straight to the backend's own thread, so they take
+/// effect while the application thread is busy or blocked waiting for
+/// events. Menu changes are recorded in the same menu model the
+/// `Application` uses.
+#[derive(Clone)]
+pub struct TrayHandle {
+    handle: api::api::Handle,
+    menu: Arc<Mutex<Vec<MenuNode>>>,
+    menu_update: Arc<Mutex<()>>,
+}
+
+impl TrayHandle {
+    pub fn set_icon_from_file(&self, file: &str) -> Result<(), SystrayError> {
+        self.handle.set_icon_from_file(file)
+    }
+
+    /// See `Application::set_icon_from_file_nowait`.
+    pub fn set_icon_from_file_nowait(&self, file: &str) {
+        self.handle.set_icon_from_file_nowait(file)
+    }
+
+    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), SystrayError> {
+        self.handle.set_tooltip(&Tooltip::new(tooltip))
+    }
+
+    pub fn set_rich_tooltip(&self, tooltip: &Tooltip) -> Result<(), SystrayError> {
+        self.handle.set_tooltip(tooltip)
+    }
+
+    pub fn set_label(&self, item: MenuItem, label: &str) -> Result<(), SystrayError> {
+        update_node(&self.menu, &self.menu_update, item.id,
+                    || self.handle.set_menu_entry_label(item.id, label),
+                    |node| node.label = label.to_string())
+    }
+
+    /// See `MenuItem::set_label_nowait`.
+    pub fn set_label_nowait(&self, item: MenuItem, label: &str) {
+        update_node(&self.menu, &self.menu_update, item.id, || {
+            self.handle.set_menu_entry_label_nowait(item.id, label);
+            Ok(())
+        }, |node| node.label = label.to_string()).ok();
+    }
+
+    /// Changes the state without running the item's callback.
+    pub fn set_checked(&self, item: CheckItem, checked: bool) -> Result<(), SystrayError> {
+        update_node(&self.menu, &self.menu_update, item.item.id,
+                    || self.handle.set_menu_entry_checked(item.item.id, checked),
+                    |node| node.kind = MenuNodeKind::Check { checked: checked })
+    }
+
+    pub fn request_attention(&self, icon: &str) -> Result<(), SystrayError> {
+        self.handle.request_attention(icon)
+    }
+
+    pub fn clear_attention(&self) -> Result<(), SystrayError> {
+        self.handle.clear_attention()
+    }
+
+    pub fn set_visible(&self, visible: bool) -> Result<(), SystrayError> {
+        self.handle.set_visible(visible)
+    }
+
+    /// See `Application::set_icon_label`.
+    pub fn set_icon_label(&self, label: &str, guide: &str) -> Result<(), SystrayError> {
+        self.handle.set_icon_label(label, guide)
+    }
+
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.handle.has_capability(capability)
+    }
+
+    /// Asks the backend to shut down. Once it has, the application's event
+    /// loop ends, as if `Application::quit` had been called.
+    pub fn quit(&self) {
+        self.handle.quit()
+    }
+}
+
+/// Iterator returned by `Application::events`.
+pub struct Events<'a> {
+    app: &'a mut Application,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = SystrayEvent;
+
+    fn next(&mut self) -> Option<SystrayEvent> {
+        self.app.wait_for_message().ok()
     }
 }
 
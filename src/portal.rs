@@ -0,0 +1,194 @@
+//! The color scheme preferred by the desktop, as told by the settings
+//! interface of the XDG desktop portal.
+
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::Message;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use {ColorScheme, SystrayError};
+
+const PORTAL_NAME: &'static str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &'static str = "/org/freedesktop/portal/desktop";
+const SETTINGS: &'static str = "org.freedesktop.portal.Settings";
+const APPEARANCE: &'static str = "org.freedesktop.appearance";
+const COLOR_SCHEME: &'static str = "color-scheme";
+
+// How long to wait for the portal, which may have to be started first.
+const CALL_TIMEOUT_MS: u64 = 2000;
+// How often the threads below look whether they should stop.
+const POLL_MS: u64 = 100;
+
+fn bus_error(e: ::dbus::Error) -> SystrayError {
+    SystrayError::OsError(format!("D-Bus error: {}", e))
+}
+
+// The portal encodes no preference as 0, dark as 1 and light as 2. Read
+// wraps the value in one more variant than ReadOne and the signal do.
+fn scheme_from_value(value: &RefArg) -> Option<ColorScheme> {
+    match value.as_u64() {
+        Some(1) => Some(ColorScheme::Dark),
+        Some(2) => Some(ColorScheme::Light),
+        Some(_) => None,
+        None => value.as_iter().and_then(|mut i| i.next().and_then(scheme_from_value))
+    }
+}
+
+fn scheme_to_value(scheme: Option<ColorScheme>) -> u32 {
+    match scheme {
+        None => 0,
+        Some(ColorScheme::Dark) => 1,
+        Some(ColorScheme::Light) => 2,
+    }
+}
+
+/// Asks the portal for the color scheme. None if there is no preference,
+/// or no portal to ask.
+pub fn read_color_scheme(conn: &Connection) -> Option<ColorScheme> {
+    let proxy = conn.with_proxy(PORTAL_NAME, PORTAL_PATH, Duration::from_millis(CALL_TIMEOUT_MS));
+    let value: Result<(Variant<Box<RefArg>>,), _> = proxy.method_call(SETTINGS, "ReadOne", (APPEARANCE, COLOR_SCHEME));
+    // ReadOne is only there since version 2 of the interface
+    let value = value.or_else(|_| proxy.method_call(SETTINGS, "Read", (APPEARANCE, COLOR_SCHEME)));
+    value.ok().and_then(|(v,)| scheme_from_value(&v))
+}
+
+/// Calls `f` with the color scheme from the portal on a thread of its own,
+/// first right away and then whenever it changes, until dropped.
+pub struct ColorSchemeWatch {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ColorSchemeWatch {
+    pub fn new<F>(mut f: F) -> ColorSchemeWatch
+        where F: FnMut(Option<ColorScheme>) + Send + 'static {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let conn = match Connection::new_session() {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("No session bus to watch the color scheme on: {}", e);
+                    return;
+                }
+            };
+            // Changes arrive through a channel, so f is only called from
+            // this loop.
+            let (tx, rx) = channel();
+            let rule = MatchRule::new_signal(SETTINGS, "SettingChanged").with_path(PORTAL_PATH);
+            let added = conn.add_match(rule, move |(namespace, key, value): (String, String, Variant<Box<RefArg>>), _, _| {
+                if namespace == APPEARANCE && key == COLOR_SCHEME {
+                    tx.send(scheme_from_value(&value)).ok();
+                }
+                true
+            });
+            if let Err(e) = added {
+                warn!("Error watching the color scheme: {}", e);
+                return;
+            }
+            // Read after subscribing, so no change is missed in between.
+            f(read_color_scheme(&conn));
+            while !thread_stop.load(Ordering::SeqCst) {
+                if let Err(e) = conn.process(Duration::from_millis(POLL_MS)) {
+                    warn!("Error watching the color scheme: {}", e);
+                    return;
+                }
+                for scheme in rx.try_iter() {
+                    f(scheme);
+                }
+            }
+        });
+        ColorSchemeWatch {
+            stop: stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for ColorSchemeWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            t.join().ok();
+        }
+    }
+}
+
+/// A stand-in for the settings interface of the portal, for tests. It
+/// takes the name of the portal on the session bus, so everything there
+/// that asks for the color scheme gets the one it was given.
+pub struct StandInPortal {
+    changes: ::std::sync::mpsc::Sender<Option<ColorScheme>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl StandInPortal {
+    pub fn start(scheme: Option<ColorScheme>) -> Result<StandInPortal, SystrayError> {
+        let (tx, rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let conn = match Connection::new_session()
+                .and_then(|c| c.request_name(PORTAL_NAME, false, true, true).map(|_| c)) {
+                Ok(c) => c,
+                Err(e) => {
+                    ready_tx.send(Err(bus_error(e))).ok();
+                    return;
+                }
+            };
+            let value = Arc::new(::std::sync::Mutex::new(scheme_to_value(scheme)));
+            let reply_value = value.clone();
+            conn.start_receive(MatchRule::new_method_call().with_interface(SETTINGS), Box::new(move |msg, conn| {
+                let value = *reply_value.lock().unwrap();
+                let known = msg.get2::<&str, &str>() == (Some(APPEARANCE), Some(COLOR_SCHEME));
+                let reply = match (msg.member().as_ref().map(|m| &**m), known) {
+                    (Some("ReadOne"), true) => msg.method_return().append1(Variant(value)),
+                    (Some("Read"), true) => msg.method_return().append1(Variant(Variant(value))),
+                    _ => msg.error(&"org.freedesktop.portal.Error.NotFound".into(),
+                                   &::std::ffi::CString::new("Requested setting not found").unwrap())
+                };
+                conn.send(reply).ok();
+                true
+            }));
+            ready_tx.send(Ok(())).ok();
+            while !thread_stop.load(Ordering::SeqCst) {
+                if conn.process(Duration::from_millis(POLL_MS)).is_err() {
+                    return;
+                }
+                for scheme in rx.try_iter() {
+                    *value.lock().unwrap() = scheme_to_value(scheme);
+                    let signal = Message::new_signal(PORTAL_PATH, SETTINGS, "SettingChanged").unwrap()
+                        .append3(APPEARANCE, COLOR_SCHEME, Variant(scheme_to_value(scheme)));
+                    conn.send(signal).ok();
+                }
+            }
+        });
+        ready_rx.recv().unwrap_or(Err(SystrayError::OsError("Portal thread exited".to_string())))?;
+        Ok(StandInPortal {
+            changes: tx,
+            stop: stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Changes the color scheme, telling everyone watching.
+    pub fn set_color_scheme(&self, scheme: Option<ColorScheme>) {
+        self.changes.send(scheme).ok();
+    }
+}
+
+impl Drop for StandInPortal {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            t.join().ok();
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// Minimal-diff menu updates.
+//
+// `Application::set_menu` first matches the new menu against the current one
+// with `assign_ids`, so entries that survive keep their ids, then `reconcile`
+// tells the backend only about what actually changed. Nothing here touches a
+// real backend, which keeps it testable against a fake one.
+
+use std;
+use std::collections::{HashMap, HashSet};
+use {api, MenuNode, MenuNodeKind, SystrayError};
+
+/// In-place menu operations offered by a backend.
+pub trait MenuBackend {
+    /// Creates `node`, and everything below it, at `position` among the
+    /// entries of `parent`. None is the top level menu.
+    fn insert_menu_node(&mut self, parent: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError>;
+    /// Removes an entry along with everything nested below it.
+    fn remove_menu_entry(&mut self, id: u32) -> Result<(), SystrayError>;
+    fn set_menu_entry_label(&mut self, id: u32, label: &str) -> Result<(), SystrayError>;
+    fn set_menu_entry_icon(&mut self, id: u32, icon: Option<&str>) -> Result<(), SystrayError>;
+    fn set_menu_entry_enabled(&mut self, id: u32, enabled: bool) -> Result<(), SystrayError>;
+    fn set_menu_entry_visible(&mut self, id: u32, visible: bool) -> Result<(), SystrayError>;
+    fn set_menu_entry_checked(&mut self, id: u32, checked: bool) -> Result<(), SystrayError>;
+    /// Selects member `index` of a radio group, deselecting the others.
+    fn set_radio_selected(&mut self, group: u32, index: usize) -> Result<(), SystrayError>;
+}
+
+impl MenuBackend for api::api::Window {
+    fn insert_menu_node(&mut self, parent: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        api::api::Window::insert_menu_node(self, parent, position, node)
+    }
+
+    fn remove_menu_entry(&mut self, id: u32) -> Result<(), SystrayError> {
+        api::api::Window::remove_menu_entry(self, id)
+    }
+
+    fn set_menu_entry_label(&mut self, id: u32, label: &str) -> Result<(), SystrayError> {
+        api::api::Window::set_menu_entry_label(self, id, label)
+    }
+
+    fn set_menu_entry_icon(&mut self, id: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        api::api::Window::set_menu_entry_icon(self, id, icon)
+    }
+
+    fn set_menu_entry_enabled(&mut self, id: u32, enabled: bool) -> Result<(), SystrayError> {
+        api::api::Window::set_menu_entry_enabled(self, id, enabled)
+    }
+
+    fn set_menu_entry_visible(&mut self, id: u32, visible: bool) -> Result<(), SystrayError> {
+        api::api::Window::set_menu_entry_visible(self, id, visible)
+    }
+
+    fn set_menu_entry_checked(&mut self, id: u32, checked: bool) -> Result<(), SystrayError> {
+        api::api::Window::set_menu_entry_checked(self, id, checked)
+    }
+
+    fn set_radio_selected(&mut self, group: u32, index: usize) -> Result<(), SystrayError> {
+        api::api::Window::set_radio_selected(self, group, index)
+    }
+}
+
+// Whether new could take over the id of old.
+fn can_match(old: &MenuNode, new: &MenuNode, ids: &HashMap<u32, u32>) -> bool {
+    if old.key != new.key {
+        return false;
+    }
+    match (&old.kind, &new.kind) {
+        (&MenuNodeKind::Radio { group: old_group, index: old_index, .. },
+         &MenuNodeKind::Radio { group, index, .. }) => {
+            // All members of a group have to end up in the same group.
+            old_index == index && match ids.get(&group) {
+                Some(g) => *g == old_group,
+                None => !ids.values().any(|g| *g == old_group)
+            }
+        }
+        (o, n) => std::mem::discriminant(o) == std::mem::discriminant(n)
+    }
+}
+
+/// Gives the nodes of `new` the ids of the entries they take the place of in
+/// `old`, and ids from `fresh` when there is no such entry.
+///
+/// Entries are only matched within the same parent menu. An entry with a key
+/// matches the entry with the same key; others match an entry of the same
+/// kind, preferring one with the same label. The ids `new` comes with, radio
+/// group ids included, are placeholders; the returned map tells which id
+/// each of them ended up as.
+pub fn assign_ids<F>(old: &[MenuNode], new: &mut [MenuNode], fresh: &mut F) -> HashMap<u32, u32>
+    where F: FnMut() -> u32 {
+    let mut ids = HashMap::new();
+    assign_sibling_ids(old, new, fresh, &mut ids);
+    ids
+}
+
+fn assign_sibling_ids<F>(old: &[MenuNode], new: &mut [MenuNode], fresh: &mut F, ids: &mut HashMap<u32, u32>)
+    where F: FnMut() -> u32 {
+    let mut claimed = vec![false; old.len()];
+    let mut found = vec![None; new.len()];
+    // Exact matches go first, so an entry inserted in front of others does
+    // not take over the id of the one that follows it.
+    for &exact in [true, false].iter() {
+        for (n, node) in new.iter().enumerate() {
+            if found[n].is_some() {
+                continue;
+            }
+            for (i, o) in old.iter().enumerate() {
+                if claimed[i] || !can_match(o, node, ids) {
+                    continue;
+                }
+                if exact && node.key.is_none() && o.label != node.label {
+                    continue;
+                }
+                claimed[i] = true;
+                found[n] = Some(i);
+                if let (&MenuNodeKind::Radio { group: old_group, .. },
+                        &MenuNodeKind::Radio { group, .. }) = (&o.kind, &node.kind) {
+                    ids.insert(group, old_group);
+                }
+                break;
+            }
+        }
+    }
+    for (n, node) in new.iter_mut().enumerate() {
+        let placeholder = node.id;
+        match found[n] {
+            Some(i) => {
+                node.id = old[i].id;
+                assign_sibling_ids(&old[i].children, &mut node.children, fresh, ids);
+            }
+            None => {
+                node.id = fresh();
+                assign_sibling_ids(&[], &mut node.children, fresh, ids);
+            }
+        }
+        ids.insert(placeholder, node.id);
+        if let MenuNodeKind::Radio { ref mut group, .. } = node.kind {
+            let g = match ids.get(&*group) {
+                Some(g) => *g,
+                None => fresh()
+            };
+            ids.insert(*group, g);
+            *group = g;
+        }
+    }
+}
+
+// Whether old can be turned into new through updates alone.
+fn same_shape(old: &MenuNodeKind, new: &MenuNodeKind) -> bool {
+    match (old, new) {
+        (&MenuNodeKind::Radio { group: old_group, index: old_index, .. },
+         &MenuNodeKind::Radio { group, index, .. }) => old_group == group && old_index == index,
+        (o, n) => std::mem::discriminant(o) == std::mem::discriminant(n)
+    }
+}
+
+/// Brings the entries of `parent` from `old` to `new` with as few backend
+/// calls as it can. Entries are told apart by id, as handed out by
+/// `assign_ids`.
+pub fn reconcile<B>(backend: &mut B, parent: Option<u32>, old: &[MenuNode], new: &[MenuNode]) -> Result<(), SystrayError>
+    where B: MenuBackend {
+    let kept: HashSet<u32> = new.iter().map(|n| n.id).collect();
+    // What the backend holds for parent, as operations go.
+    let mut current = vec![];
+    for o in old {
+        if kept.contains(&o.id) {
+            current.push(o);
+        } else {
+            backend.remove_menu_entry(o.id)?;
+        }
+    }
+    for (i, n) in new.iter().enumerate() {
+        match current.iter().position(|o| o.id == n.id) {
+            Some(j) if j == i && same_shape(&current[j].kind, &n.kind) => {
+                update_node(backend, current[j], n)?;
+            }
+            Some(j) => {
+                // Moved, or turned into a different kind of entry.
+                backend.remove_menu_entry(n.id)?;
+                current.remove(j);
+                backend.insert_menu_node(parent, i, n)?;
+                current.insert(i, n);
+            }
+            None => {
+                backend.insert_menu_node(parent, i, n)?;
+                current.insert(i, n);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn update_node<B>(backend: &mut B, old: &MenuNode, new: &MenuNode) -> Result<(), SystrayError>
+    where B: MenuBackend {
+    if old.label != new.label {
+        backend.set_menu_entry_label(new.id, &new.label)?;
+    }
+    if old.icon != new.icon {
+        backend.set_menu_entry_icon(new.id, new.icon.as_ref().map(|i| i.as_str()))?;
+    }
+    if old.enabled != new.enabled {
+        backend.set_menu_entry_enabled(new.id, new.enabled)?;
+    }
+    match (&old.kind, &new.kind) {
+        (&MenuNodeKind::Check { checked: was }, &MenuNodeKind::Check { checked }) if was != checked => {
+            backend.set_menu_entry_checked(new.id, checked)?;
+        }
+        (&MenuNodeKind::Radio { selected: false, .. }, &MenuNodeKind::Radio { group, index, selected: true }) => {
+            backend.set_radio_selected(group, index)?;
+        }
+        _ => ()
+    }
+    if old.visible != new.visible {
+        backend.set_menu_entry_visible(new.id, new.visible)?;
+    }
+    reconcile(backend, Some(new.id), &old.children, &new.children)
+}
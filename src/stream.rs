@@ -0,0 +1,102 @@
+// Async event handling, behind the `async` feature. Nothing here depends on a
+// particular runtime: the backend thread wakes the polling task through the
+// EventSender whenever it queues an event.
+
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::TryRecvError;
+use std::task::{Context, Poll};
+use {Application, SystrayEvent};
+
+/// Stream of events returned by `Application::event_stream`.
+///
+/// Like with `wait_for_message`, each event is dispatched to its menu
+/// callback before it is yielded. The stream ends once the backend shuts
+/// down.
+pub struct EventStream<'a> {
+    app: &'a mut Application,
+}
+
+impl<'a> EventStream<'a> {
+    fn try_next(&mut self) -> Poll<Option<SystrayEvent>> {
+        match self.app.rx.try_recv() {
+            Ok(msg) => {
+                self.app.dispatch(&msg);
+                Poll::Ready(Some(msg))
+            }
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(None)
+        }
+    }
+}
+
+impl<'a> Stream for EventStream<'a> {
+    type Item = SystrayEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<SystrayEvent>> {
+        let this = self.get_mut();
+        if let Poll::Ready(event) = this.try_next() {
+            return Poll::Ready(event);
+        }
+        // Look again after registering, so an event queued in between is not
+        // left waiting for the next one.
+        *this.app.waker.lock().unwrap() = Some(cx.waker().clone());
+        this.try_next()
+    }
+}
+
+/// Future returned by `Application::run`.
+pub struct Run<'a, F, Fut> {
+    events: EventStream<'a>,
+    handler: F,
+    pending: Option<Pin<Box<Fut>>>,
+}
+
+// Nothing is pinned in place: the handler's futures live in their own boxes.
+impl<'a, F, Fut> Unpin for Run<'a, F, Fut> {}
+
+impl<'a, F, Fut> Future for Run<'a, F, Fut>
+    where F: FnMut(SystrayEvent) -> Fut,
+          Fut: Future<Output = ()> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            if let Some(ref mut fut) = this.pending {
+                if let Poll::Pending = fut.as_mut().poll(cx) {
+                    return Poll::Pending;
+                }
+            }
+            this.pending = None;
+            match Pin::new(&mut this.events).poll_next(cx) {
+                Poll::Ready(Some(event)) => this.pending = Some(Box::pin((this.handler)(event))),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending
+            }
+        }
+    }
+}
+
+impl Application {
+    /// Events as an asynchronous stream, for use from any executor.
+    pub fn event_stream<'a>(&'a mut self) -> EventStream<'a> {
+        EventStream { app: self }
+    }
+
+    /// Runs the event loop as a future. Each event is dispatched to its menu
+    /// callback, then handed to `handler`, whose future is awaited before
+    /// the next event is looked at. This makes `async fn` handlers possible.
+    ///
+    /// Completes once the backend shuts down, e.g. after `quit`.
+    pub fn run<'a, F, Fut>(&'a mut self, handler: F) -> Run<'a, F, Fut>
+        where F: FnMut(SystrayEvent) -> Fut,
+              Fut: Future<Output = ()> {
+        Run {
+            events: self.event_stream(),
+            handler: handler,
+            pending: None,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// Tooltips with more than a single line of text, and tooltips computed
+// only when they are about to be shown.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tooltip shown when hovering the icon, modelled on the ToolTip property
+/// of StatusNotifierItem.
+///
+/// `body` may use the basic markup StatusNotifierItem hosts understand, like
+/// `<b>`, `<i>` and `<br/>`. Backends that only show plain text get the
+/// result of `to_plain_text` instead.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tooltip {
+    pub title: String,
+    pub body: String,
+    /// Icon name or file, for hosts that show one next to the text.
+    pub icon: Option<String>,
+}
+
+impl Tooltip {
+    pub fn new(title: &str) -> Tooltip {
+        Tooltip {
+            title: title.to_string(),
+            ..Tooltip::default()
+        }
+    }
+
+    pub fn body(mut self, body: &str) -> Tooltip {
+        self.body = body.to_string();
+        self
+    }
+
+    pub fn icon(mut self, icon: &str) -> Tooltip {
+        self.icon = Some(icon.to_string());
+        self
+    }
+
+    /// The title, and below it the body with its markup removed.
+    pub fn to_plain_text(&self) -> String {
+        let body = strip_markup(&self.body);
+        match (self.title.is_empty(), body.is_empty()) {
+            (_, true) => self.title.clone(),
+            (true, false) => body,
+            (false, false) => format!("{}\n{}", self.title, body)
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Tooltip {
+    fn from(title: &'a str) -> Tooltip {
+        Tooltip::new(title)
+    }
+}
+
+/// Computes the tooltip on demand, see `Application::set_tooltip_provider`.
+pub struct TooltipProvider {
+    ttl: Duration,
+    // The function, and the last tooltip it returned along with when.
+    state: Mutex<(Box<FnMut() -> Tooltip + Send>, Option<(Instant, Tooltip)>)>,
+}
+
+impl TooltipProvider {
+    pub fn new<F>(ttl: Duration, f: F) -> TooltipProvider
+        where F: FnMut() -> Tooltip + Send + 'static {
+        TooltipProvider {
+            ttl: ttl,
+            state: Mutex::new((Box::new(f), None)),
+        }
+    }
+
+    /// How long a computed tooltip is reused.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// The tooltip, computed anew only if the last one is older than the
+    /// ttl.
+    pub fn get(&self) -> Tooltip {
+        let mut state = self.state.lock().unwrap();
+        if let Some((at, ref tooltip)) = state.1 {
+            if at.elapsed() < self.ttl {
+                return tooltip.clone();
+            }
+        }
+        let tooltip = (state.0)();
+        state.1 = Some((Instant::now(), tooltip.clone()));
+        tooltip
+    }
+}
+
+// Drops tags, turning <br> into line breaks, and resolves the entities
+// that markup needs for literal text.
+fn strip_markup(markup: &str) -> String {
+    let mut text = String::new();
+    let mut rest = markup;
+    while let Some(i) = rest.find(|c| c == '<' || c == '&') {
+        text.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with('<') {
+            let end = rest.find('>').map(|e| e + 1).unwrap_or(rest.len());
+            let name = rest[1..end].trim_start_matches('/')
+                .split(|c: char| !c.is_alphanumeric())
+                .next()
+                .unwrap_or("");
+            if name.eq_ignore_ascii_case("br") {
+                text.push('\n');
+            }
+            rest = &rest[end..];
+            continue;
+        }
+        let entities = [("&amp;", '&'), ("&lt;", '<'), ("&gt;", '>'), ("&quot;", '"'), ("&apos;", '\'')];
+        match entities.iter().find(|&&(e, _)| rest.starts_with(e)) {
+            Some(&(e, c)) => {
+                text.push(c);
+                rest = &rest[e.len()..];
+            }
+            None => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
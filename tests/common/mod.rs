@@ -0,0 +1,97 @@
+// Helpers shared by the tests, most of which need a session bus of their
+// own. Not every test uses every helper.
+#![allow(dead_code)]
+
+#[cfg(target_os = "linux")]
+extern crate png;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{self, ChildStdin, Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+use systray::{MenuNode, MenuNodeKind};
+
+// One session bus for all tests, since libdbus reads its address only
+// once. The shell stops it as soon as its stdin closes along with the test
+// process, and leaves stdout to the daemon alone, so that reading the
+// address ends should the daemon fail to start. Holding the guard keeps
+// other tests off the names they take.
+static BUS: Mutex<Option<ChildStdin>> = Mutex::new(None);
+
+// A session bus without service directories, so nothing installed, like
+// xdg-desktop-portal, is started on it behind the tests' backs.
+const BUS_CONFIG: &'static str = "<busconfig>
+  <type>session</type>
+  <listen>unix:tmpdir=/tmp</listen>
+  <auth>EXTERNAL</auth>
+  <policy context=\"default\">
+    <allow send_destination=\"*\" eavesdrop=\"true\"/>
+    <allow eavesdrop=\"true\"/>
+    <allow own=\"*\"/>
+  </policy>
+</busconfig>
+";
+
+// Fails the test where there is no dbus-daemon to start, rather than
+// letting it pass without having run.
+pub fn private_bus() -> MutexGuard<'static, Option<ChildStdin>> {
+    let mut bus = BUS.lock().unwrap_or_else(|e| e.into_inner());
+    if bus.is_none() {
+        let config = env::temp_dir().join(format!("systray-test-bus-{}.conf", process::id()));
+        fs::write(&config, BUS_CONFIG).unwrap();
+        let mut shell = Command::new("sh")
+            .args(&["-c", "dbus-daemon --config-file=\"$1\" --nofork --print-address & exec >/dev/null; read _; kill $!",
+                    "sh", &config.to_string_lossy()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Could not start sh for dbus-daemon");
+        let mut address = String::new();
+        BufReader::new(shell.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        fs::remove_file(&config).ok();
+        assert!(!address.trim().is_empty(), "These tests need dbus-daemon to start a session bus");
+        env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+        *bus = shell.stdin.take();
+    }
+    bus
+}
+
+// An enabled, visible menu entry without icon or children.
+pub fn node(id: u32, kind: MenuNodeKind, label: &str) -> MenuNode {
+    MenuNode {
+        id: id,
+        key: None,
+        kind: kind,
+        label: label.to_string(),
+        icon: None,
+        enabled: true,
+        visible: true,
+        children: vec![],
+    }
+}
+
+// Encodes 8 bit pixels as a PNG, RGB or RGBA depending on how many bytes
+// there are per pixel.
+#[cfg(target_os = "linux")]
+pub fn encode_png(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    let color = if pixels.len() == (width * height * 3) as usize {
+        png::ColorType::RGB
+    } else {
+        png::ColorType::RGBA
+    };
+    let mut data = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header().unwrap().write_image_data(pixels).unwrap();
+    }
+    data
+}
+
+#[cfg(target_os = "linux")]
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) {
+    fs::write(path, encode_png(width, height, pixels)).unwrap();
+}
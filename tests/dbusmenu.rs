@@ -0,0 +1,428 @@
+#![cfg(all(target_os = "linux", feature = "dbus"))]
+
+extern crate dbus;
+extern crate systray;
+
+mod common;
+
+use common::{node, private_bus, write_png};
+use dbus::arg::{prop_cast, PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, MessageType};
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use systray::dbusmenu::DbusMenu;
+use systray::reconcile::MenuBackend;
+use systray::{MenuNode, MenuNodeKind, SystrayEvent};
+
+const DBUSMENU: &'static str = "com.canonical.dbusmenu";
+const MENU_PATH: &'static str = "/MenuBar";
+
+type Child = (i32, PropMap, Vec<Variant<Box<RefArg>>>);
+type Layout = (i32, PropMap, Vec<Variant<Child>>);
+
+// Signals as a host sees them: LayoutUpdated with revision and parent, or
+// ItemsPropertiesUpdated with the ids of the items updated and the names of
+// the properties removed from them, sorted.
+#[derive(Debug, PartialEq)]
+enum Update {
+    Layout(u32, i32),
+    Properties(Vec<i32>, Vec<(i32, Vec<String>)>),
+}
+
+// Serves a menu from a thread of its own, the way a backend would.
+struct Exporter {
+    menu: Arc<Mutex<DbusMenu>>,
+    events: Receiver<SystrayEvent>,
+    name: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Exporter {
+    fn start(menu: &[MenuNode]) -> Exporter {
+        let mut exported = DbusMenu::new(MENU_PATH);
+        exported.set_menu(menu).unwrap();
+        exported.take_signals();
+        let menu = Arc::new(Mutex::new(exported));
+        let (event_tx, event_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_menu = menu.clone();
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let conn = Connection::new_session().unwrap();
+            let call_menu = thread_menu.clone();
+            conn.start_receive(MatchRule::new_method_call().with_path(MENU_PATH), Box::new(move |msg, conn| {
+                let (reply, events) = call_menu.lock().unwrap().handle_call(&msg);
+                for event in events {
+                    event_tx.send(event).ok();
+                }
+                conn.send(reply).ok();
+                true
+            }));
+            ready_tx.send(conn.unique_name().to_string()).unwrap();
+            while !thread_stop.load(Ordering::SeqCst) {
+                conn.process(Duration::from_millis(20)).unwrap();
+                for signal in thread_menu.lock().unwrap().take_signals() {
+                    conn.send(signal).ok();
+                }
+            }
+        });
+        Exporter {
+            menu: menu,
+            events: event_rx,
+            name: ready_rx.recv().unwrap(),
+            stop: stop,
+            thread: Some(thread),
+        }
+    }
+
+    fn change<F>(&self, f: F)
+        where F: FnOnce(&mut DbusMenu) {
+        f(&mut self.menu.lock().unwrap())
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            t.join().ok();
+        }
+    }
+}
+
+// Stands in for the host reading the menu.
+struct Host {
+    conn: Connection,
+    name: String,
+    updates: Receiver<Update>,
+}
+
+impl Host {
+    fn new(name: &str) -> Host {
+        let conn = Connection::new_session().unwrap();
+        let (update_tx, update_rx) = channel();
+        let rule = MatchRule::new().with_type(MessageType::Signal).with_interface(DBUSMENU);
+        conn.add_match_no_cb(&rule.match_str()).unwrap();
+        conn.start_receive(rule, Box::new(move |msg, _| {
+            let update = match msg.member().as_ref().map(|m| &**m) {
+                Some("LayoutUpdated") => msg.read2().ok().map(|(revision, parent)| Update::Layout(revision, parent)),
+                Some("ItemsPropertiesUpdated") => msg.read2::<Vec<(i32, PropMap)>, Vec<(i32, Vec<String>)>>().ok()
+                    .map(|(updated, mut removed)| {
+                        for &mut (_, ref mut names) in &mut removed {
+                            names.sort();
+                        }
+                        Update::Properties(updated.into_iter().map(|(id, _)| id).collect(), removed)
+                    }),
+                _ => None
+            };
+            if let Some(update) = update {
+                update_tx.send(update).ok();
+            }
+            true
+        }));
+        Host {
+            conn: conn,
+            name: name.to_string(),
+            updates: update_rx,
+        }
+    }
+
+    fn call<A, R>(&self, method: &str, args: A) -> Result<R, dbus::Error>
+        where A: dbus::arg::AppendAll, R: dbus::arg::ReadAll {
+        self.conn.with_proxy(&*self.name, MENU_PATH, Duration::from_secs(5)).method_call(DBUSMENU, method, args)
+    }
+
+    fn layout(&self, parent: i32, depth: i32, names: &[&str]) -> (u32, Layout) {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        self.call("GetLayout", (parent, depth, names)).unwrap()
+    }
+
+    fn click(&self, id: i32) {
+        let _: () = self.call("Event", (id, "clicked", Variant(0i32), 0u32)).unwrap();
+    }
+
+    fn next_update(&self) -> Update {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Ok(update) = self.updates.try_recv() {
+                return update;
+            }
+            assert!(Instant::now() < deadline, "no update from the menu");
+            self.conn.process(Duration::from_millis(50)).unwrap();
+        }
+    }
+}
+
+fn sample_menu() -> Vec<MenuNode> {
+    let mut disabled = node(5, MenuNodeKind::Item, "Disabled");
+    disabled.enabled = false;
+    let mut submenu = node(6, MenuNodeKind::Submenu, "More");
+    submenu.children.push(node(7, MenuNodeKind::Item, "Nested"));
+    vec![
+        node(0, MenuNodeKind::Item, "Open_file"),
+        node(1, MenuNodeKind::Separator, ""),
+        node(2, MenuNodeKind::Check { checked: false }, "Mute"),
+        node(3, MenuNodeKind::Radio { group: 10, index: 0, selected: true }, "Low"),
+        node(4, MenuNodeKind::Radio { group: 10, index: 1, selected: false }, "High"),
+        disabled,
+        submenu,
+    ]
+}
+
+fn label(props: &PropMap) -> Option<&str> {
+    prop_cast::<String>(props, "label").map(|l| &**l)
+}
+
+fn toggle_state(props: &PropMap) -> Option<i32> {
+    prop_cast::<i32>(props, "toggle-state").cloned()
+}
+
+fn properties(host: &Host, id: i32) -> PropMap {
+    let (props,): (Vec<(i32, PropMap)>,) = host.call("GetGroupProperties", (vec![id], Vec::<String>::new())).unwrap();
+    props.into_iter().next().expect("no properties").1
+}
+
+fn next_event(exporter: &Exporter) -> SystrayEvent {
+    exporter.events.recv_timeout(Duration::from_secs(5)).expect("no event")
+}
+
+#[test]
+fn layout_follows_the_menu() {
+    let _bus = private_bus();
+    let exporter = Exporter::start(&sample_menu());
+    let host = Host::new(&exporter.name);
+
+    let (revision, (id, props, children)) = host.layout(0, -1, &[]);
+    assert_eq!(revision, exporter.menu.lock().unwrap().revision());
+    assert_eq!(id, 0);
+    assert_eq!(prop_cast::<String>(&props, "children-display").map(|s| &**s), Some("submenu"));
+    let children: Vec<Child> = children.into_iter().map(|c| c.0).collect();
+    let ids: Vec<i32> = children.iter().map(|c| c.0).collect();
+    assert_eq!(ids, vec![1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(label(&children[0].1), Some("Open__file"));
+    assert_eq!(prop_cast::<String>(&children[1].1, "type").map(|s| &**s), Some("separator"));
+    assert_eq!(prop_cast::<String>(&children[2].1, "toggle-type").map(|s| &**s), Some("checkmark"));
+    assert_eq!(toggle_state(&children[2].1), Some(0));
+    assert_eq!(prop_cast::<String>(&children[3].1, "toggle-type").map(|s| &**s), Some("radio"));
+    assert_eq!(toggle_state(&children[3].1), Some(1));
+    assert_eq!(toggle_state(&children[4].1), Some(0));
+    assert_eq!(prop_cast::<bool>(&children[5].1, "enabled"), Some(&false));
+    assert_eq!(prop_cast::<String>(&children[6].1, "children-display").map(|s| &**s), Some("submenu"));
+    assert_eq!(children[6].2.len(), 1);
+
+    let (_, (id, _, nested)) = host.layout(7, -1, &[]);
+    assert_eq!(id, 7);
+    assert_eq!(nested.len(), 1);
+    assert_eq!(label(&nested[0].0 .1), Some("Nested"));
+
+    // Depth 0 leaves out the children, and property names pick properties.
+    let (_, (_, _, children)) = host.layout(0, 0, &[]);
+    assert!(children.is_empty());
+    let (_, (_, _, children)) = host.layout(0, 1, &["label"]);
+    assert_eq!(children.len(), 7);
+    assert!(children[6].0 .2.is_empty());
+    assert_eq!(children[0].0 .1.keys().collect::<Vec<_>>(), vec!["label"]);
+
+    let (props,): (Vec<(i32, PropMap)>,) = host.call("GetGroupProperties", (vec![3, 8, 42], vec!["label".to_string()]))
+        .unwrap();
+    let labels: Vec<(i32, Option<&str>)> = props.iter().map(|&(id, ref p)| (id, label(p))).collect();
+    assert_eq!(labels, vec![(3, Some("Mute")), (8, Some("Nested"))]);
+
+    let (value,): (Variant<String>,) = host.call("GetProperty", (5, "label")).unwrap();
+    assert_eq!(value.0, "High");
+    assert!(host.call::<_, (u32, Layout)>("GetLayout", (42, -1, Vec::<String>::new())).is_err());
+
+    let proxy = host.conn.with_proxy(&*exporter.name, MENU_PATH, Duration::from_secs(5));
+    assert_eq!(proxy.get::<u32>(DBUSMENU, "Version").unwrap(), 3);
+    assert_eq!(proxy.get::<String>(DBUSMENU, "Status").unwrap(), "normal");
+}
+
+#[test]
+fn changes_bump_the_revision_and_are_signalled() {
+    let _bus = private_bus();
+    let exporter = Exporter::start(&sample_menu());
+    let host = Host::new(&exporter.name);
+    let (first, _) = host.layout(0, -1, &[]);
+
+    exporter.change(|menu| menu.insert_menu_node(None, 1, &node(8, MenuNodeKind::Item, "Added")).unwrap());
+    let second = match host.next_update() {
+        Update::Layout(revision, 0) => revision,
+        u => panic!("unexpected {:?}", u)
+    };
+    assert!(second > first);
+    let (revision, (_, _, children)) = host.layout(0, 1, &["label"]);
+    assert_eq!(revision, second);
+    assert_eq!(label(&children[1].0 .1), Some("Added"));
+
+    // Changes inside a submenu name the submenu as the parent.
+    exporter.change(|menu| menu.remove_menu_entry(7).unwrap());
+    match host.next_update() {
+        Update::Layout(revision, 7) => assert!(revision > second),
+        u => panic!("unexpected {:?}", u)
+    }
+    let (_, (_, _, nested)) = host.layout(7, -1, &[]);
+    assert!(nested.is_empty());
+
+    // Properties change without a new revision.
+    let revision = exporter.menu.lock().unwrap().revision();
+    exporter.change(|menu| {
+        menu.set_menu_entry_label(0, "Open").unwrap();
+        menu.set_menu_entry_enabled(0, false).unwrap();
+    });
+    assert_eq!(host.next_update(), Update::Properties(vec![1], vec![]));
+    assert_eq!(exporter.menu.lock().unwrap().revision(), revision);
+    let props = properties(&host, 1);
+    assert_eq!(label(&props), Some("Open"));
+    assert_eq!(prop_cast::<bool>(&props, "enabled"), Some(&false));
+
+    exporter.change(|menu| menu.set_radio_selected(10, 1).unwrap());
+    assert_eq!(host.next_update(), Update::Properties(vec![4, 5], vec![]));
+    assert_eq!(toggle_state(&properties(&host, 4)), Some(0));
+    assert_eq!(toggle_state(&properties(&host, 5)), Some(1));
+
+    // Replacing the menu lays it out again from the top.
+    exporter.change(|menu| menu.set_menu(&[node(0, MenuNodeKind::Item, "Only")]).unwrap());
+    match host.next_update() {
+        Update::Layout(_, 0) => (),
+        u => panic!("unexpected {:?}", u)
+    }
+    let (_, (_, _, children)) = host.layout(0, -1, &[]);
+    assert_eq!(children.len(), 1);
+}
+
+#[test]
+fn clicks_toggle_entries_and_become_events() {
+    let _bus = private_bus();
+    let exporter = Exporter::start(&sample_menu());
+    let host = Host::new(&exporter.name);
+
+    host.click(1);
+    match next_event(&exporter) {
+        SystrayEvent::MenuItemActivated { id: 0, checked: None, selected: None, .. } => (),
+        e => panic!("unexpected {:?}", e)
+    }
+
+    host.click(3);
+    match next_event(&exporter) {
+        SystrayEvent::MenuItemActivated { id: 2, checked: Some(true), .. } => (),
+        e => panic!("unexpected {:?}", e)
+    }
+    assert_eq!(host.next_update(), Update::Properties(vec![3], vec![]));
+    assert_eq!(toggle_state(&properties(&host, 3)), Some(1));
+
+    // Radio entries report their group and the member chosen.
+    host.click(5);
+    match next_event(&exporter) {
+        SystrayEvent::MenuItemActivated { id: 10, selected: Some(1), .. } => (),
+        e => panic!("unexpected {:?}", e)
+    }
+    assert_eq!(toggle_state(&properties(&host, 4)), Some(0));
+    assert_eq!(toggle_state(&properties(&host, 5)), Some(1));
+    assert_eq!(exporter.menu.lock().unwrap().menu()[4].kind,
+               MenuNodeKind::Radio { group: 10, index: 1, selected: true });
+
+    // Disabled entries, separators and submenus do nothing.
+    host.click(6);
+    host.click(2);
+    host.click(7);
+    assert!(exporter.events.try_recv().is_err());
+
+    let _: () = host.call("Event", (0, "opened", Variant(0i32), 0u32)).unwrap();
+    match next_event(&exporter) {
+        SystrayEvent::MenuOpened { .. } => (),
+        e => panic!("unexpected {:?}", e)
+    }
+    let (errors,): (Vec<i32>,) = host.call("EventGroup", (vec![(0, "closed", Variant(0i32), 0u32),
+                                                               (42, "clicked", Variant(0i32), 0u32)],))
+        .unwrap();
+    assert_eq!(errors, vec![42]);
+    match next_event(&exporter) {
+        SystrayEvent::MenuClosed { .. } => (),
+        e => panic!("unexpected {:?}", e)
+    }
+    assert!(host.call::<_, ()>("Event", (42, "clicked", Variant(0i32), 0u32)).is_err());
+
+    let (need_update,): (bool,) = host.call("AboutToShow", (7,)).unwrap();
+    assert!(!need_update);
+    let (updates, errors): (Vec<i32>, Vec<i32>) = host.call("AboutToShowGroup", (vec![0, 7, 42],)).unwrap();
+    assert!(updates.is_empty());
+    assert_eq!(errors, vec![42]);
+}
+
+#[test]
+fn icons_are_sent_as_data_or_by_name() {
+    let _bus = private_bus();
+    let dir = env::temp_dir().join(format!("systray-dbusmenu-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let png_file = dir.join("icon.png");
+    write_png(&png_file, 1, 1, &[255, 0, 0, 255]);
+    let svg_file = dir.join("icon.svg");
+    fs::write(&svg_file, "<svg xmlns=\"http://www.w3.org/2000/svg\"/>").unwrap();
+
+    let mut item = node(0, MenuNodeKind::Item, "Save");
+    item.icon = Some(png_file.to_string_lossy().into_owned());
+    let exporter = Exporter::start(&[item]);
+    let host = Host::new(&exporter.name);
+    let props = properties(&host, 1);
+    assert_eq!(prop_cast::<Vec<u8>>(&props, "icon-data"), Some(&fs::read(&png_file).unwrap()));
+
+    let svg = svg_file.to_string_lossy().into_owned();
+    exporter.change(|menu| menu.set_menu_entry_icon(0, Some(&svg)).unwrap());
+    assert_eq!(host.next_update(), Update::Properties(vec![1], vec![(1, vec!["icon-data".to_string()])]));
+    let props = properties(&host, 1);
+    assert!(prop_cast::<Vec<u8>>(&props, "icon-data").is_none());
+    assert_eq!(prop_cast::<String>(&props, "icon-name"), Some(&svg));
+
+    let missing = dir.join("missing.png").to_string_lossy().into_owned();
+    assert!(exporter.menu.lock().unwrap().set_menu_entry_icon(0, Some(&missing)).is_err());
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn removed_properties_are_signalled() {
+    let _bus = private_bus();
+    let dir = env::temp_dir().join(format!("systray-dbusmenu-removed-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let png_file = dir.join("icon.png");
+    write_png(&png_file, 1, 1, &[0, 0, 255, 255]);
+    let png = png_file.to_string_lossy().into_owned();
+    let svg_file = dir.join("icon.svg");
+    fs::write(&svg_file, "<svg xmlns=\"http://www.w3.org/2000/svg\"/>").unwrap();
+    let svg = svg_file.to_string_lossy().into_owned();
+
+    let mut item = node(0, MenuNodeKind::Item, "Save");
+    item.icon = Some(png.clone());
+    let exporter = Exporter::start(&[item, node(1, MenuNodeKind::Item, "Quit")]);
+    let host = Host::new(&exporter.name);
+    assert!(properties(&host, 1).contains_key("icon-data"));
+
+    exporter.change(|menu| menu.set_menu_entry_icon(0, None).unwrap());
+    assert_eq!(host.next_update(), Update::Properties(vec![1], vec![(1, vec!["icon-data".to_string()])]));
+    let props = properties(&host, 1);
+    assert!(!props.contains_key("icon-data"));
+    assert!(!props.contains_key("icon-name"));
+
+    // Only what the item had before the first change since the last signal
+    // counts, not what it had in between.
+    exporter.change(|menu| {
+        menu.set_menu_entry_icon(1, Some(&svg)).unwrap();
+        menu.set_menu_entry_icon(1, None).unwrap();
+        menu.set_menu_entry_icon(0, Some(&svg)).unwrap();
+        menu.set_menu_entry_label(0, "Save all").unwrap();
+    });
+    assert_eq!(host.next_update(), Update::Properties(vec![2, 1], vec![]));
+
+    exporter.change(|menu| menu.set_menu_entry_icon(0, Some(&png)).unwrap());
+    assert_eq!(host.next_update(), Update::Properties(vec![1], vec![(1, vec!["icon-name".to_string()])]));
+    fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,221 @@
+extern crate systray;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use systray::icon_theme::IconTheme;
+
+// A directory of icon themes, removed again when dropped. Theme and icon
+// names used in it are made up, so nothing installed on the system
+// matches them.
+struct Themes {
+    dir: PathBuf,
+}
+
+impl Themes {
+    fn new(test: &str) -> Themes {
+        let dir = env::temp_dir().join(format!("systray-icon-theme-{}-{}", process::id(), test));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        Themes { dir: dir }
+    }
+
+    fn index(&self, theme: &str, index: &str) {
+        fs::create_dir_all(self.dir.join(theme)).unwrap();
+        fs::write(self.dir.join(theme).join("index.theme"), index).unwrap();
+    }
+
+    fn icon(&self, path: &str) -> PathBuf {
+        let file = self.dir.join(path);
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, path).unwrap();
+        file
+    }
+
+    fn theme(&self, name: &str) -> IconTheme {
+        IconTheme::new(name).prepend_dir(&self.dir)
+    }
+}
+
+impl Drop for Themes {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+const FIXED: &'static str = "[Icon Theme]
+Name=Fixed
+Directories=16x16/apps,48x48/apps,64x64/apps
+
+[16x16/apps]
+Size=16
+Type=Fixed
+
+[48x48/apps]
+Size=48
+Type=Fixed
+
+[64x64/apps]
+Size=64
+Type=Fixed
+";
+
+#[test]
+fn fixed_directories_match_their_size_only() {
+    let themes = Themes::new("fixed");
+    themes.index("systray-test-fixed", FIXED);
+    let small = themes.icon("systray-test-fixed/16x16/apps/systray-test-icon.png");
+    let large = themes.icon("systray-test-fixed/48x48/apps/systray-test-icon.png");
+    let huge = themes.icon("systray-test-fixed/64x64/apps/systray-test-icon.png");
+    let theme = themes.theme("systray-test-fixed");
+    assert_eq!(theme.lookup("systray-test-icon", 16, 1), Some(small));
+    assert_eq!(theme.lookup("systray-test-icon", 48, 1), Some(large.clone()));
+    assert_eq!(theme.lookup("systray-test-icon", 64, 1), Some(huge.clone()));
+    // Closest otherwise, on either side.
+    assert_eq!(theme.lookup("systray-test-icon", 44, 1), Some(large));
+    assert_eq!(theme.lookup("systray-test-icon", 128, 1), Some(huge));
+}
+
+#[test]
+fn threshold_directories_match_around_their_size() {
+    let themes = Themes::new("threshold");
+    themes.index("systray-test-threshold", "[Icon Theme]
+Directories=24x24/apps,32x32/apps
+
+[24x24/apps]
+Size=24
+
+[32x32/apps]
+Size=32
+Type=Threshold
+Threshold=6
+");
+    let small = themes.icon("systray-test-threshold/24x24/apps/systray-test-icon.png");
+    let large = themes.icon("systray-test-threshold/32x32/apps/systray-test-icon.png");
+    let theme = themes.theme("systray-test-threshold");
+    // The default threshold is 2.
+    assert_eq!(theme.lookup("systray-test-icon", 22, 1), Some(small.clone()));
+    assert_eq!(theme.lookup("systray-test-icon", 26, 1), Some(small));
+    assert_eq!(theme.lookup("systray-test-icon", 27, 1), Some(large.clone()));
+    assert_eq!(theme.lookup("systray-test-icon", 38, 1), Some(large.clone()));
+    assert_eq!(theme.lookup("systray-test-icon", 40, 1), Some(large));
+}
+
+#[test]
+fn scalable_directories_match_their_range() {
+    let themes = Themes::new("scalable");
+    themes.index("systray-test-scalable", "[Icon Theme]
+Directories=16x16/apps,scalable/apps
+
+[16x16/apps]
+Size=16
+Type=Fixed
+
+[scalable/apps]
+Size=64
+MinSize=24
+MaxSize=256
+Type=Scalable
+");
+    let fixed = themes.icon("systray-test-scalable/16x16/apps/systray-test-icon.png");
+    let svg = themes.icon("systray-test-scalable/scalable/apps/systray-test-icon.svg");
+    let theme = themes.theme("systray-test-scalable");
+    assert_eq!(theme.lookup("systray-test-icon", 16, 1), Some(fixed.clone()));
+    assert_eq!(theme.lookup("systray-test-icon", 24, 1), Some(svg.clone()));
+    assert_eq!(theme.lookup("systray-test-icon", 200, 1), Some(svg.clone()));
+    assert_eq!(theme.lookup("systray-test-icon", 512, 1), Some(svg));
+    assert_eq!(theme.lookup("systray-test-icon", 18, 1), Some(fixed));
+}
+
+#[test]
+fn scaled_directories_are_used_for_their_scale() {
+    let themes = Themes::new("scaled");
+    themes.index("systray-test-scaled", "[Icon Theme]
+Directories=24x24/apps
+ScaledDirectories=24x24@2/apps
+
+[24x24/apps]
+Size=24
+Type=Fixed
+
+[24x24@2/apps]
+Size=24
+Scale=2
+Type=Fixed
+");
+    let normal = themes.icon("systray-test-scaled/24x24/apps/systray-test-icon.png");
+    let double = themes.icon("systray-test-scaled/24x24@2/apps/systray-test-icon.png");
+    let theme = themes.theme("systray-test-scaled");
+    assert_eq!(theme.lookup("systray-test-icon", 24, 1), Some(normal));
+    assert_eq!(theme.lookup("systray-test-icon", 24, 2), Some(double));
+}
+
+#[test]
+fn inherited_themes_are_searched_in_order() {
+    let themes = Themes::new("inherits");
+    themes.index("systray-test-child", "[Icon Theme]
+Inherits=systray-test-parent,systray-test-other
+Directories=48x48/apps
+
+[48x48/apps]
+Size=48
+Type=Fixed
+");
+    themes.index("systray-test-parent", FIXED);
+    themes.index("systray-test-other", FIXED);
+    let own = themes.icon("systray-test-child/48x48/apps/systray-test-own.png");
+    themes.icon("systray-test-parent/48x48/apps/systray-test-own.png");
+    let parent = themes.icon("systray-test-parent/48x48/apps/systray-test-shared.png");
+    themes.icon("systray-test-other/48x48/apps/systray-test-shared.png");
+    let other = themes.icon("systray-test-other/16x16/apps/systray-test-last.png");
+    let theme = themes.theme("systray-test-child");
+    assert_eq!(theme.lookup("systray-test-own", 48, 1), Some(own));
+    assert_eq!(theme.lookup("systray-test-shared", 48, 1), Some(parent));
+    // A theme's closest size wins over an exact one further down.
+    assert_eq!(theme.lookup("systray-test-last", 48, 1), Some(other));
+}
+
+#[test]
+fn inheritance_cycles_end() {
+    let themes = Themes::new("cycle");
+    themes.index("systray-test-a", "[Icon Theme]\nInherits=systray-test-b\nDirectories=\n");
+    themes.index("systray-test-b", "[Icon Theme]\nInherits=systray-test-a\nDirectories=\n");
+    assert_eq!(themes.theme("systray-test-a").lookup("systray-test-missing", 48, 1), None);
+}
+
+#[test]
+fn hicolor_is_the_fallback_theme() {
+    let themes = Themes::new("hicolor");
+    themes.index("systray-test-plain", FIXED);
+    themes.index("hicolor", FIXED);
+    let file = themes.icon("hicolor/48x48/apps/systray-test-hicolor.png");
+    assert_eq!(themes.theme("systray-test-plain").lookup("systray-test-hicolor", 48, 1), Some(file.clone()));
+    // Also when the theme itself does not exist.
+    assert_eq!(themes.theme("systray-test-nonexistent").lookup("systray-test-hicolor", 48, 1), Some(file));
+}
+
+#[test]
+fn unthemed_icons_come_last() {
+    let themes = Themes::new("unthemed");
+    themes.index("systray-test-plain", FIXED);
+    let unthemed = themes.icon("systray-test-unthemed.png");
+    let theme = themes.theme("systray-test-plain");
+    assert_eq!(theme.lookup("systray-test-unthemed", 48, 1), Some(unthemed));
+    let themed = themes.icon("systray-test-plain/16x16/apps/systray-test-unthemed.png");
+    assert_eq!(theme.lookup("systray-test-unthemed", 48, 1), Some(themed));
+    assert_eq!(theme.lookup("systray-test-missing", 48, 1), None);
+}
+
+#[test]
+fn extensions_are_tried_in_order() {
+    let themes = Themes::new("extensions");
+    themes.index("systray-test-ext", FIXED);
+    let png = themes.icon("systray-test-ext/48x48/apps/systray-test-icon.png");
+    let svg = themes.icon("systray-test-ext/48x48/apps/systray-test-icon.svg");
+    themes.icon("systray-test-ext/48x48/apps/systray-test-bitmap.bmp");
+    assert_eq!(themes.theme("systray-test-ext").lookup("systray-test-icon", 48, 1), Some(png));
+    let svg_first = themes.theme("systray-test-ext").extensions(&["svg", "png"]);
+    assert_eq!(svg_first.lookup("systray-test-icon", 48, 1), Some(svg));
+    assert_eq!(svg_first.lookup("systray-test-bitmap", 48, 1), None);
+}
@@ -0,0 +1,81 @@
+#![cfg(all(target_os = "linux", feature = "dbus"))]
+
+extern crate dbus;
+extern crate systray;
+
+mod common;
+
+use common::private_bus;
+use dbus::blocking::Connection;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+use systray::ColorScheme;
+use systray::portal::{read_color_scheme, ColorSchemeWatch, StandInPortal};
+
+fn watch() -> (ColorSchemeWatch, Receiver<Option<ColorScheme>>) {
+    let (tx, rx) = channel();
+    let watch = ColorSchemeWatch::new(move |scheme| {
+        tx.send(scheme).ok();
+    });
+    (watch, rx)
+}
+
+fn next(rx: &Receiver<Option<ColorScheme>>) -> Option<ColorScheme> {
+    rx.recv_timeout(Duration::from_secs(5)).expect("no color scheme reported")
+}
+
+#[test]
+fn no_portal_means_no_preference() {
+    let _bus = private_bus();
+    let conn = Connection::new_session().unwrap();
+    assert_eq!(read_color_scheme(&conn), None);
+}
+
+#[test]
+fn reads_color_scheme_from_portal() {
+    let _bus = private_bus();
+    let conn = Connection::new_session().unwrap();
+    let portal = StandInPortal::start(Some(ColorScheme::Dark)).unwrap();
+    assert_eq!(read_color_scheme(&conn), Some(ColorScheme::Dark));
+    portal.set_color_scheme(Some(ColorScheme::Light));
+    // Taken up by the portal once it processes the change, so keep asking.
+    let mut scheme = None;
+    for _ in 0..50 {
+        scheme = read_color_scheme(&conn);
+        if scheme == Some(ColorScheme::Light) {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(scheme, Some(ColorScheme::Light));
+    portal.set_color_scheme(None);
+}
+
+#[test]
+fn watch_reports_current_scheme_then_changes() {
+    let _bus = private_bus();
+    let portal = StandInPortal::start(Some(ColorScheme::Light)).unwrap();
+    let (_watch, rx) = watch();
+    assert_eq!(next(&rx), Some(ColorScheme::Light));
+    portal.set_color_scheme(Some(ColorScheme::Dark));
+    assert_eq!(next(&rx), Some(ColorScheme::Dark));
+    portal.set_color_scheme(None);
+    assert_eq!(next(&rx), None);
+}
+
+#[test]
+fn watch_without_portal_reports_no_preference() {
+    let _bus = private_bus();
+    let (_watch, rx) = watch();
+    assert_eq!(next(&rx), None);
+}
+
+#[test]
+fn watch_picks_up_portal_started_later() {
+    let _bus = private_bus();
+    let (_watch, rx) = watch();
+    assert_eq!(next(&rx), None);
+    let portal = StandInPortal::start(None).unwrap();
+    portal.set_color_scheme(Some(ColorScheme::Dark));
+    assert_eq!(next(&rx), Some(ColorScheme::Dark));
+}
@@ -0,0 +1,290 @@
+extern crate systray;
+
+mod common;
+
+use common::node;
+use systray::{MenuNode, MenuNodeKind, SystrayError};
+use systray::reconcile::{assign_ids, reconcile, MenuBackend};
+
+#[derive(Debug, PartialEq)]
+enum Op {
+    Insert(Option<u32>, usize, u32),
+    Remove(u32),
+    Label(u32, String),
+    Icon(u32, Option<String>),
+    Enabled(u32, bool),
+    Visible(u32, bool),
+    Checked(u32, bool),
+    RadioSelected(u32, usize),
+}
+
+// Records every call and keeps a menu of its own up to date with them, so
+// tests can check both what was done and where it led.
+struct FakeBackend {
+    ops: Vec<Op>,
+    menu: Vec<MenuNode>,
+}
+
+fn find(nodes: &mut Vec<MenuNode>, id: u32) -> Option<&mut MenuNode> {
+    for node in nodes.iter_mut() {
+        if node.id == id {
+            return Some(node);
+        }
+        if let Some(found) = find(&mut node.children, id) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+fn remove(nodes: &mut Vec<MenuNode>, id: u32) -> bool {
+    if let Some(i) = nodes.iter().position(|n| n.id == id) {
+        nodes.remove(i);
+        return true;
+    }
+    nodes.iter_mut().any(|n| remove(&mut n.children, id))
+}
+
+fn select(nodes: &mut Vec<MenuNode>, group_id: u32, member: usize) {
+    for node in nodes.iter_mut() {
+        if let MenuNodeKind::Radio { group, index, ref mut selected } = node.kind {
+            if group == group_id {
+                *selected = index == member;
+            }
+        }
+        select(&mut node.children, group_id, member);
+    }
+}
+
+impl FakeBackend {
+    fn new(menu: &[MenuNode]) -> FakeBackend {
+        FakeBackend { ops: vec![], menu: menu.to_vec() }
+    }
+
+    fn entry(&mut self, id: u32) -> Result<&mut MenuNode, SystrayError> {
+        find(&mut self.menu, id).ok_or(SystrayError::OsError(format!("No entry {}", id)))
+    }
+}
+
+impl MenuBackend for FakeBackend {
+    fn insert_menu_node(&mut self, parent: Option<u32>, position: usize, node: &MenuNode) -> Result<(), SystrayError> {
+        self.ops.push(Op::Insert(parent, position, node.id));
+        let siblings = match parent {
+            None => &mut self.menu,
+            Some(p) => &mut self.entry(p)?.children
+        };
+        if position > siblings.len() {
+            return Err(SystrayError::OsError(format!("Bad position {}", position)));
+        }
+        siblings.insert(position, node.clone());
+        Ok(())
+    }
+
+    fn remove_menu_entry(&mut self, id: u32) -> Result<(), SystrayError> {
+        self.ops.push(Op::Remove(id));
+        if !remove(&mut self.menu, id) {
+            return Err(SystrayError::OsError(format!("No entry {}", id)));
+        }
+        Ok(())
+    }
+
+    fn set_menu_entry_label(&mut self, id: u32, label: &str) -> Result<(), SystrayError> {
+        self.ops.push(Op::Label(id, label.to_string()));
+        self.entry(id)?.label = label.to_string();
+        Ok(())
+    }
+
+    fn set_menu_entry_icon(&mut self, id: u32, icon: Option<&str>) -> Result<(), SystrayError> {
+        let icon = icon.map(|i| i.to_string());
+        self.ops.push(Op::Icon(id, icon.clone()));
+        self.entry(id)?.icon = icon;
+        Ok(())
+    }
+
+    fn set_menu_entry_enabled(&mut self, id: u32, enabled: bool) -> Result<(), SystrayError> {
+        self.ops.push(Op::Enabled(id, enabled));
+        self.entry(id)?.enabled = enabled;
+        Ok(())
+    }
+
+    fn set_menu_entry_visible(&mut self, id: u32, visible: bool) -> Result<(), SystrayError> {
+        self.ops.push(Op::Visible(id, visible));
+        self.entry(id)?.visible = visible;
+        Ok(())
+    }
+
+    fn set_menu_entry_checked(&mut self, id: u32, checked: bool) -> Result<(), SystrayError> {
+        self.ops.push(Op::Checked(id, checked));
+        self.entry(id)?.kind = MenuNodeKind::Check { checked: checked };
+        Ok(())
+    }
+
+    fn set_radio_selected(&mut self, group: u32, index: usize) -> Result<(), SystrayError> {
+        self.ops.push(Op::RadioSelected(group, index));
+        select(&mut self.menu, group, index);
+        Ok(())
+    }
+}
+
+fn item(id: u32, label: &str) -> MenuNode {
+    node(id, MenuNodeKind::Item, label)
+}
+
+fn keyed(id: u32, key: &str, label: &str) -> MenuNode {
+    let mut n = item(id, label);
+    n.key = Some(key.to_string());
+    n
+}
+
+fn check(id: u32, label: &str, checked: bool) -> MenuNode {
+    node(id, MenuNodeKind::Check { checked: checked }, label)
+}
+
+fn radio(id: u32, group: u32, index: usize, selected: usize, label: &str) -> MenuNode {
+    node(id, MenuNodeKind::Radio { group: group, index: index, selected: index == selected }, label)
+}
+
+fn submenu(id: u32, label: &str, children: Vec<MenuNode>) -> MenuNode {
+    let mut n = node(id, MenuNodeKind::Submenu, label);
+    n.children = children;
+    n
+}
+
+// Runs what Application::set_menu does, with placeholder ids from 1000 up
+// in `new` and fresh ids handed out from 100 up. Returns the applied menu
+// and the operations it took.
+fn apply(old: &[MenuNode], mut new: Vec<MenuNode>) -> (Vec<MenuNode>, Vec<Op>) {
+    let mut next = 100;
+    assign_ids(old, &mut new, &mut || {
+        next += 1;
+        next - 1
+    });
+    let mut backend = FakeBackend::new(old);
+    reconcile(&mut backend, None, old, &new).unwrap();
+    assert_eq!(backend.menu, new);
+    (new, backend.ops)
+}
+
+fn ids(nodes: &[MenuNode]) -> Vec<u32> {
+    nodes.iter().map(|n| n.id).collect()
+}
+
+#[test]
+fn unchanged_menu_needs_no_operations() {
+    let old = vec![item(1, "Status"), node(2, MenuNodeKind::Separator, ""), item(3, "Quit")];
+    let new = vec![item(1000, "Status"), node(1001, MenuNodeKind::Separator, ""), item(1002, "Quit")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![1, 2, 3]);
+    assert_eq!(ops, vec![]);
+}
+
+#[test]
+fn changed_label_is_relabeled_in_place() {
+    let old = vec![item(1, "Connected to A"), item(2, "Quit")];
+    let new = vec![item(1000, "Connected to B"), item(1001, "Quit")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![1, 2]);
+    assert_eq!(ops, vec![Op::Label(1, "Connected to B".to_string())]);
+}
+
+#[test]
+fn property_changes_are_applied_in_place() {
+    let old = vec![item(1, "Sync"), check(2, "Notifications", true)];
+    let mut sync = item(1000, "Sync");
+    sync.enabled = false;
+    sync.icon = Some("sync.png".to_string());
+    let mut notifications = check(1001, "Notifications", false);
+    notifications.visible = false;
+    let (_, ops) = apply(&old, vec![sync, notifications]);
+    assert_eq!(ops, vec![
+        Op::Icon(1, Some("sync.png".to_string())),
+        Op::Enabled(1, false),
+        Op::Checked(2, false),
+        Op::Visible(2, false),
+    ]);
+}
+
+#[test]
+fn inserted_entry_leaves_the_others_alone() {
+    let old = vec![item(1, "Open"), item(2, "Quit")];
+    let new = vec![item(1000, "Open"), item(1001, "Pause"), item(1002, "Quit")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![1, 100, 2]);
+    assert_eq!(ops, vec![Op::Insert(None, 1, 100)]);
+}
+
+#[test]
+fn removed_entry_leaves_the_others_alone() {
+    let old = vec![item(1, "Open"), item(2, "Pause"), item(3, "Quit")];
+    let new = vec![item(1000, "Open"), item(1001, "Quit")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![1, 3]);
+    assert_eq!(ops, vec![Op::Remove(2)]);
+}
+
+#[test]
+fn keys_keep_identity_across_relabeling() {
+    let old = vec![keyed(1, "a", "Host A: up"), keyed(2, "b", "Host B: up")];
+    let new = vec![keyed(1000, "b", "Host B: down"), keyed(1001, "a", "Host A: up")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![2, 1]);
+    assert!(ops.iter().all(|op| match *op {
+        Op::Insert(_, _, id) | Op::Remove(id) => id == 2,
+        Op::Label(id, _) => id == 2,
+        _ => false
+    }), "{:?}", ops);
+}
+
+#[test]
+fn changed_kind_replaces_the_entry() {
+    let old = vec![item(1, "Notifications")];
+    let new = vec![check(1000, "Notifications", true)];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![100]);
+    assert_eq!(ops, vec![Op::Remove(1), Op::Insert(None, 0, 100)]);
+}
+
+#[test]
+fn radio_selection_is_a_single_operation() {
+    let old = vec![radio(2, 1, 0, 0, "Low"), radio(3, 1, 1, 0, "Balanced")];
+    let new = vec![radio(1001, 1000, 0, 1, "Low"), radio(1002, 1000, 1, 1, "Balanced")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![2, 3]);
+    assert_eq!(menu[0].kind, MenuNodeKind::Radio { group: 1, index: 0, selected: false });
+    assert_eq!(ops, vec![Op::RadioSelected(1, 1)]);
+}
+
+#[test]
+fn radio_group_ids_are_mapped() {
+    let old = vec![radio(2, 1, 0, 0, "Low")];
+    let mut new = vec![radio(1001, 1000, 0, 0, "Low"), radio(1002, 1000, 1, 0, "High")];
+    let mut next = 100;
+    let mapped = assign_ids(&old, &mut new, &mut || {
+        next += 1;
+        next - 1
+    });
+    assert_eq!(mapped[&1000], 1);
+    assert_eq!(mapped[&1001], 2);
+    assert_eq!(mapped[&1002], 100);
+    assert_eq!(new[1].kind, MenuNodeKind::Radio { group: 1, index: 1, selected: false });
+}
+
+#[test]
+fn submenus_are_diffed_recursively() {
+    let old = vec![submenu(1, "Hosts", vec![item(2, "A"), item(3, "B")]), item(4, "Quit")];
+    let new = vec![submenu(1000, "Hosts", vec![item(1001, "A"), item(1002, "C")]), item(1003, "Quit")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![1, 4]);
+    assert_eq!(ids(&menu[0].children), vec![2, 3]);
+    assert_eq!(ops, vec![Op::Label(3, "C".to_string())]);
+}
+
+#[test]
+fn new_submenu_is_inserted_with_its_entries() {
+    let old = vec![item(1, "Quit")];
+    let new = vec![submenu(1000, "Hosts", vec![item(1001, "A")]), item(1002, "Quit")];
+    let (menu, ops) = apply(&old, new);
+    assert_eq!(ids(&menu), vec![100, 1]);
+    assert_eq!(ids(&menu[0].children), vec![101]);
+    assert_eq!(ops, vec![Op::Insert(None, 0, 100)]);
+}
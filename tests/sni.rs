@@ -0,0 +1,358 @@
+#![cfg(all(target_os = "linux", feature = "sni"))]
+
+extern crate dbus;
+extern crate systray;
+
+mod common;
+
+use common::{encode_png, private_bus};
+use dbus::blocking::Connection;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, MessageType};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use systray::{Application, ApplicationBuilder, Category, Menu, MenuEntry, ScrollOrientation, SystrayError, SystrayEvent,
+              Tooltip};
+
+const DBUSMENU: &'static str = "com.canonical.dbusmenu";
+const ITEM: &'static str = "org.kde.StatusNotifierItem";
+const ITEM_PATH: &'static str = "/StatusNotifierItem";
+const WATCHER: &'static str = "org.kde.StatusNotifierWatcher";
+
+type Pixmap = (i32, i32, Vec<u8>);
+
+// Stands in for the panel: takes the watcher name, reports the items that
+// register with it, and the signals they send.
+struct Host {
+    registrations: Receiver<String>,
+    // Sender and member of each signal
+    signals: Receiver<(String, String)>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Host {
+    fn start() -> Host {
+        let (registered_tx, registered_rx) = channel();
+        let (signal_tx, signal_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            let conn = Connection::new_session().unwrap();
+            conn.request_name(WATCHER, false, true, true).unwrap();
+            conn.start_receive(MatchRule::new_method_call().with_interface(WATCHER), Box::new(move |msg, conn| {
+                if let Some(service) = msg.get1::<String>() {
+                    registered_tx.send(service).ok();
+                }
+                conn.send(msg.method_return()).ok();
+                true
+            }));
+            let rule = MatchRule::new().with_type(MessageType::Signal).with_interface(ITEM);
+            conn.add_match_no_cb(&rule.match_str()).unwrap();
+            conn.start_receive(rule, Box::new(move |msg, _| {
+                let sender = msg.sender().map(|s| s.to_string()).unwrap_or_default();
+                let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+                signal_tx.send((sender, member)).ok();
+                true
+            }));
+            ready_tx.send(()).unwrap();
+            while !thread_stop.load(Ordering::SeqCst) {
+                conn.process(Duration::from_millis(50)).unwrap();
+            }
+        });
+        ready_rx.recv().unwrap();
+        Host {
+            registrations: registered_rx,
+            signals: signal_rx,
+            stop: stop,
+            thread: Some(thread),
+        }
+    }
+
+    fn next_registration(&self) -> String {
+        self.registrations.recv_timeout(Duration::from_secs(5)).expect("no item registered")
+    }
+
+    // Waits for the item owned by owner to send member, skipping the rest.
+    fn expect_signal(&self, owner: &str, member: &str) {
+        loop {
+            let (sender, name) = self.signals.recv_timeout(Duration::from_secs(5))
+                .expect(&format!("no {} signal", member));
+            if sender == owner && name == member {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            t.join().ok();
+        }
+    }
+}
+
+fn name_owner(conn: &Connection, name: &str) -> Option<String> {
+    let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_secs(5));
+    let owner: Result<(String,), _> = proxy.method_call("org.freedesktop.DBus", "GetNameOwner", (name,));
+    owner.ok().map(|o| o.0)
+}
+
+fn get<T>(conn: &Connection, service: &str, property: &str) -> T
+    where T: for<'b> dbus::arg::Get<'b> + 'static {
+    conn.with_proxy(service, ITEM_PATH, Duration::from_secs(5)).get(ITEM, property).unwrap()
+}
+
+fn call(conn: &Connection, service: &str, method: &str, args: (i32, i32)) {
+    let proxy = conn.with_proxy(service, ITEM_PATH, Duration::from_secs(5));
+    let _: () = proxy.method_call(ITEM, method, args).unwrap();
+}
+
+fn next_event(app: &mut Application) -> SystrayEvent {
+    app.wait_for_message_timeout(Duration::from_secs(5)).unwrap().expect("no event")
+}
+
+#[test]
+fn registers_with_watcher_and_exports_properties() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let app = ApplicationBuilder::new()
+        .id("sni-test")
+        .title("SNI test")
+        .category(Category::Hardware)
+        .icon("battery-good")
+        .tooltip(Tooltip::new("Charged").body("<b>100%</b>"))
+        .build()
+        .unwrap();
+    let service = host.next_registration();
+    assert!(service.starts_with("org.kde.StatusNotifierItem-"));
+    let conn = Connection::new_session().unwrap();
+    assert_eq!(get::<String>(&conn, &service, "Id"), "sni-test");
+    assert_eq!(get::<String>(&conn, &service, "Title"), "SNI test");
+    assert_eq!(get::<String>(&conn, &service, "Category"), "Hardware");
+    assert_eq!(get::<String>(&conn, &service, "Status"), "Active");
+    assert_eq!(get::<String>(&conn, &service, "IconName"), "battery-good");
+    assert_eq!(get::<bool>(&conn, &service, "ItemIsMenu"), false);
+    let tooltip: (String, Vec<Pixmap>, String, String) = get(&conn, &service, "ToolTip");
+    assert_eq!(tooltip, (String::new(), vec![], "Charged".to_string(), "<b>100%</b>".to_string()));
+    let all = conn.with_proxy(&*service, ITEM_PATH, Duration::from_secs(5)).get_all(ITEM).unwrap();
+    assert!(all.contains_key("IconPixmap"));
+    assert!(all.contains_key("Menu"));
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn icons_from_buffers_are_exported_as_pixmaps() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let app = ApplicationBuilder::new().build().unwrap();
+    let service = host.next_registration();
+    let conn = Connection::new_session().unwrap();
+    let owner = name_owner(&conn, &service).unwrap();
+    app.set_icon_from_rgba(&[1, 2, 3, 4, 5, 6, 7, 8], 1, 2).unwrap();
+    host.expect_signal(&owner, "NewIcon");
+    let pixmaps: Vec<Pixmap> = get(&conn, &service, "IconPixmap");
+    assert_eq!(pixmaps, vec![(1, 2, vec![4, 1, 2, 3, 8, 5, 6, 7])]);
+    assert_eq!(get::<String>(&conn, &service, "IconName"), "");
+
+    app.set_icon_from_png(&encode_png(2, 1, &[10, 20, 30, 40, 50, 60])).unwrap();
+    host.expect_signal(&owner, "NewIcon");
+    let pixmaps: Vec<Pixmap> = get(&conn, &service, "IconPixmap");
+    assert_eq!(pixmaps, vec![(2, 1, vec![255, 10, 20, 30, 255, 40, 50, 60])]);
+
+    app.set_icon_from_theme("mail-unread").unwrap();
+    host.expect_signal(&owner, "NewIcon");
+    assert_eq!(get::<String>(&conn, &service, "IconName"), "mail-unread");
+    assert_eq!(get::<Vec<Pixmap>>(&conn, &service, "IconPixmap"), vec![]);
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn status_label_and_tooltip_changes_are_signalled() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let app = ApplicationBuilder::new().build().unwrap();
+    let service = host.next_registration();
+    let conn = Connection::new_session().unwrap();
+    let owner = name_owner(&conn, &service).unwrap();
+
+    app.set_visible(false).unwrap();
+    host.expect_signal(&owner, "NewStatus");
+    assert_eq!(get::<String>(&conn, &service, "Status"), "Passive");
+    app.set_visible(true).unwrap();
+    app.request_attention("mail-unread").unwrap();
+    host.expect_signal(&owner, "NewAttentionIcon");
+    assert_eq!(get::<String>(&conn, &service, "Status"), "NeedsAttention");
+    assert_eq!(get::<String>(&conn, &service, "AttentionIconName"), "mail-unread");
+    app.clear_attention().unwrap();
+    host.expect_signal(&owner, "NewStatus");
+    assert_eq!(get::<String>(&conn, &service, "Status"), "Active");
+
+    app.set_icon_label("3", "99").unwrap();
+    host.expect_signal(&owner, "XAyatanaNewLabel");
+    assert_eq!(get::<String>(&conn, &service, "XAyatanaLabel"), "3");
+    assert_eq!(get::<String>(&conn, &service, "XAyatanaLabelGuide"), "99");
+
+    app.set_tooltip(&"Syncing".to_string()).unwrap();
+    host.expect_signal(&owner, "NewToolTip");
+    let tooltip: (String, Vec<Pixmap>, String, String) = get(&conn, &service, "ToolTip");
+    assert_eq!(tooltip.2, "Syncing");
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn tooltip_provider_is_asked_when_hosts_read_it() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let app = ApplicationBuilder::new().build().unwrap();
+    let service = host.next_registration();
+    let conn = Connection::new_session().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let provider_calls = calls.clone();
+    app.set_tooltip_provider(Duration::from_secs(0), move || {
+        let n = provider_calls.fetch_add(1, Ordering::SeqCst) + 1;
+        Tooltip::new(&format!("Read {} times", n))
+    }).unwrap();
+    let tooltip: (String, Vec<Pixmap>, String, String) = get(&conn, &service, "ToolTip");
+    assert_eq!(tooltip.2, "Read 1 times");
+    let tooltip: (String, Vec<Pixmap>, String, String) = get(&conn, &service, "ToolTip");
+    assert_eq!(tooltip.2, "Read 2 times");
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn host_calls_become_events() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let mut app = ApplicationBuilder::new().build().unwrap();
+    let service = host.next_registration();
+    let conn = Connection::new_session().unwrap();
+
+    call(&conn, &service, "Activate", (3, 4));
+    match next_event(&mut app) {
+        SystrayEvent::IconActivated { position, .. } => assert_eq!(position, Some((3, 4))),
+        e => panic!("unexpected event {:?}", e)
+    }
+    call(&conn, &service, "SecondaryActivate", (5, 6));
+    match next_event(&mut app) {
+        SystrayEvent::SecondaryActivate { position, .. } => assert_eq!(position, Some((5, 6))),
+        e => panic!("unexpected event {:?}", e)
+    }
+    call(&conn, &service, "ContextMenu", (7, 8));
+    match next_event(&mut app) {
+        SystrayEvent::ContextMenuRequested { position, .. } => assert_eq!(position, Some((7, 8))),
+        e => panic!("unexpected event {:?}", e)
+    }
+    let proxy = conn.with_proxy(&*service, ITEM_PATH, Duration::from_secs(5));
+    let _: () = proxy.method_call(ITEM, "Scroll", (2, "vertical")).unwrap();
+    match next_event(&mut app) {
+        SystrayEvent::Scroll { delta, orientation, .. } => {
+            assert_eq!((delta, orientation), (-2, ScrollOrientation::Vertical));
+        }
+        e => panic!("unexpected event {:?}", e)
+    }
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn menu_is_exported_over_dbusmenu() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let mut app = ApplicationBuilder::new().build().unwrap();
+    let service = host.next_registration();
+    let conn = Connection::new_session().unwrap();
+    let clicked = Arc::new(AtomicUsize::new(0));
+    let counter = clicked.clone();
+    let item = app.add_menu_item(&"Quit".to_string(), move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+    }).unwrap();
+    app.add_check_item("Mute", false, |_, _| ()).unwrap();
+
+    let menu: dbus::Path = get(&conn, &service, "Menu");
+    let proxy = conn.with_proxy(&*service, &*menu, Duration::from_secs(5));
+    let (_, (_, _, children)): (u32, (i32, dbus::arg::PropMap, Vec<dbus::arg::Variant<Box<dbus::arg::RefArg>>>)) =
+        proxy.method_call(DBUSMENU, "GetLayout", (0, -1, Vec::<String>::new())).unwrap();
+    assert_eq!(children.len(), 2);
+
+    let id = systray::dbusmenu::item_id(item.id());
+    let _: () = proxy.method_call(DBUSMENU, "Event", (id, "clicked", dbus::arg::Variant(0i32), 0u32)).unwrap();
+    match next_event(&mut app) {
+        SystrayEvent::MenuItemActivated { id, .. } => assert_eq!(id, item.id()),
+        e => panic!("unexpected event {:?}", e)
+    }
+    assert_eq!(clicked.load(Ordering::SeqCst), 1);
+
+    item.set_label(&mut app, "Exit").unwrap();
+    let (label,): (dbus::arg::Variant<String>,) = proxy.method_call(DBUSMENU, "GetProperty", (id, "label")).unwrap();
+    assert_eq!(label.0, "Exit");
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn failed_menu_change_leaves_the_menu_where_the_backend_got_to() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let mut app = ApplicationBuilder::new().build().unwrap();
+    let service = host.next_registration();
+    let conn = Connection::new_session().unwrap();
+    app.set_menu(Menu::new().entry(MenuEntry::item("Open", |_| ()).key("open"))).unwrap();
+
+    let missing = format!("/nonexistent/systray-test-{}.png", std::process::id());
+    let result = app.set_menu(Menu::new()
+        .entry(MenuEntry::item("Open now", |_| ()).key("open"))
+        .entry(MenuEntry::item("Broken", |_| ()).icon(&missing)));
+    assert!(result.is_err());
+
+    let menu: dbus::Path = get(&conn, &service, "Menu");
+    let proxy = conn.with_proxy(&*service, &*menu, Duration::from_secs(5));
+    let (_, (_, _, children)): (u32, (i32, dbus::arg::PropMap, Vec<dbus::arg::Variant<Box<dbus::arg::RefArg>>>)) =
+        proxy.method_call(DBUSMENU, "GetLayout", (0, 1, vec!["label".to_string()])).unwrap();
+    let exported: Vec<String> = children.iter().map(|child| {
+        let mut fields = child.0.as_iter().unwrap();
+        fields.next();
+        let props = fields.next().unwrap();
+        let mut props = props.as_iter().unwrap();
+        props.next();
+        props.next().unwrap().as_str().unwrap().to_string()
+    }).collect();
+    let model: Vec<String> = app.menu().iter().map(|node| node.label.clone()).collect();
+    assert_eq!(model, exported);
+    assert!(!model.contains(&"Broken".to_string()));
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn registers_again_with_a_new_watcher() {
+    let _bus = private_bus();
+    let app = ApplicationBuilder::new().build().unwrap();
+    let host = Host::start();
+    let first = host.next_registration();
+    drop(host);
+    let host = Host::start();
+    assert_eq!(host.next_registration(), first);
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn quit_leaves_the_bus_and_ends_events() {
+    let _bus = private_bus();
+    let host = Host::start();
+    let mut app = ApplicationBuilder::new().build().unwrap();
+    let service = host.next_registration();
+    let conn = Connection::new_session().unwrap();
+    assert!(name_owner(&conn, &service).is_some());
+    app.quit();
+    match app.wait_for_message_timeout(Duration::from_secs(5)) {
+        Err(SystrayError::Disconnected) => (),
+        r => panic!("expected a disconnect, got {:?}", r.map(|_| ()))
+    }
+    assert_eq!(name_owner(&conn, &service), None);
+}
@@ -0,0 +1,180 @@
+#![cfg(all(target_os = "linux", feature = "sni", feature = "async"))]
+
+extern crate dbus;
+extern crate futures_core;
+extern crate systray;
+
+mod common;
+
+use common::private_bus;
+use dbus::blocking::Connection;
+use futures_core::Stream;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use systray::{ApplicationBuilder, SystrayEvent, TrayHandle};
+
+const ITEM: &'static str = "org.kde.StatusNotifierItem";
+const ITEM_PATH: &'static str = "/StatusNotifierItem";
+
+// Unparks the thread blocked on a future, counting how often it was woken.
+struct ThreadWaker {
+    thread: Thread,
+    wakes: AtomicUsize,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+impl ThreadWaker {
+    fn new() -> Arc<ThreadWaker> {
+        Arc::new(ThreadWaker { thread: thread::current(), wakes: AtomicUsize::new(0) })
+    }
+
+    // Parks until woken after the wake count seen, failing after a while.
+    fn park_after(&self, seen: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.wakes.load(Ordering::SeqCst) == seen {
+            let now = Instant::now();
+            assert!(now < deadline, "task was never woken");
+            thread::park_timeout(deadline - now);
+        }
+    }
+}
+
+// The least an executor does: poll, and poll again only once woken.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = Box::pin(fut);
+    let state = ThreadWaker::new();
+    let waker = Waker::from(state.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        let seen = state.wakes.load(Ordering::SeqCst);
+        if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return output;
+        }
+        state.park_after(seen);
+    }
+}
+
+// Handles an activation in two steps, asking to be polled again in
+// between, and quits the backend after the one at x 4.
+struct Handler {
+    log: Arc<Mutex<Vec<String>>>,
+    handle: TrayHandle,
+    x: i32,
+    started: bool,
+}
+
+impl Future for Handler {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if !self.started {
+            self.started = true;
+            self.log.lock().unwrap().push(format!("start {}", self.x));
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.log.lock().unwrap().push(format!("end {}", self.x));
+        if self.x == 4 {
+            self.handle.quit();
+        }
+        Poll::Ready(())
+    }
+}
+
+// The bus name of the one item of this process.
+fn item_name(conn: &Connection) -> String {
+    let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_secs(5));
+    let (names,): (Vec<String>,) = proxy.method_call("org.freedesktop.DBus", "ListNames", ()).unwrap();
+    let prefix = format!("{}-{}-", ITEM, process::id());
+    names.into_iter().find(|n| n.starts_with(&prefix)).expect("no item on the bus")
+}
+
+// Activates the item from another connection, as a host would.
+fn activate(service: &str, x: i32) {
+    let conn = Connection::new_session().unwrap();
+    let proxy = conn.with_proxy(service, ITEM_PATH, Duration::from_secs(5));
+    let _: () = proxy.method_call(ITEM, "Activate", (x, 0)).unwrap();
+}
+
+fn position(event: &SystrayEvent) -> Option<(i32, i32)> {
+    match *event {
+        SystrayEvent::IconActivated { position, .. } => position,
+        ref e => panic!("unexpected event {:?}", e)
+    }
+}
+
+#[test]
+fn event_stream_wakes_the_parked_task() {
+    let _bus = private_bus();
+    let mut app = ApplicationBuilder::new().build().unwrap();
+    let service = item_name(&Connection::new_session().unwrap());
+    {
+        let mut events = app.event_stream();
+        let state = ThreadWaker::new();
+        let waker = Waker::from(state.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut events).poll_next(&mut cx).is_pending());
+
+        let host = thread::spawn(move || activate(&service, 1));
+        state.park_after(0);
+        match Pin::new(&mut events).poll_next(&mut cx) {
+            Poll::Ready(Some(ref event)) => assert_eq!(position(event), Some((1, 0))),
+            _ => panic!("no event after the wake")
+        }
+        host.join().unwrap();
+    }
+    app.shutdown().unwrap();
+}
+
+#[test]
+fn event_stream_ends_once_the_backend_quits() {
+    let _bus = private_bus();
+    let mut app = ApplicationBuilder::new().build().unwrap();
+    let service = item_name(&Connection::new_session().unwrap());
+    activate(&service, 2);
+    let handle = app.handle();
+    let mut events = app.event_stream();
+    let first = block_on(future::poll_fn(|cx| Pin::new(&mut events).poll_next(cx)));
+    assert_eq!(first.as_ref().and_then(position), Some((2, 0)));
+
+    let quit = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.quit();
+    });
+    assert!(block_on(future::poll_fn(|cx| Pin::new(&mut events).poll_next(cx))).is_none());
+    quit.join().unwrap();
+}
+
+#[test]
+fn run_awaits_each_handler_before_the_next_event() {
+    let _bus = private_bus();
+    let mut app = ApplicationBuilder::new().build().unwrap();
+    let service = item_name(&Connection::new_session().unwrap());
+    activate(&service, 3);
+    activate(&service, 4);
+    let handle = app.handle();
+    let log = Arc::new(Mutex::new(vec![]));
+    block_on(app.run(|event| Handler {
+        log: log.clone(),
+        handle: handle.clone(),
+        x: position(&event).unwrap().0,
+        started: false,
+    }));
+    assert_eq!(*log.lock().unwrap(), vec!["start 3", "end 3", "start 4", "end 4"]);
+}
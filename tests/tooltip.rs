@@ -0,0 +1,99 @@
+extern crate systray;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use systray::{Tooltip, TooltipProvider};
+
+fn plain(body: &str) -> String {
+    Tooltip::new("").body(body).to_plain_text()
+}
+
+#[test]
+fn tags_are_dropped() {
+    assert_eq!(plain("<b>Charged</b> at <i>100%</i>"), "Charged at 100%");
+    assert_eq!(plain("<a href=\"https://example.com\">link</a>"), "link");
+    assert_eq!(plain("<img src=\"battery.png\"/>"), "");
+}
+
+#[test]
+fn line_breaks_become_newlines() {
+    assert_eq!(plain("one<br/>two<br>three<BR />four"), "one\ntwo\nthree\nfour");
+}
+
+#[test]
+fn nested_markup_keeps_only_the_text() {
+    assert_eq!(plain("<b>Battery <i>low: <u>5%</u></i></b>"), "Battery low: 5%");
+    assert_eq!(plain("<b><i></i></b>"), "");
+}
+
+#[test]
+fn entities_are_resolved_once() {
+    assert_eq!(plain("a &lt; b &amp;&amp; c &gt; d"), "a < b && c > d");
+    assert_eq!(plain("&quot;quoted&quot; &apos;too&apos;"), "\"quoted\" 'too'");
+    assert_eq!(plain("&amp;lt;"), "&lt;");
+}
+
+#[test]
+fn unknown_entities_and_stray_ampersands_are_kept() {
+    assert_eq!(plain("Tom & Jerry"), "Tom & Jerry");
+    assert_eq!(plain("&nbsp;x"), "&nbsp;x");
+    assert_eq!(plain("trailing &"), "trailing &");
+}
+
+#[test]
+fn unterminated_tag_is_dropped() {
+    assert_eq!(plain("text <b"), "text ");
+}
+
+#[test]
+fn title_and_body_are_joined() {
+    assert_eq!(Tooltip::new("Battery").body("<b>80%</b>").to_plain_text(), "Battery\n80%");
+    assert_eq!(Tooltip::new("Battery").to_plain_text(), "Battery");
+    assert_eq!(Tooltip::new("Battery").body("<br/>").to_plain_text(), "Battery\n\n");
+    assert_eq!(plain("80%"), "80%");
+}
+
+// A provider counting how often it was asked, and saying so in the title.
+fn counting_provider(ttl: Duration) -> (TooltipProvider, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let provider = TooltipProvider::new(ttl, move || {
+        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        Tooltip::new(&format!("call {}", n))
+    });
+    (provider, calls)
+}
+
+#[test]
+fn provider_runs_only_when_asked() {
+    let (provider, calls) = counting_provider(Duration::from_secs(60));
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+    assert_eq!(provider.get().title, "call 1");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn provider_result_is_reused_within_ttl() {
+    let (provider, calls) = counting_provider(Duration::from_secs(60));
+    assert_eq!(provider.get().title, "call 1");
+    assert_eq!(provider.get().title, "call 1");
+    assert_eq!(provider.get().title, "call 1");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(provider.ttl(), Duration::from_secs(60));
+}
+
+#[test]
+fn provider_runs_again_once_ttl_passed() {
+    let (provider, calls) = counting_provider(Duration::from_millis(20));
+    assert_eq!(provider.get().title, "call 1");
+    thread::sleep(Duration::from_millis(40));
+    assert_eq!(provider.get().title, "call 2");
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    let (provider, calls) = counting_provider(Duration::from_secs(0));
+    provider.get();
+    provider.get();
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}